@@ -0,0 +1,111 @@
+use napi::bindgen_prelude::*;
+use napi::{check_status, sys, JsFunction, JsObject, JsUnknown, NapiValue, ValueType};
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::ptr;
+
+// Rethrows from a rejected promise instead of from the native call
+const GUARD_SCRIPT: &str = "(callback) => function (...args) {
+    try {
+        return callback.apply(this, args);
+    } catch (error) {
+        return Promise.reject(error);
+    }
+}";
+
+// The value returned by a JS callback invoked with call_with_return_value.
+// napi-rs aborts the process if a return value fails to convert, so this
+// accepts anything and leaves interpreting it to the receiver.
+pub(crate) struct CallbackResult {
+    env: sys::napi_env,
+    value: sys::napi_value,
+}
+
+impl FromNapiValue for CallbackResult {
+    unsafe fn from_napi_value(env: sys::napi_env, value: sys::napi_value) -> Result<Self> {
+        Ok(CallbackResult { env, value })
+    }
+}
+
+impl CallbackResult {
+    // Converts the value, or returns None if it is not a T
+    pub(crate) fn get<T: FromNapiValue>(&self) -> Option<T> {
+        unsafe { T::from_napi_value(self.env, self.value) }.ok()
+    }
+
+    // Calls `done` with whether the value is a yes, and what it settled
+    // with: a promise is once it fulfils with anything but `false`, and is
+    // not if it rejects; any other value is unless it is `false`
+    pub(crate) fn settle(self, done: impl FnOnce(bool, &CallbackResult) + 'static) -> Result<()> {
+        let env = unsafe { Env::from_raw(self.env) };
+        let value: JsUnknown = unsafe { JsUnknown::from_raw_unchecked(self.env, self.value) };
+
+        if value.get_type()? == ValueType::Object {
+            let promise: JsObject = unsafe { value.cast() };
+            let then: JsUnknown = promise.get_named_property("then")?;
+            if then.get_type()? == ValueType::Function {
+                let then: JsFunction = unsafe { then.cast() };
+                let mut settlement = External::new(Settlement(Cell::new(Some(Box::new(done)))));
+                let data: *mut Settlement = &mut *settlement;
+                let settlement = unsafe { External::to_napi_value(self.env, settlement)? };
+                let on_fulfilled = handler(&env, fulfilled, data.cast(), settlement)?;
+                let on_rejected = handler(&env, rejected, data.cast(), settlement)?;
+                then.call(Some(&promise), &[on_fulfilled, on_rejected])?;
+                return Ok(());
+            }
+        }
+
+        done(self.get::<bool>() != Some(false), &self);
+        Ok(())
+    }
+}
+
+type Done = Box<dyn FnOnce(bool, &CallbackResult)>;
+
+// Shared by the two handlers attached to a promise; whichever runs takes `done`
+struct Settlement(Cell<Option<Done>>);
+
+// Creates a promise handler that calls `callback` with `data`. The handler
+// holds `settlement`, which owns `data`, so it outlives every call.
+fn handler(env: &Env, callback: HandlerFn, data: *mut c_void, settlement: sys::napi_value) -> Result<JsFunction> {
+    let mut raw = ptr::null_mut();
+    check_status!(unsafe { sys::napi_create_function(env.raw(), ptr::null(), 0, Some(callback), data, &mut raw) })?;
+    let name = b"settlement\0".as_ptr().cast();
+    check_status!(unsafe { sys::napi_set_named_property(env.raw(), raw, name, settlement) })?;
+    Ok(unsafe { JsFunction::from_raw_unchecked(env.raw(), raw) })
+}
+
+type HandlerFn = unsafe extern "C" fn(sys::napi_env, sys::napi_callback_info) -> sys::napi_value;
+
+unsafe extern "C" fn fulfilled(env: sys::napi_env, info: sys::napi_callback_info) -> sys::napi_value {
+    settle_with(env, info, true)
+}
+
+unsafe extern "C" fn rejected(env: sys::napi_env, info: sys::napi_callback_info) -> sys::napi_value {
+    settle_with(env, info, false)
+}
+
+unsafe fn settle_with(env: sys::napi_env, info: sys::napi_callback_info, fulfilled: bool) -> sys::napi_value {
+    let mut argc = 1;
+    let mut argv = [ptr::null_mut(); 1];
+    let mut data = ptr::null_mut();
+    sys::napi_get_cb_info(env, info, &mut argc, argv.as_mut_ptr(), ptr::null_mut(), &mut data);
+
+    let settlement = &*(data as *const Settlement);
+    if let Some(done) = settlement.0.take() {
+        // Missing arguments read as undefined
+        let value = CallbackResult { env, value: argv[0] };
+        done(fulfilled && value.get::<bool>() != Some(false), &value);
+    }
+    ptr::null_mut()
+}
+
+// Wraps a callback that will be invoked with call_with_return_value so that
+// throwing returns a rejected promise instead. napi-rs aborts the process
+// when such a callback throws; a rejected promise is reported as unhandled
+// (or handled, by receivers that await it) like any other.
+pub(crate) fn guard(env: &Env, callback: JsFunction) -> Result<JsFunction> {
+    let wrap: JsFunction = env.run_script(GUARD_SCRIPT)?;
+    wrap.call(None, &[callback])?.try_into()
+}
@@ -0,0 +1,152 @@
+use napi::bindgen_prelude::{Buffer, Either};
+use napi_derive::napi;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryFrom;
+
+use crate::parse_hex_id;
+
+// Length of the connection IDs the server chooses
+pub(crate) const CID_LEN: usize = quiche::MAX_CONN_ID_LEN;
+
+// Rounds of the Feistel network; four make a keyed permutation that cannot
+// be told from a random one (Luby-Rackoff)
+const ROUNDS: u8 = 4;
+
+// Shortest `cidKey` accepted
+const MIN_KEY_LEN: usize = 16;
+
+// Leading plaintext bytes of a connection ID that carry the server's workerId
+const ROUTING_LEN: usize = 2;
+
+// Picks the connection IDs the server hands out. With a key, each is
+// encrypted with a format-preserving permutation, a Feistel network over
+// its two halves with HMAC-SHA256 as the round function, so that nothing an
+// ID carries for the server can be read or linked by on-path observers. A
+// workerId is carried in the first ROUTING_LEN bytes of the plaintext, where
+// a dispatcher holding the key (or none, without one) can find it.
+pub(crate) struct ConnectionIds {
+    rng: SystemRandom,
+    key: Option<hmac::Key>,
+    worker_id: Option<u16>,
+}
+
+impl ConnectionIds {
+    pub(crate) fn new(key: Option<&[u8]>, worker_id: Option<u32>) -> napi::Result<Self> {
+        let key = match key {
+            Some(key) if key.len() < MIN_KEY_LEN => {
+                return Err(napi::Error::from_reason(format!("cidKey must be at least {} bytes", MIN_KEY_LEN)))
+            }
+            key => key.map(|key| hmac::Key::new(hmac::HMAC_SHA256, key)),
+        };
+        let worker_id = worker_id
+            .map(|id| u16::try_from(id).map_err(|_| napi::Error::from_reason("workerId must be at most 65535")))
+            .transpose()?;
+        Ok(ConnectionIds { rng: SystemRandom::new(), key, worker_id })
+    }
+
+    // A fresh connection ID, or None if the system RNG failed
+    pub(crate) fn generate(&self) -> Option<quiche::ConnectionId<'static>> {
+        let mut cid = [0; CID_LEN];
+        self.rng.fill(&mut cid).ok()?;
+        if let Some(worker_id) = self.worker_id {
+            cid[..ROUTING_LEN].copy_from_slice(&worker_id.to_be_bytes());
+        }
+        if let Some(key) = &self.key {
+            permute(key, &mut cid, false);
+        }
+        Some(cid.to_vec().into())
+    }
+
+    // The plaintext of a connection ID this server chose
+    pub(crate) fn decrypt(&self, cid: &[u8]) -> Option<[u8; CID_LEN]> {
+        if cid.len() != CID_LEN {
+            return None;
+        }
+        let mut plain = [0; CID_LEN];
+        plain.copy_from_slice(cid);
+        if let Some(key) = &self.key {
+            permute(key, &mut plain, true);
+        }
+        Some(plain)
+    }
+}
+
+/// The `workerId` embedded in a connection ID chosen by a server started
+/// with one, so that a dispatcher in front of several servers can steer each
+/// packet to the one that owns its connection. `cid` is the destination
+/// connection ID of a packet, or a `connId` as events report it; `cidKey` is
+/// the servers' shared key, if they have one. Returns `null` for IDs of
+/// another length than servers choose. Without `cidKey` the worker ID is
+/// simply the ID's first two bytes, big-endian, which an eBPF dispatcher can
+/// read straight off the packet.
+#[napi(ts_args_type = "cid: Buffer | string, cidKey?: Buffer")]
+pub fn cid_worker_id(cid: Either<Buffer, String>, cid_key: Option<Buffer>) -> napi::Result<Option<u32>> {
+    let cid = match cid {
+        Either::A(cid) => cid.to_vec(),
+        Either::B(id) => {
+            parse_hex_id(&id).ok_or_else(|| napi::Error::from_reason(format!("Invalid connection ID {:?}", id)))?
+        }
+    };
+    let ids = ConnectionIds::new(cid_key.as_deref(), None)?;
+    Ok(ids.decrypt(&cid).map(|plain| u16::from_be_bytes([plain[0], plain[1]]).into()))
+}
+
+// Runs the Feistel network over `block` forwards, or backwards to undo it
+fn permute(key: &hmac::Key, block: &mut [u8; CID_LEN], inverse: bool) {
+    let (left, right) = block.split_at_mut(CID_LEN / 2);
+    for i in 0..ROUNDS {
+        let round = if inverse { ROUNDS - 1 - i } else { i };
+        // Even rounds mix the right half into the left, odd ones the reverse
+        let (target, source) = if round % 2 == 0 { (&mut *left, &*right) } else { (&mut *right, &*left) };
+        let mut ctx = hmac::Context::with_key(key);
+        ctx.update(&[round]);
+        ctx.update(source);
+        for (byte, mask) in target.iter_mut().zip(ctx.sign().as_ref()) {
+            *byte ^= mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_id;
+
+    #[test]
+    fn encrypted_ids_decrypt_to_random_plaintext() {
+        let ids = ConnectionIds::new(Some(&[7; 32]), None).unwrap();
+        let cid = ids.generate().unwrap();
+        let plain = ids.decrypt(&cid).unwrap();
+        assert_ne!(&plain[..], &cid[..]);
+
+        let mut again = plain;
+        permute(ids.key.as_ref().unwrap(), &mut again, false);
+        assert_eq!(&again[..], &cid[..]);
+
+        // Another key reads something else
+        let other = ConnectionIds::new(Some(&[8; 32]), None).unwrap();
+        assert_ne!(other.decrypt(&cid).unwrap(), plain);
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        assert!(ConnectionIds::new(Some(&[7; 8]), None).is_err());
+        assert!(ConnectionIds::new(None, None).unwrap().decrypt(&[1; 8]).is_none());
+    }
+
+    #[test]
+    fn carries_the_worker_id() {
+        let key = Buffer::from(vec![7; 32]);
+        let ids = ConnectionIds::new(Some(&key), Some(0x1234)).unwrap();
+        let cid = ids.generate().unwrap();
+        assert_eq!(cid_worker_id(Either::A(cid.to_vec().into()), Some(key)).unwrap(), Some(0x1234));
+
+        let ids = ConnectionIds::new(None, Some(7)).unwrap();
+        let cid = ids.generate().unwrap();
+        assert_eq!(&cid[..2], &[0, 7]);
+        assert_eq!(cid_worker_id(Either::B(hex_id(&cid)), None).unwrap(), Some(7));
+        assert_eq!(cid_worker_id(Either::A(vec![0; 8].into()), None).unwrap(), None);
+        assert!(ConnectionIds::new(None, Some(0x10000)).is_err());
+    }
+}
@@ -0,0 +1,1001 @@
+use napi::bindgen_prelude::*;
+use napi::{JsDeferred, JsFunction, JsObject, JsUnknown};
+use napi_derive::napi;
+use quiche::{self, Config, RecvInfo};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::ClockWatch;
+use crate::congestion::CongestionWatch;
+use crate::context;
+use crate::config::{self, QuicConfig};
+use crate::digest::{Digesting, Digests};
+use crate::error_codes::TransportError;
+use crate::events::{event_callback, negotiated_alpn, EmitEvent, EventCallback, QuicEvent};
+use crate::framing::{message_bytes, Framers, Framing};
+use crate::http3::{to_h3_headers, H3RequestOptions, H3Response, HttpHeader};
+use crate::pending::PendingWrites;
+use crate::qlog::{QlogDir, QlogOutput};
+use crate::transport::{self, Transport};
+use crate::{
+    apply_datagram_options, apply_pacing_rate, close_code, close_connection, coalescing_window, enable_qlog,
+    flush_egress, h3_err_to_napi, hex_id, identity, io_err_to_napi, is_collected, load_trust_anchors, open_keylog,
+    peer_cert_chain, qlog_dir, quiche_err_to_napi, read_datagrams, read_streams, send_datagram, send_request_datagram,
+    set_stream_priority, start_qlog, stop_qlog, stream_readable_fin, stream_shutdown, to_stream_id,
+    DEFAULT_DGRAM_QUEUE_LEN, DatagramOptions, DatagramPolicy, MAX_DATAGRAM_SIZE, RECV_BUFFER_SIZE,
+};
+
+// Longest the loop waits in recv before re-reading conn.timeout(), so timers
+// armed by writes from the JS thread are picked up promptly
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Options for `connect()`.
+#[napi(object)]
+#[derive(Default)]
+pub struct QuicClientOptions {
+    /// Name used for SNI and certificate verification; defaults to `host`.
+    pub server_name: Option<String>,
+    /// Application protocols to offer, `["h3"]` by default.
+    pub alpn: Option<Vec<String>>,
+    /// Verify the server certificate against the system trust store and
+    /// `caFile`/`caDir` (default `true`).
+    pub verify_peer: Option<bool>,
+    /// Log TLS alerts that fail the handshake to stderr (default `true`).
+    pub log_tls_alerts: Option<bool>,
+    /// Caps the sending rate, in bytes per second. See `QuicServerOptions`.
+    pub max_pacing_rate_bps: Option<i64>,
+    /// Enables DATAGRAM frames; off unless given.
+    pub datagrams: Option<DatagramOptions>,
+    /// Transport parameters and congestion control settings.
+    pub config: Option<QuicConfig>,
+    /// Directory to write a qlog trace (`client-<connId>.sqlog`) of the
+    /// connection into. Requires building with the `qlog` cargo feature.
+    pub qlog_dir: Option<String>,
+    /// Compression and rotation of the `qlogDir` trace; see `QuicServerOptions`.
+    pub qlog_output: Option<QlogOutput>,
+    /// File to append TLS secrets to; see `QuicServerOptions`.
+    pub keylog_file: Option<String>,
+    /// PEM certificate chain and private key to present if the server asks
+    /// for a client certificate.
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// The same given as content instead; see `QuicServerOptions`.
+    pub cert: Option<Either<String, Buffer>>,
+    pub key: Option<Either<String, Buffer>>,
+    /// CA certificates to trust in addition to the system store, as a PEM
+    /// bundle and/or a hashed directory; see `QuicServerOptions`.
+    pub ca_file: Option<String>,
+    pub ca_dir: Option<String>,
+    /// Window, in microseconds, for which `streamSend()` writes are held
+    /// back so that tiny ones share packets; see `QuicServerOptions`.
+    pub write_coalescing_us: Option<u32>,
+    /// Local address to send from, which must be one of this host's; any
+    /// address of the server's family by default. `host` resolves to an
+    /// address of the same family as this one.
+    pub local_address: Option<String>,
+    /// Local UDP port to bind, an ephemeral one by default.
+    pub local_port: Option<u32>,
+    /// Network device to restrict the socket to (Linux only).
+    pub bind_device: Option<String>,
+    /// Keeps a long-lived connection up through network churn; off unless given.
+    pub keep_alive: Option<KeepAlive>,
+    /// QUIC version to offer first, for interop testing: one quiche
+    /// supports, or a reserved one (`0x?a?a?a?a`) to make the server answer
+    /// with Version Negotiation, after which QUIC v1 is used. QUIC v1 by default.
+    pub version: Option<u32>,
+}
+
+/// How a client keeps its connection up; see `QuicClientOptions.keepAlive`.
+#[napi(object)]
+pub struct KeepAlive {
+    /// Sends a PING this often once the handshake has completed, so NAT
+    /// bindings and the server's idle timer stay fresh.
+    pub interval_ms: Option<u32>,
+    /// Connects again after the connection times out or the server closes
+    /// it, resuming the TLS session if the server issued a ticket, up to
+    /// this many times in a row without completing a handshake (default 0:
+    /// never). Connections closed by `close()` or for a local error are not
+    /// replaced. Stream IDs start over on the new connection.
+    pub max_reconnects: Option<u32>,
+    /// How long to wait before each attempt, 1000 ms by default.
+    pub reconnect_delay_ms: Option<u32>,
+}
+
+// The packet loop's settings, fixed for the client's lifetime
+struct LoopOptions {
+    local: SocketAddr,
+    peer: SocketAddr,
+    log_tls_alerts: bool,
+    keep_alive: Option<Duration>,
+}
+
+// What it takes to replace a connection that dropped, under keepAlive
+struct Reconnect {
+    config: Config,
+    server_name: String,
+    qlog_dir: Option<QlogDir>,
+    keylog: Option<File>,
+    max_attempts: u32,
+    delay: Duration,
+    // Attempts in a row that did not complete a handshake
+    failed: u32,
+    // The latest TLS session the server issued a ticket for
+    session: Option<Vec<u8>>,
+}
+
+impl Reconnect {
+    // Opens a new connection in place of the closed one, unless this side
+    // closed it or the attempts are used up. Returns its ID.
+    fn replace(&mut self, shared: &Shared, options: &LoopOptions, established: bool) -> Option<String> {
+        {
+            let conn = shared.conn.lock().unwrap();
+            if let Some(session) = conn.session() {
+                self.session = Some(session.to_vec());
+            }
+            if conn.local_error().is_some() {
+                return None;
+            }
+        }
+        self.failed = if established { 0 } else { self.failed + 1 };
+        if self.failed >= self.max_attempts {
+            return None;
+        }
+        thread::sleep(self.delay);
+
+        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+        SystemRandom::new().fill(&mut scid).ok()?;
+        let conn_id = hex_id(&scid);
+        let scid = quiche::ConnectionId::from_ref(&scid);
+        let (local, peer) = (options.local, options.peer);
+        let mut conn = match quiche::connect(Some(&self.server_name), &scid, local, peer, &mut self.config) {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Failed to reconnect to {:?}: {:?}", options.peer, e);
+                return None;
+            }
+        };
+        if let Some(session) = &self.session {
+            if let Err(e) = conn.set_session(session) {
+                eprintln!("Failed to resume TLS session: {:?}", e);
+            }
+        }
+        if let Some(dir) = &self.qlog_dir {
+            if let Err(e) = enable_qlog(&mut conn, dir, &conn_id, "client") {
+                eprintln!("Failed to start qlog: {}", e);
+            }
+        }
+        if let Some(keylog) = self.keylog.as_ref().and_then(|file| file.try_clone().ok()) {
+            conn.set_keylog(Box::new(keylog));
+        }
+        println!("Reconnecting to {:?}", options.peer);
+
+        *shared.conn.lock().unwrap() = conn;
+        *shared.h3.lock().unwrap() = None;
+        *shared.framers.lock().unwrap() = Framers::default();
+        *shared.graceful_close.lock().unwrap() = None;
+        *shared.next_streams.lock().unwrap() = [0, 2];
+        *shared.digests.lock().unwrap() = Digests::default();
+        Some(conn_id)
+    }
+}
+
+// State touched by both the JS thread (writes) and the packet loop. When
+// both locks are needed, `conn` is taken first.
+struct Shared {
+    conn: Mutex<quiche::Connection>,
+    h3: Mutex<Option<H3Session>>,
+    socket: Box<dyn Transport + Send + Sync>,
+    // Window streamSend() writes are held back for, which the loop sends at
+    // least once in
+    coalescing: Mutex<Option<Duration>>,
+    // Streams carrying messages framed by setMessageFraming(); locked after conn
+    framers: Mutex<Framers>,
+    // Set by close() with a timeout until the close is sent; locked after h3
+    graceful_close: Mutex<Option<GracefulClose>>,
+    // Next client-initiated bidi and uni stream IDs openStream() hands out
+    next_streams: Mutex<[u64; 2]>,
+    // Streams whose data setStreamDigest() hashes; locked after h3
+    digests: Mutex<Digests>,
+    datagram_policy: DatagramPolicy,
+    // Datagrams the policy dropped
+    congestion_drops: AtomicU64,
+    // Writes made before the handshake let them go out; locked after conn
+    pending: Mutex<PendingWrites>,
+}
+
+// A close() waiting for the data already written to be acknowledged
+struct GracefulClose {
+    deadline: Instant,
+    application_error: bool,
+    error_code: u64,
+    reason: Vec<u8>,
+    // Streams opened by then that quiche still holds
+    open: Vec<u64>,
+}
+
+type ResponseDeferred = JsDeferred<H3Response, Box<dyn FnOnce(Env) -> Result<H3Response> + Send>>;
+
+// HTTP/3 layer of the connection, created once the handshake has negotiated h3
+struct H3Session {
+    h3: quiche::h3::Connection,
+    // Requests still waiting for their response head
+    responses: HashMap<u64, ResponseDeferred>,
+    // Request body bytes not yet accepted by flow control
+    pending_bodies: HashMap<u64, Vec<u8>>,
+}
+
+impl H3Session {
+    // Starts HTTP/3 on the connection if it is established and negotiated h3
+    fn start(conn: &mut quiche::Connection) -> Result<Option<H3Session>> {
+        if !conn.is_established() || conn.application_proto() != b"h3" {
+            return Ok(None);
+        }
+
+        let config = quiche::h3::Config::new().map_err(h3_err_to_napi)?;
+        let h3 = quiche::h3::Connection::with_transport(conn, &config).map_err(h3_err_to_napi)?;
+        Ok(Some(H3Session { h3, responses: HashMap::new(), pending_bodies: HashMap::new() }))
+    }
+
+    // Queues as much of a request body as flow control allows, keeping the rest
+    fn send_body(&mut self, conn: &mut quiche::Connection, stream_id: u64, body: Vec<u8>) -> Result<()> {
+        let written = match self.h3.send_body(conn, stream_id, &body, true) {
+            Ok(written) => written,
+            Err(quiche::h3::Error::Done) => 0,
+            Err(e) => return Err(h3_err_to_napi(e)),
+        };
+
+        if written < body.len() {
+            self.pending_bodies.insert(stream_id, body[written..].to_vec());
+        }
+        Ok(())
+    }
+
+    fn send_pending_bodies(&mut self, conn: &mut quiche::Connection) {
+        for (stream_id, body) in std::mem::take(&mut self.pending_bodies) {
+            if let Err(e) = self.send_body(conn, stream_id, body) {
+                eprintln!("Failed to send request body on stream {}: {}", stream_id, e.reason);
+                self.fail(stream_id, e);
+            }
+        }
+    }
+
+    // Handles every pending HTTP/3 event, settling h3Request() promises with
+    // response heads and delivering bodies as `data` events
+    fn poll(
+        &mut self,
+        conn: &mut quiche::Connection,
+        conn_id: &str,
+        peer: SocketAddr,
+        buf: &mut [u8],
+        events: &dyn EmitEvent,
+    ) {
+        loop {
+            match self.h3.poll(conn) {
+                Ok((stream_id, quiche::h3::Event::Headers { list, .. })) => {
+                    let deferred = match self.responses.remove(&stream_id) {
+                        Some(deferred) => deferred,
+                        // Trailers, or a stream this client never requested
+                        None => continue,
+                    };
+
+                    let headers: Vec<HttpHeader> = list.iter().map(HttpHeader::from).collect();
+                    let status = headers
+                        .iter()
+                        .find(|h| h.name == ":status")
+                        .and_then(|h| h.value.parse::<u32>().ok());
+                    let status = match status {
+                        Some(status) => status,
+                        None => {
+                            deferred.reject(napi::Error::from_reason("Response without a valid :status"));
+                            continue;
+                        }
+                    };
+
+                    events.emit(QuicEvent::response(conn_id, peer, stream_id, status, headers.clone()));
+
+                    let response = H3Response { stream_id: stream_id as i64, status, headers };
+                    deferred.resolve(Box::new(move |_| Ok(response)));
+                }
+                Ok((stream_id, quiche::h3::Event::Data)) => {
+                    // Everything readable goes out as one event
+                    let mut body = Vec::new();
+                    loop {
+                        match self.h3.recv_body(conn, stream_id, buf) {
+                            Ok(len) => body.extend_from_slice(&buf[..len]),
+                            Err(quiche::h3::Error::Done) => break,
+                            Err(e) => {
+                                eprintln!("Failed to read body on stream {}: {:?}", stream_id, e);
+                                break;
+                            }
+                        }
+                    }
+                    if !body.is_empty() {
+                        events.emit(QuicEvent::data(conn_id, peer, stream_id, body, false));
+                    }
+                }
+                Ok((stream_id, quiche::h3::Event::Finished)) => {
+                    events.emit(QuicEvent::data(conn_id, peer, stream_id, Vec::new(), true));
+                }
+                Ok((stream_id, quiche::h3::Event::Reset(code))) => {
+                    let message = format!("Stream {} reset with error code {:#x}", stream_id, code);
+                    self.fail(stream_id, napi::Error::from_reason(message));
+                    events.emit(QuicEvent::stream_reset(conn_id, peer, stream_id, code));
+                }
+                Ok((_, quiche::h3::Event::PriorityUpdate)) => (),
+                Ok((id, quiche::h3::Event::GoAway)) => {
+                    println!("Received GOAWAY ({}) from {:?}", id, peer);
+                }
+                Err(quiche::h3::Error::Done) => break,
+                Err(e) => {
+                    eprintln!("HTTP/3 error: {:?}", e);
+                    events.emit(QuicEvent::connection_error(conn_id, peer, format!("{:?}", e)));
+                    break;
+                }
+            }
+        }
+    }
+
+    // Rejects the request on `stream_id` if it is still waiting for a response
+    fn fail(&mut self, stream_id: u64, err: napi::Error) {
+        self.pending_bodies.remove(&stream_id);
+        if let Some(deferred) = self.responses.remove(&stream_id) {
+            deferred.reject(err);
+        }
+    }
+
+    fn fail_all(&mut self, reason: &str) {
+        self.pending_bodies.clear();
+        for (_, deferred) in self.responses.drain() {
+            deferred.reject(napi::Error::from_reason(reason.to_string()));
+        }
+    }
+}
+
+/// An outbound QUIC connection. The handshake and all packet I/O run on a
+/// background thread; events are delivered to the callback given to `connect()`.
+#[napi]
+pub struct QuicClient {
+    shared: Arc<Shared>,
+    // `:authority` for HTTP/3 requests
+    authority: String,
+}
+
+/// Opens a QUIC connection to `host:port` and starts the handshake.
+#[napi]
+pub fn connect(
+    host: String,
+    port: u32,
+    options: Option<QuicClientOptions>,
+    callback: JsFunction,
+) -> Result<QuicClient> {
+    let options = options.unwrap_or_default();
+    let port = u16::try_from(port).map_err(|_| napi::Error::from_reason("port must be between 0 and 65535"))?;
+
+    let local_ip = match options.local_address.as_deref() {
+        Some(address) => Some(
+            address
+                .parse::<IpAddr>()
+                .map_err(|_| napi::Error::from_reason(format!("localAddress {:?} is not an IP address", address)))?,
+        ),
+        None => None,
+    };
+    let local_port = u16::try_from(options.local_port.unwrap_or(0))
+        .map_err(|_| napi::Error::from_reason("localPort must be between 0 and 65535"))?;
+
+    let peer = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(io_err_to_napi)?
+        .find(|peer| local_ip.is_none_or(|ip| ip.is_ipv4() == peer.is_ipv4()))
+        .ok_or_else(|| match local_ip {
+            Some(ip) => napi::Error::from_reason(format!("{} has no address reachable from {}", host, ip)),
+            None => napi::Error::from_reason(format!("Could not resolve {}", host)),
+        })?;
+
+    let local_ip = local_ip.unwrap_or(match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    let socket =
+        transport::bind_udp((local_ip, local_port), options.bind_device.as_deref()).map_err(io_err_to_napi)?;
+    let local = socket.local_addr().map_err(io_err_to_napi)?;
+
+    let mut config = build_client_config(&options)?;
+    let keylog = open_keylog(&mut config, options.keylog_file.as_deref())?;
+    let socket: Box<dyn Transport + Send + Sync> =
+        if apply_pacing_rate(&mut config, options.max_pacing_rate_bps)? {
+            transport::paced(socket)
+        } else {
+            Box::new(socket)
+        };
+
+    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+    SystemRandom::new()
+        .fill(&mut scid)
+        .map_err(|_| napi::Error::from_reason("Failed to generate connection ID"))?;
+    let conn_id = hex_id(&scid);
+    let scid = quiche::ConnectionId::from_ref(&scid);
+
+    let server_name = options.server_name.as_deref().unwrap_or(&host);
+    let mut conn = quiche::connect(Some(server_name), &scid, local, peer, &mut config)
+        .map_err(quiche_err_to_napi)?;
+    if let Some(dir) = qlog_dir(options.qlog_dir.as_deref(), options.qlog_output.as_ref())? {
+        enable_qlog(&mut conn, &dir, &conn_id, "client").map_err(io_err_to_napi)?;
+    }
+    if let Some(keylog) = keylog.as_ref().map(File::try_clone).transpose().map_err(io_err_to_napi)? {
+        conn.set_keylog(Box::new(keylog));
+    }
+    println!("Connecting to {:?} from {:?}", peer, local);
+
+    let authority = if port == 443 { server_name.to_string() } else { format!("{}:{}", server_name, port) };
+
+    let coalescing = Mutex::new(coalescing_window(options.write_coalescing_us));
+    let send_queue_len = options.datagrams.as_ref().and_then(|datagrams| datagrams.send_queue_len);
+    let max_pending_datagrams = send_queue_len.unwrap_or(DEFAULT_DGRAM_QUEUE_LEN) as usize;
+    let framers = Mutex::new(Framers::default());
+    let shared = Arc::new(Shared {
+        conn: Mutex::new(conn),
+        h3: Mutex::new(None),
+        socket,
+        coalescing,
+        framers,
+        graceful_close: Mutex::new(None),
+        next_streams: Mutex::new([0, 2]),
+        digests: Mutex::new(Digests::default()),
+        datagram_policy: DatagramPolicy::parse(options.datagrams.as_ref())?,
+        congestion_drops: AtomicU64::new(0),
+        pending: Mutex::new(PendingWrites::new(max_pending_datagrams)),
+    });
+    let events = event_callback(callback)?;
+
+    let keep_alive = options.keep_alive.as_ref();
+    let loop_options = LoopOptions {
+        local,
+        peer,
+        log_tls_alerts: options.log_tls_alerts.unwrap_or(true),
+        keep_alive: keep_alive.and_then(|k| k.interval_ms).map(|ms| Duration::from_millis(ms.into())),
+    };
+    let mut reconnect = Reconnect {
+        config,
+        server_name: server_name.to_string(),
+        qlog_dir: qlog_dir(options.qlog_dir.as_deref(), options.qlog_output.as_ref())?,
+        keylog,
+        max_attempts: keep_alive.and_then(|k| k.max_reconnects).unwrap_or(0),
+        delay: Duration::from_millis(keep_alive.and_then(|k| k.reconnect_delay_ms).unwrap_or(1000).into()),
+        failed: 0,
+        session: None,
+    };
+    let loop_shared = shared.clone();
+    thread::Builder::new()
+        .name("quic-client".into())
+        .spawn(move || {
+            let (mut conn_id, mut previous) = (conn_id, None);
+            loop {
+                let ran = run_client(&loop_shared, &conn_id, previous.as_deref(), &loop_options, &events);
+                let established = match ran {
+                    Ok(established) => established,
+                    Err(e) => {
+                        eprintln!("QUIC client stopped: {}", e.reason);
+                        events.emit(QuicEvent::error(e.reason));
+                        return;
+                    }
+                };
+                match reconnect.replace(&loop_shared, &loop_options, established) {
+                    Some(next) => previous = Some(std::mem::replace(&mut conn_id, next)),
+                    None => return,
+                }
+            }
+        })
+        .map_err(io_err_to_napi)?;
+
+    Ok(QuicClient { shared, authority })
+}
+
+#[napi]
+impl QuicClient {
+    #[napi]
+    pub fn is_established(&self) -> bool {
+        self.shared.conn.lock().unwrap().is_established()
+    }
+
+    /// Reserves the next client-initiated stream ID. The stream is opened on
+    /// the wire by the first `streamSend()`.
+    #[napi]
+    pub fn open_stream(&self, bidirectional: Option<bool>) -> i64 {
+        let mut next_streams = self.shared.next_streams.lock().unwrap();
+        let next = &mut next_streams[if bidirectional.unwrap_or(true) { 0 } else { 1 }];
+
+        let stream_id = *next;
+        *next += 4;
+        stream_id as i64
+    }
+
+    /// Queues `data` on a stream and returns how many bytes were accepted,
+    /// which is less than `data.length` when flow control is exhausted.
+    /// Before the handshake completes, all of it is held natively and sent
+    /// once it does, or as 0-RTT data when the session is resumed with it.
+    #[napi]
+    pub fn stream_send(&self, stream_id: i64, data: Buffer, fin: bool) -> Result<u32> {
+        let stream_id = to_stream_id(stream_id)?;
+        let mut conn = self.shared.conn.lock().unwrap();
+
+        let mut pending = self.shared.pending.lock().unwrap();
+        if pending.holds_stream(&conn, stream_id) {
+            pending.stream_send(stream_id, &data, fin)?;
+            return Ok(data.len() as u32);
+        }
+        drop(pending);
+
+        let written = match conn.stream_send(stream_id, &data, fin) {
+            Ok(written) => written,
+            Err(quiche::Error::Done) => 0,
+            Err(e) => return Err(quiche_err_to_napi(e)),
+        };
+
+        // Held writes go out with the loop's next pass
+        if self.shared.coalescing.lock().unwrap().is_none() {
+            let mut out = [0; MAX_DATAGRAM_SIZE];
+            flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+        }
+
+        Ok(written as u32)
+    }
+
+    /// Sets how long, in microseconds, `streamSend()` writes are held back
+    /// before being sent, batching tiny ones into fewer packets; 0 turns it
+    /// off. See `QuicServer.setWriteCoalescing()`.
+    #[napi]
+    pub fn set_write_coalescing(&self, window_us: u32) {
+        *self.shared.coalescing.lock().unwrap() = coalescing_window(Some(window_us));
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut self.shared.conn.lock().unwrap(), &mut out);
+    }
+
+    /// Hashes what a stream delivers in `data` events from now on. See
+    /// `QuicServer.setStreamDigest()`.
+    #[napi(ts_args_type = "streamId: number, algorithm: 'sha256' | 'sha384' | 'sha512'")]
+    pub fn set_stream_digest(&self, stream_id: i64, algorithm: String) -> Result<()> {
+        self.shared.digests.lock().unwrap().start(to_stream_id(stream_id)?, &algorithm)
+    }
+
+    /// Keeps `context` with a stream, to be handed back as the `context` of
+    /// every event about it. See `QuicServer.setStreamContext()`.
+    #[napi(ts_args_type = "streamId: number, context: object | null")]
+    pub fn set_stream_context(&self, env: Env, stream_id: i64, context: Option<JsObject>) -> Result<()> {
+        to_stream_id(stream_id)?;
+        let conn_id = hex_id(self.shared.conn.lock().unwrap().source_id().as_ref());
+        context::set(env, &conn_id, stream_id, context)
+    }
+
+    /// Carries whole messages on a stream from now on. See
+    /// `QuicServer.setMessageFraming()`.
+    #[napi(ts_args_type = "streamId: number, framing: 'ndjson' | 'length', maxMessageBytes?: number")]
+    pub fn set_message_framing(&self, stream_id: i64, framing: String, max_message_bytes: Option<u32>) -> Result<()> {
+        let stream_id = to_stream_id(stream_id)?;
+        let framing = Framing::parse(&framing)?;
+        if self.shared.h3.lock().unwrap().is_some() {
+            return Err(napi::Error::from_reason("Message framing is only available on raw QUIC connections"));
+        }
+        self.shared.framers.lock().unwrap().set(stream_id, framing, max_message_bytes)
+    }
+
+    /// Sends one message on a stream given `setMessageFraming()`, queueing
+    /// what flow control holds back. See `QuicServer.sendMessage()`.
+    #[napi(ts_args_type = "streamId: number, message: unknown, fin?: boolean")]
+    pub fn send_message(
+        &self,
+        env: Env,
+        stream_id: i64,
+        message: Either3<Buffer, String, JsUnknown>,
+        fin: Option<bool>,
+    ) -> Result<u32> {
+        let stream_id = to_stream_id(stream_id)?;
+        let message = message_bytes(env, message)?;
+        let mut conn = self.shared.conn.lock().unwrap();
+        let queued = self.shared.framers.lock().unwrap().send(&mut conn, stream_id, &message, fin.unwrap_or(false))?;
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+        Ok(queued)
+    }
+
+    /// Whether the server's FIN has been read on a stream, with everything
+    /// before it: the stream will yield no more `data` events. Also `true`
+    /// for streams that are gone, or not opened yet.
+    #[napi]
+    pub fn stream_finished(&self, stream_id: i64) -> Result<bool> {
+        Ok(self.shared.conn.lock().unwrap().stream_finished(to_stream_id(stream_id)?))
+    }
+
+    /// Whether a stream has data, or its end, waiting to be read. `false`
+    /// while `streamFinished()` is too means nothing has arrived yet.
+    #[napi]
+    pub fn stream_readable_fin(&self, stream_id: i64) -> Result<bool> {
+        Ok(stream_readable_fin(&self.shared.conn.lock().unwrap(), to_stream_id(stream_id)?))
+    }
+
+    /// Abruptly ends one or both directions of a stream with an error code.
+    /// See `QuicServer.streamShutdown()`.
+    #[napi(ts_args_type = "streamId: number, direction: 'read' | 'write' | 'both', errorCode: number")]
+    pub fn stream_shutdown(&self, stream_id: i64, direction: String, error_code: i64) -> Result<()> {
+        let stream_id = to_stream_id(stream_id)?;
+        let mut conn = self.shared.conn.lock().unwrap();
+        stream_shutdown(&mut conn, stream_id, &direction, error_code)?;
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+        Ok(())
+    }
+
+    /// Sets a stream's urgency (0–7, lower is sent first; 3 by default) and
+    /// whether it shares bandwidth round-robin with streams of equal urgency.
+    #[napi]
+    pub fn set_stream_priority(&self, stream_id: i64, urgency: u32, incremental: bool) -> Result<()> {
+        set_stream_priority(&mut self.shared.conn.lock().unwrap(), to_stream_id(stream_id)?, urgency, incremental)
+    }
+
+    /// The largest datagram payload `datagramSend()` accepts now, or `null`
+    /// if the server does not accept datagrams. It follows the path MTU, so
+    /// it may grow during the connection.
+    #[napi]
+    pub fn dgram_max_writable_len(&self) -> Option<u32> {
+        self.shared.conn.lock().unwrap().dgram_max_writable_len().map(|len| len as u32)
+    }
+
+    /// Outgoing datagrams dropped by the `drop-when-congested` policy.
+    #[napi]
+    pub fn congestion_dropped_datagrams(&self) -> i64 {
+        self.shared.congestion_drops.load(Ordering::Relaxed) as i64
+    }
+
+    /// Queues `data` as a DATAGRAM frame. Returns `false` if it was dropped
+    /// because the send queue is full. Before the handshake completes,
+    /// datagrams are held natively, up to `sendQueueLen`, and sent once it
+    /// does or 0-RTT allows.
+    #[napi]
+    pub fn datagram_send(&self, data: Buffer) -> Result<bool> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        let mut pending = self.shared.pending.lock().unwrap();
+        if pending.holds_datagrams(&conn) {
+            return Ok(pending.datagram_send(&data));
+        }
+        drop(pending);
+
+        let queued = send_datagram(&mut conn, &data, self.shared.datagram_policy, &self.shared.congestion_drops)?;
+
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+
+        Ok(queued)
+    }
+
+    /// Queues `data` as an HTTP Datagram (RFC 9297) tied to a request's
+    /// stream. Returns `false` if it was dropped because the send queue is
+    /// full. The server's arrive as `datagram` events with the `streamId`.
+    #[napi]
+    pub fn send_request_datagram(&self, stream_id: i64, data: Buffer) -> Result<bool> {
+        let stream_id = to_stream_id(stream_id)?;
+        let mut conn = self.shared.conn.lock().unwrap();
+        let h3 = self.shared.h3.lock().unwrap();
+        let session = h3.as_ref().ok_or_else(|| {
+            napi::Error::from_reason("HTTP/3 is not available until the handshake completes with ALPN h3")
+        })?;
+        let (policy, drops) = (self.shared.datagram_policy, &self.shared.congestion_drops);
+        let queued = send_request_datagram(&session.h3, &mut conn, stream_id, &data, policy, drops)?;
+
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+
+        Ok(queued)
+    }
+
+    /// Sends an HTTP/3 request once the handshake has completed with ALPN
+    /// `h3`. The returned promise resolves with the response head; the body
+    /// arrives as `data` events for its `streamId`, after a `response` event.
+    /// Streams reserved with `openStream()` must not be mixed with requests.
+    #[napi(ts_return_type = "Promise<H3Response>")]
+    pub fn h3_request(&self, env: Env, request: H3RequestOptions) -> Result<JsObject> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        let mut h3 = self.shared.h3.lock().unwrap();
+
+        if h3.is_none() {
+            *h3 = H3Session::start(&mut conn)?;
+        }
+        let session = h3.as_mut().ok_or_else(|| {
+            napi::Error::from_reason("HTTP/3 is not available until the handshake completes with ALPN h3")
+        })?;
+
+        let method = request.method.as_deref().unwrap_or("GET");
+        let mut headers = vec![
+            HttpHeader { name: ":method".into(), value: method.into() },
+            HttpHeader { name: ":scheme".into(), value: "https".into() },
+            HttpHeader { name: ":authority".into(), value: self.authority.clone() },
+            HttpHeader { name: ":path".into(), value: request.path },
+        ];
+        headers.extend(request.headers.unwrap_or_default());
+
+        let body = request.body.map(|body| body.to_vec()).filter(|body| !body.is_empty());
+        let stream_id = session
+            .h3
+            .send_request(&mut conn, &to_h3_headers(&headers), body.is_none())
+            .map_err(h3_err_to_napi)?;
+
+        if let Some(body) = body {
+            session.send_body(&mut conn, stream_id, body)?;
+        }
+
+        let (deferred, promise) = env.create_deferred()?;
+        session.responses.insert(stream_id, deferred);
+
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+
+        Ok(promise)
+    }
+
+    /// The application protocol negotiated with the server, or `null` until
+    /// the handshake has chosen one.
+    #[napi]
+    pub fn alpn(&self) -> Option<String> {
+        negotiated_alpn(&self.shared.conn.lock().unwrap())
+    }
+
+    /// Returns the DER certificates the server presented, leaf first, or
+    /// `null` before the handshake has completed.
+    #[napi]
+    pub fn peer_cert_chain(&self) -> Option<Vec<Buffer>> {
+        peer_cert_chain(&self.shared.conn.lock().unwrap())
+    }
+
+    /// Starts writing a qlog trace of the connection to a new file at
+    /// `path`, replacing any trace it was writing. See
+    /// `QuicServer.startQlog()`.
+    #[napi]
+    pub fn start_qlog(&self, path: String) -> Result<()> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        let conn_id = hex_id(conn.source_id().as_ref());
+        start_qlog(&mut conn, Path::new(&path), &conn_id, "client").map_err(io_err_to_napi)
+    }
+
+    /// Stops the connection's qlog trace and flushes its file.
+    #[napi]
+    pub fn stop_qlog(&self) {
+        stop_qlog(&mut self.shared.conn.lock().unwrap());
+    }
+
+    /// Closes the connection, with NO_ERROR unless an error code is given.
+    /// It is a transport error code, or an application one if
+    /// `applicationError` is set. A `closed` event follows once the draining
+    /// period ends. With `timeoutMs`, the close waits until the streams
+    /// opened so far are done, with everything written to them acknowledged
+    /// and the server's side read, and HTTP/3 requests have their responses,
+    /// or until `timeoutMs` runs out. Streams must be finished to be done.
+    #[napi(
+        ts_args_type = "errorCode?: number, applicationError?: boolean, reason?: Buffer, timeoutMs?: number"
+    )]
+    pub fn close(
+        &self,
+        error_code: Option<i64>,
+        application_error: Option<bool>,
+        reason: Option<Buffer>,
+        timeout_ms: Option<u32>,
+    ) -> Result<()> {
+        let application_error = application_error.unwrap_or(false);
+        let error_code = error_code.unwrap_or(TransportError::NoError as i64);
+        let reason = reason.as_deref().unwrap_or_default();
+
+        if let Some(ms) = timeout_ms {
+            let [next_bidi, next_uni] = *self.shared.next_streams.lock().unwrap();
+            let bidi = (0..next_bidi).step_by(4);
+            let uni = (2..next_uni).step_by(4);
+            *self.shared.graceful_close.lock().unwrap() = Some(GracefulClose {
+                deadline: Instant::now() + Duration::from_millis(ms.into()),
+                application_error,
+                error_code: close_code(error_code)?,
+                reason: reason.to_vec(),
+                open: bidi.chain(uni).collect(),
+            });
+            return Ok(());
+        }
+
+        let mut conn = self.shared.conn.lock().unwrap();
+        close_connection(&mut conn, application_error, error_code, reason)?;
+
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        flush_egress(self.shared.socket.as_ref(), &mut conn, &mut out);
+        Ok(())
+    }
+}
+
+fn build_client_config(options: &QuicClientOptions) -> Result<Config> {
+    let version = options.version.unwrap_or(quiche::PROTOCOL_VERSION);
+    let mut config = Config::new(version).map_err(|e| match e {
+        quiche::Error::UnknownVersion => {
+            napi::Error::from_reason(format!("QUIC version {:#x} is neither supported nor reserved", version))
+        }
+        e => quiche_err_to_napi(e),
+    })?;
+
+    let alpn: Vec<Vec<u8>> = match &options.alpn {
+        Some(protos) => protos.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        None => vec![b"h3".to_vec()],
+    };
+    let alpn: Vec<&[u8]> = alpn.iter().map(|p| p.as_slice()).collect();
+    config.set_application_protos(&alpn).map_err(|e| {
+        napi::Error::from_reason(format!("Failed to set ALPN protocols: {:?}", e))
+    })?;
+
+    let identity = identity(
+        options.cert_path.as_deref(),
+        options.cert.as_ref(),
+        options.key_path.as_deref(),
+        options.key.as_ref(),
+    )?;
+    if let Some((cert, key)) = identity {
+        config.load_cert_chain_from_pem_file(&cert.path).map_err(quiche_err_to_napi)?;
+        config.load_priv_key_from_pem_file(&key.path).map_err(quiche_err_to_napi)?;
+    }
+
+    load_trust_anchors(&mut config, options.ca_file.as_deref(), options.ca_dir.as_deref())?;
+    config.verify_peer(options.verify_peer.unwrap_or(true));
+    config::apply(&mut config, options.config.as_ref().unwrap_or(&QuicConfig::default()), false)?;
+    apply_datagram_options(&mut config, options.datagrams.as_ref());
+
+    Ok(config)
+}
+
+// Drives the handshake and the connection's timers until the connection
+// closes, and returns whether its handshake completed. `previous` is the
+// connection this one replaces under keepAlive.
+fn run_client(
+    shared: &Shared,
+    conn_id: &str,
+    previous: Option<&str>,
+    options: &LoopOptions,
+    events: &EventCallback,
+) -> Result<bool> {
+    let LoopOptions { local, peer, log_tls_alerts, keep_alive } = *options;
+    let mut next_ping = keep_alive.map(|interval| Instant::now() + interval);
+    let mut buf = [0; RECV_BUFFER_SIZE];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+    let mut handshake_done = false;
+    let mut early_data_ready = false;
+    let mut tls_alert_reported = false;
+    let mut clock = ClockWatch::new();
+    let mut congestion = CongestionWatch::default();
+
+    // Send the first Initial flight
+    flush_egress(shared.socket.as_ref(), &mut shared.conn.lock().unwrap(), &mut out);
+
+    loop {
+        if let Some(offset) = clock.check() {
+            eprintln!("System clock jumped by {} ms relative to monotonic time", offset);
+            events.emit(QuicEvent::clock_jump(offset));
+        }
+
+        let timeout = shared.conn.lock().unwrap().timeout();
+        let wait = timeout.map_or(MAX_POLL_INTERVAL, |t| t.min(MAX_POLL_INTERVAL));
+        // Held writes are flushed at the end of the pass
+        let coalescing = *shared.coalescing.lock().unwrap();
+        let wait = coalescing.map_or(wait, |window| wait.min(window.max(Duration::from_millis(1))));
+        let wait = next_ping.map_or(wait, |at| wait.min(at.saturating_duration_since(Instant::now())));
+
+        let received = if wait.is_zero() {
+            Err(io::ErrorKind::TimedOut.into())
+        } else {
+            shared.socket.set_read_timeout(Some(wait)).map_err(io_err_to_napi)?;
+            shared.socket.recv_from(&mut buf)
+        };
+
+        let mut conn = shared.conn.lock().unwrap();
+
+        match received {
+            Ok((len, _)) if len >= buf.len() => {
+                eprintln!("Dropping oversized datagram from {:?}", peer);
+            }
+            Ok((len, from)) => {
+                if let Err(e) = conn.recv(&mut buf[..len], RecvInfo { from, to: local }) {
+                    eprintln!("QUIC recv error: {:?}", e);
+                    events.emit(QuicEvent::connection_error(conn_id, peer, format!("{:?}", e)));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                conn.on_timeout();
+            }
+            Err(e) => return Err(io_err_to_napi(e)),
+        }
+
+        if let Some((before, after)) = congestion.check(&conn) {
+            events.emit(QuicEvent::congestion(conn_id, peer, before, after));
+        }
+
+        if !tls_alert_reported {
+            if let Some(event) = QuicEvent::tls_alert(conn_id, peer, &conn) {
+                tls_alert_reported = true;
+                if log_tls_alerts {
+                    eprintln!("{}", event.message.as_deref().unwrap_or_default());
+                }
+                events.emit(event);
+            }
+        }
+
+        if !early_data_ready && conn.is_in_early_data() {
+            early_data_ready = true;
+            events.emit(QuicEvent::early_data_ready(conn_id, peer, &conn));
+        }
+
+        if !handshake_done && conn.is_established() {
+            handshake_done = true;
+            println!("Handshake done with {:?}", peer);
+            events.emit(QuicEvent::handshake_done(conn_id, peer, &conn));
+            events.emit(QuicEvent::datagram_support(conn_id, peer, &conn));
+            if let Some(previous) = previous {
+                events.emit(QuicEvent::reconnected(conn_id, peer, previous, &conn));
+            }
+        }
+
+        if let (Some(at), Some(interval)) = (next_ping, keep_alive) {
+            if Instant::now() >= at {
+                if conn.is_established() {
+                    let _ = conn.send_ack_eliciting();
+                }
+                next_ping = Some(Instant::now() + interval);
+            }
+        }
+
+        shared.pending.lock().unwrap().flush(&mut conn, shared.datagram_policy, &shared.congestion_drops);
+
+        let mut h3 = shared.h3.lock().unwrap();
+        if h3.is_none() {
+            match H3Session::start(&mut conn) {
+                Ok(session) => *h3 = session,
+                Err(e) => eprintln!("Failed to start HTTP/3: {}", e.reason),
+            }
+        }
+
+        read_datagrams(&mut conn, conn_id, peer, &mut buf, events, h3.is_some());
+
+        let mut digests = shared.digests.lock().unwrap();
+        let events = &Digesting::new(&mut digests, events);
+        match h3.as_mut() {
+            Some(session) => {
+                session.send_pending_bodies(&mut conn);
+                session.poll(&mut conn, conn_id, peer, &mut buf, events);
+            }
+            None => {
+                let mut framers = shared.framers.lock().unwrap();
+                framers.flush(&mut conn);
+                framers.read(&mut conn, conn_id, peer, &mut buf, events);
+                read_streams(&mut conn, conn_id, peer, &mut buf, events, |id| framers.contains(id));
+            }
+        }
+
+        let mut graceful_close = shared.graceful_close.lock().unwrap();
+        if let Some(close) = graceful_close.as_mut() {
+            close.open.retain(|&id| !is_collected(&conn, id));
+            let answered = h3.as_ref().is_none_or(|s| s.responses.is_empty() && s.pending_bodies.is_empty());
+            let sent = shared.pending.lock().unwrap().is_empty();
+            if (close.open.is_empty() && answered && sent) || Instant::now() >= close.deadline {
+                let close = graceful_close.take().unwrap();
+                let _ = conn.close(close.application_error, close.error_code, &close.reason);
+            }
+        }
+        drop(graceful_close);
+        flush_egress(shared.socket.as_ref(), &mut conn, &mut out);
+
+        if conn.is_closed() {
+            println!("Connection to {:?} closed", peer);
+            if let Some(session) = h3.as_mut() {
+                session.fail_all("Connection closed");
+            }
+            events.emit(QuicEvent::closed(conn_id, peer, &conn));
+            return Ok(handshake_done);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant, SystemTime};
+
+// Divergence between wall-clock and monotonic time that counts as a jump.
+// Loop iterations are far shorter than this, so scheduling jitter never trips it.
+const JUMP_THRESHOLD: Duration = Duration::from_secs(1);
+
+// Notices when the wall clock moves differently from the monotonic clock,
+// as it does when a VM is paused or a laptop resumes from suspend. All timer
+// math uses Instant, so a jump cannot fire or stall quiche's timers; it is
+// only reported so applications can tell why peers may have timed out.
+pub(crate) struct ClockWatch {
+    monotonic: Instant,
+    wall: SystemTime,
+}
+
+impl ClockWatch {
+    pub(crate) fn new() -> Self {
+        ClockWatch { monotonic: Instant::now(), wall: SystemTime::now() }
+    }
+
+    // Returns how far, in milliseconds, the wall clock moved beyond the
+    // monotonic clock since the last check (negative if it went backwards)
+    pub(crate) fn check(&mut self) -> Option<i64> {
+        let monotonic = Instant::now();
+        let wall = SystemTime::now();
+
+        let elapsed = monotonic.duration_since(self.monotonic).as_millis() as i64;
+        let wall_elapsed = match wall.duration_since(self.wall) {
+            Ok(forward) => forward.as_millis() as i64,
+            Err(backward) => -(backward.duration().as_millis() as i64),
+        };
+
+        self.monotonic = monotonic;
+        self.wall = wall;
+
+        let jump = wall_elapsed - elapsed;
+        if jump.unsigned_abs() as u128 > JUMP_THRESHOLD.as_millis() {
+            Some(jump)
+        } else {
+            None
+        }
+    }
+}
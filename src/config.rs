@@ -0,0 +1,247 @@
+use napi_derive::napi;
+use quiche::Config;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::http3::RequestPolicy;
+use crate::{h3_err_to_napi, quiche_err_to_napi, MAX_DATAGRAM_SIZE};
+
+/// Transport settings shared by servers and clients. Every field is optional;
+/// omitted ones keep the defaults noted below.
+#[napi(object)]
+#[derive(Clone, Default)]
+pub struct QuicConfig {
+    /// Idle timeout in milliseconds (default 5000; 0 disables it).
+    pub max_idle_timeout_ms: Option<i64>,
+    /// Largest UDP payload accepted and sent, in bytes (default 1350).
+    pub max_recv_udp_payload_size: Option<u32>,
+    pub max_send_udp_payload_size: Option<u32>,
+    /// Connection-level flow control window, in bytes (default 10 MB).
+    pub initial_max_data: Option<i64>,
+    /// Per-stream flow control windows, in bytes (default 1 MB each).
+    pub initial_max_stream_data_bidi_local: Option<i64>,
+    pub initial_max_stream_data_bidi_remote: Option<i64>,
+    pub initial_max_stream_data_uni: Option<i64>,
+    /// Streams the peer may open concurrently (default 100 of each type).
+    pub initial_max_streams_bidi: Option<i64>,
+    pub initial_max_streams_uni: Option<i64>,
+    /// Upper bounds for flow control window auto-tuning, in bytes.
+    pub max_connection_window: Option<i64>,
+    pub max_stream_window: Option<i64>,
+    pub ack_delay_exponent: Option<i64>,
+    /// Maximum ACK delay in milliseconds.
+    pub max_ack_delay_ms: Option<i64>,
+    pub active_connection_id_limit: Option<i64>,
+    /// Forbid the peer from migrating to a new address (default `true`).
+    pub disable_active_migration: Option<bool>,
+    /// Accept (server) or send (client) 0-RTT data. Defaults to `true` on
+    /// servers and `false` on clients.
+    pub early_data: Option<bool>,
+    /// `"cubic"` (default), `"reno"`, `"bbr"` or `"bbr2"`.
+    pub congestion_control: Option<String>,
+    pub initial_congestion_window_packets: Option<u32>,
+    pub enable_hystart: Option<bool>,
+    pub enable_pacing: Option<bool>,
+    /// Send GREASE values to exercise peers' extension points (default `true`).
+    pub grease: Option<bool>,
+    /// Probe for a larger path MTU than the initial payload size.
+    pub discover_pmtu: Option<bool>,
+}
+
+impl QuicConfig {
+    // Takes the fields set in `update`, keeping the others
+    pub(crate) fn merge(&mut self, update: QuicConfig) {
+        let QuicConfig {
+            max_idle_timeout_ms,
+            max_recv_udp_payload_size,
+            max_send_udp_payload_size,
+            initial_max_data,
+            initial_max_stream_data_bidi_local,
+            initial_max_stream_data_bidi_remote,
+            initial_max_stream_data_uni,
+            initial_max_streams_bidi,
+            initial_max_streams_uni,
+            max_connection_window,
+            max_stream_window,
+            ack_delay_exponent,
+            max_ack_delay_ms,
+            active_connection_id_limit,
+            disable_active_migration,
+            early_data,
+            congestion_control,
+            initial_congestion_window_packets,
+            enable_hystart,
+            enable_pacing,
+            grease,
+            discover_pmtu,
+        } = update;
+
+        self.max_idle_timeout_ms = max_idle_timeout_ms.or(self.max_idle_timeout_ms);
+        self.max_recv_udp_payload_size = max_recv_udp_payload_size.or(self.max_recv_udp_payload_size);
+        self.max_send_udp_payload_size = max_send_udp_payload_size.or(self.max_send_udp_payload_size);
+        self.initial_max_data = initial_max_data.or(self.initial_max_data);
+        self.initial_max_stream_data_bidi_local =
+            initial_max_stream_data_bidi_local.or(self.initial_max_stream_data_bidi_local);
+        self.initial_max_stream_data_bidi_remote =
+            initial_max_stream_data_bidi_remote.or(self.initial_max_stream_data_bidi_remote);
+        self.initial_max_stream_data_uni = initial_max_stream_data_uni.or(self.initial_max_stream_data_uni);
+        self.initial_max_streams_bidi = initial_max_streams_bidi.or(self.initial_max_streams_bidi);
+        self.initial_max_streams_uni = initial_max_streams_uni.or(self.initial_max_streams_uni);
+        self.max_connection_window = max_connection_window.or(self.max_connection_window);
+        self.max_stream_window = max_stream_window.or(self.max_stream_window);
+        self.ack_delay_exponent = ack_delay_exponent.or(self.ack_delay_exponent);
+        self.max_ack_delay_ms = max_ack_delay_ms.or(self.max_ack_delay_ms);
+        self.active_connection_id_limit = active_connection_id_limit.or(self.active_connection_id_limit);
+        self.disable_active_migration = disable_active_migration.or(self.disable_active_migration);
+        self.early_data = early_data.or(self.early_data);
+        self.congestion_control = congestion_control.or(self.congestion_control.take());
+        self.initial_congestion_window_packets =
+            initial_congestion_window_packets.or(self.initial_congestion_window_packets);
+        self.enable_hystart = enable_hystart.or(self.enable_hystart);
+        self.enable_pacing = enable_pacing.or(self.enable_pacing);
+        self.grease = grease.or(self.grease);
+        self.discover_pmtu = discover_pmtu.or(self.discover_pmtu);
+    }
+}
+
+// Applies `options` on top of the binding's defaults
+pub(crate) fn apply(config: &mut Config, options: &QuicConfig, is_server: bool) -> napi::Result<()> {
+    config.set_max_idle_timeout(u64_option("maxIdleTimeoutMs", options.max_idle_timeout_ms)?.unwrap_or(5000));
+    config.set_max_recv_udp_payload_size(
+        options.max_recv_udp_payload_size.map_or(MAX_DATAGRAM_SIZE, |v| v as usize),
+    );
+    config.set_max_send_udp_payload_size(
+        options.max_send_udp_payload_size.map_or(MAX_DATAGRAM_SIZE, |v| v as usize),
+    );
+    config.set_initial_max_data(u64_option("initialMaxData", options.initial_max_data)?.unwrap_or(10_000_000));
+    config.set_initial_max_stream_data_bidi_local(
+        u64_option("initialMaxStreamDataBidiLocal", options.initial_max_stream_data_bidi_local)?
+            .unwrap_or(1_000_000),
+    );
+    config.set_initial_max_stream_data_bidi_remote(
+        u64_option("initialMaxStreamDataBidiRemote", options.initial_max_stream_data_bidi_remote)?
+            .unwrap_or(1_000_000),
+    );
+    config.set_initial_max_stream_data_uni(
+        u64_option("initialMaxStreamDataUni", options.initial_max_stream_data_uni)?.unwrap_or(1_000_000),
+    );
+    config.set_initial_max_streams_bidi(
+        u64_option("initialMaxStreamsBidi", options.initial_max_streams_bidi)?.unwrap_or(100),
+    );
+    config.set_initial_max_streams_uni(
+        u64_option("initialMaxStreamsUni", options.initial_max_streams_uni)?.unwrap_or(100),
+    );
+    config.set_disable_active_migration(options.disable_active_migration.unwrap_or(true));
+
+    if let Some(v) = u64_option("maxConnectionWindow", options.max_connection_window)? {
+        config.set_max_connection_window(v);
+    }
+    if let Some(v) = u64_option("maxStreamWindow", options.max_stream_window)? {
+        config.set_max_stream_window(v);
+    }
+    if let Some(v) = u64_option("ackDelayExponent", options.ack_delay_exponent)? {
+        config.set_ack_delay_exponent(v);
+    }
+    if let Some(v) = u64_option("maxAckDelayMs", options.max_ack_delay_ms)? {
+        config.set_max_ack_delay(v);
+    }
+    if let Some(v) = u64_option("activeConnectionIdLimit", options.active_connection_id_limit)? {
+        config.set_active_connection_id_limit(v);
+    }
+
+    if options.early_data.unwrap_or(is_server) {
+        config.enable_early_data();
+    }
+
+    if let Some(name) = &options.congestion_control {
+        config.set_cc_algorithm_name(name).map_err(quiche_err_to_napi)?;
+    }
+    if let Some(packets) = options.initial_congestion_window_packets {
+        config.set_initial_congestion_window_packets(packets as usize);
+    }
+    if let Some(v) = options.enable_hystart {
+        config.enable_hystart(v);
+    }
+    if let Some(v) = options.enable_pacing {
+        config.enable_pacing(v);
+    }
+    if let Some(v) = options.grease {
+        config.grease(v);
+    }
+    if let Some(v) = options.discover_pmtu {
+        config.discover_pmtu(v);
+    }
+
+    Ok(())
+}
+
+/// HTTP/3 SETTINGS advertised on connections that negotiated one ALPN.
+#[napi(object)]
+#[derive(Default)]
+pub struct Http3Settings {
+    /// Largest header section accepted, in bytes (unlimited by default).
+    /// The client is told, and a larger one closes the connection with
+    /// H3_EXCESSIVE_LOAD; `maxRequestHeaderBytes` refuses just the request.
+    pub max_field_section_size: Option<i64>,
+    /// Most header fields, pseudo-headers included, and bytes a request
+    /// head may carry, each field counting its name and value plus 32 as in
+    /// RFC 9114. A request over either is answered 431 and not delivered,
+    /// and counted in `metrics()`. Unlimited by default.
+    pub max_request_headers: Option<u32>,
+    pub max_request_header_bytes: Option<u32>,
+    /// Most body bytes a request may carry, and how long, in milliseconds,
+    /// its body may go without progress before it is complete. A request
+    /// over the size is answered 413, one that stalls 408, and the rest of
+    /// its body refused; if the application has already started answering,
+    /// the stream is reset instead. Both are counted in `metrics()`, and
+    /// neither applies to bodies piped to files. Unlimited by default.
+    pub max_request_body_bytes: Option<i64>,
+    pub request_body_timeout_ms: Option<u32>,
+    /// QPACK dynamic table size and blocked streams (both 0 by default).
+    pub qpack_max_table_capacity: Option<i64>,
+    pub qpack_blocked_streams: Option<i64>,
+    /// Advertise extended CONNECT (RFC 9220), as WebTransport and WebSockets
+    /// over HTTP/3 require (default `false`).
+    pub extended_connect: Option<bool>,
+    /// Whether connections that negotiate this ALPN get DATAGRAM frames,
+    /// and with them HTTP Datagrams (RFC 9297), which quiche advertises
+    /// whenever the transport allows them. Follows the server's `datagrams`
+    /// option by default; `true` enables them with its queue lengths, or
+    /// the defaults.
+    pub datagrams: Option<bool>,
+}
+
+// Builds the HTTP/3 configuration for one ALPN
+pub(crate) fn h3_config(settings: &Http3Settings) -> napi::Result<quiche::h3::Config> {
+    let mut config = quiche::h3::Config::new().map_err(h3_err_to_napi)?;
+
+    if let Some(v) = u64_option("maxFieldSectionSize", settings.max_field_section_size)? {
+        config.set_max_field_section_size(v);
+    }
+    if let Some(v) = u64_option("qpackMaxTableCapacity", settings.qpack_max_table_capacity)? {
+        config.set_qpack_max_table_capacity(v);
+    }
+    if let Some(v) = u64_option("qpackBlockedStreams", settings.qpack_blocked_streams)? {
+        config.set_qpack_blocked_streams(v);
+    }
+    config.enable_extended_connect(settings.extended_connect.unwrap_or(false));
+
+    Ok(config)
+}
+
+// What the settings allow of requests, checked as they arrive
+pub(crate) fn request_policy(settings: &Http3Settings) -> napi::Result<RequestPolicy> {
+    Ok(RequestPolicy {
+        max_headers: settings.max_request_headers.map(|n| n as usize),
+        max_header_bytes: settings.max_request_header_bytes.map(|n| n as usize),
+        max_body_bytes: u64_option("maxRequestBodyBytes", settings.max_request_body_bytes)?,
+        body_timeout: settings.request_body_timeout_ms.map(|ms| Duration::from_millis(ms.into())),
+        extended_connect: settings.extended_connect.unwrap_or(false),
+    })
+}
+
+fn u64_option(name: &str, value: Option<i64>) -> napi::Result<Option<u64>> {
+    value
+        .map(|v| u64::try_from(v).map_err(|_| napi::Error::from_reason(format!("{} must not be negative", name))))
+        .transpose()
+}
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+// Reactions closer together than this are merged into one event, so a burst
+// of losses cannot flood the JS thread
+const MIN_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Notices when a connection's congestion controller shrinks the window in
+// response to loss. quiche reports no congestion events of its own, so this
+// compares the active path's cwnd and loss count between checks. quiche
+// 0.22 neither sends nor reads ECN, so `ece` reactions never occur.
+#[derive(Default)]
+pub(crate) struct CongestionWatch {
+    cwnd: usize,
+    lost: usize,
+    // cwnd before a reduction that has not been reported yet
+    pending: Option<usize>,
+    last_event: Option<Instant>,
+}
+
+impl CongestionWatch {
+    // Returns the window before and after the latest reaction, once per
+    // MIN_EVENT_INTERVAL at most
+    pub(crate) fn check(&mut self, conn: &quiche::Connection) -> Option<(usize, usize)> {
+        let path = conn.path_stats().find(|p| p.active)?;
+
+        if path.cwnd < self.cwnd && path.lost > self.lost {
+            self.pending.get_or_insert(self.cwnd);
+        }
+        self.cwnd = path.cwnd;
+        self.lost = path.lost;
+
+        let before = self.pending?;
+        let now = Instant::now();
+        if self.last_event.is_some_and(|last| now.duration_since(last) < MIN_EVENT_INTERVAL) {
+            return None;
+        }
+
+        // The window may have grown back while the report was held
+        self.pending = None;
+        if path.cwnd >= before {
+            return None;
+        }
+        self.last_event = Some(now);
+        Some((before, path.cwnd))
+    }
+}
+
+// Whether more than `fraction` of the active path's congestion window is in
+// flight. quiche does not expose bytes in flight, so they are taken to be
+// the bytes sent less those acknowledged or declared lost, which slightly
+// overstates them by counting packets that carry only ACKs.
+pub(crate) fn congested(conn: &quiche::Connection, fraction: f64) -> bool {
+    let Some(path) = conn.path_stats().find(|p| p.active) else {
+        return false;
+    };
+    let stats = conn.stats();
+    let in_flight = stats.sent_bytes.saturating_sub(stats.acked_bytes + stats.lost_bytes);
+    in_flight as f64 > path.cwnd as f64 * fraction
+}
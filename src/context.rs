@@ -0,0 +1,83 @@
+use napi::{Env, JsObject, JsUnknown, Ref, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Values given setStreamContext(), by connection ID and stream. References
+// can only be made and used on the JS thread, so they live there, and are
+// attached to events as they reach JavaScript.
+thread_local! {
+    static CONTEXTS: RefCell<HashMap<(String, i64), Ref<()>>> = RefCell::new(HashMap::new());
+}
+
+// Keeps `context` for a stream, replacing any it had, or forgets the
+// stream's context when None
+pub(crate) fn set(env: Env, conn_id: &str, stream_id: i64, context: Option<JsObject>) -> Result<()> {
+    let context = context.map(|context| env.create_reference(context)).transpose()?;
+    let previous = CONTEXTS.with(|contexts| {
+        let mut contexts = contexts.borrow_mut();
+        let key = (conn_id.to_string(), stream_id);
+        match context {
+            Some(context) => contexts.insert(key, context),
+            None => contexts.remove(&key),
+        }
+    });
+    if let Some(mut previous) = previous {
+        previous.unref(env)?;
+    }
+    Ok(())
+}
+
+// Adds its stream's context to an event about to reach JavaScript as
+// `context`. The context is let go of after the last event of its stream:
+// `streamFinAcked` once both directions are done, `streamReset`,
+// `requestRejected`, the FIN of a unidirectional stream, or the
+// connection's `closed`.
+pub(crate) fn attach(
+    env: Env,
+    event: &JsUnknown,
+    kind: &str,
+    conn_id: &str,
+    stream_id: Option<i64>,
+    fin: bool,
+) -> Result<()> {
+    let Some(stream_id) = stream_id else {
+        if kind == "closed" {
+            forget_connection(env, conn_id)?;
+        }
+        return Ok(());
+    };
+
+    let last = match kind {
+        "streamFinAcked" | "streamReset" | "requestRejected" => true,
+        // Only the peer sends on a unidirectional stream that carries data
+        "data" => fin && stream_id & 0x2 != 0,
+        _ => false,
+    };
+    CONTEXTS.with(|contexts| {
+        let mut contexts = contexts.borrow_mut();
+        let key = (conn_id.to_string(), stream_id);
+        let Some(context) = contexts.get(&key) else {
+            return Ok(());
+        };
+        let value: JsUnknown = env.get_reference_value(context)?;
+        // Events are always objects
+        let mut event: JsObject = unsafe { event.cast() };
+        event.set_named_property("context", value)?;
+        if last {
+            contexts.remove(&key).unwrap().unref(env)?;
+        }
+        Ok(())
+    })
+}
+
+fn forget_connection(env: Env, conn_id: &str) -> Result<()> {
+    let forgotten: Vec<Ref<()>> = CONTEXTS.with(|contexts| {
+        let mut contexts = contexts.borrow_mut();
+        let keys: Vec<_> = contexts.keys().filter(|(id, _)| id == conn_id).cloned().collect();
+        keys.iter().filter_map(|key| contexts.remove(key)).collect()
+    });
+    for mut context in forgotten {
+        context.unref(env)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use ring::digest;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::events::{EmitEvent, QuicEvent};
+use crate::hex_id;
+
+// Running digests of what streams given setStreamDigest() deliver
+#[derive(Default)]
+pub(crate) struct Digests {
+    digests: HashMap<u64, digest::Context>,
+}
+
+impl Digests {
+    // Hashes everything the stream delivers in `data` events from now on
+    pub(crate) fn start(&mut self, stream_id: u64, algorithm: &str) -> napi::Result<()> {
+        let algorithm = match algorithm {
+            "sha256" => &digest::SHA256,
+            "sha384" => &digest::SHA384,
+            "sha512" => &digest::SHA512,
+            other => {
+                return Err(napi::Error::from_reason(format!(
+                    "Unknown digest {:?}; expected \"sha256\", \"sha384\" or \"sha512\"",
+                    other
+                )))
+            }
+        };
+        if self.digests.contains_key(&stream_id) {
+            return Err(napi::Error::from_reason(format!("Stream {} already has a digest", stream_id)));
+        }
+        self.digests.insert(stream_id, digest::Context::new(algorithm));
+        Ok(())
+    }
+
+    // Feeds a `data` event's bytes to its stream's digest, and adds the
+    // finished digest to the event that carries the stream's FIN
+    fn observe(&mut self, event: &mut QuicEvent) {
+        let Some(stream_id) = event.stream_id.map(|id| id as u64) else {
+            return;
+        };
+        match event.kind.as_str() {
+            "data" => {
+                let Some(context) = self.digests.get_mut(&stream_id) else {
+                    return;
+                };
+                if let Some(data) = &event.data {
+                    context.update(data);
+                }
+                if event.fin == Some(true) {
+                    let digest = self.digests.remove(&stream_id).unwrap().finish();
+                    event.digest = Some(hex_id(digest.as_ref()));
+                }
+            }
+            "streamReset" | "requestRejected" => {
+                self.digests.remove(&stream_id);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Passes events on to `events` through the digests, which see every `data`
+// event before JavaScript does
+pub(crate) struct Digesting<'a> {
+    digests: RefCell<&'a mut Digests>,
+    events: &'a dyn EmitEvent,
+}
+
+impl<'a> Digesting<'a> {
+    pub(crate) fn new(digests: &'a mut Digests, events: &'a dyn EmitEvent) -> Self {
+        Digesting { digests: RefCell::new(digests), events }
+    }
+}
+
+impl EmitEvent for Digesting<'_> {
+    fn emit(&self, mut event: QuicEvent) {
+        self.digests.borrow_mut().observe(&mut event);
+        self.events.emit(event);
+    }
+}
@@ -0,0 +1,106 @@
+use napi_derive::napi;
+
+/// QUIC transport error codes (RFC 9000, section 20.1).
+///
+/// TLS alerts are reported as `CryptoError + alert`.
+#[napi]
+pub enum TransportError {
+    NoError = 0x00,
+    InternalError = 0x01,
+    ConnectionRefused = 0x02,
+    FlowControlError = 0x03,
+    StreamLimitError = 0x04,
+    StreamStateError = 0x05,
+    FinalSizeError = 0x06,
+    FrameEncodingError = 0x07,
+    TransportParameterError = 0x08,
+    ConnectionIdLimitError = 0x09,
+    ProtocolViolation = 0x0a,
+    InvalidToken = 0x0b,
+    ApplicationError = 0x0c,
+    CryptoBufferExceeded = 0x0d,
+    KeyUpdateError = 0x0e,
+    AeadLimitReached = 0x0f,
+    NoViablePath = 0x10,
+    CryptoError = 0x100,
+}
+
+/// HTTP/3 application error codes (RFC 9114, section 8.1) and the QPACK
+/// codes from RFC 9204, section 6.
+#[napi]
+pub enum H3Error {
+    NoError = 0x100,
+    GeneralProtocolError = 0x101,
+    InternalError = 0x102,
+    StreamCreationError = 0x103,
+    ClosedCriticalStream = 0x104,
+    FrameUnexpected = 0x105,
+    FrameError = 0x106,
+    ExcessiveLoad = 0x107,
+    IdError = 0x108,
+    SettingsError = 0x109,
+    MissingSettings = 0x10a,
+    RequestRejected = 0x10b,
+    RequestCancelled = 0x10c,
+    RequestIncomplete = 0x10d,
+    MessageError = 0x10e,
+    ConnectError = 0x10f,
+    VersionFallback = 0x110,
+    /// A malformed HTTP Datagram (RFC 9297).
+    DatagramError = 0x33,
+    QpackDecompressionFailed = 0x200,
+    QpackEncoderStreamError = 0x201,
+    QpackDecoderStreamError = 0x202,
+}
+
+// Returns the TLS alert that closed the connection, if any, and whether this
+// endpoint sent it. Alerts travel as CRYPTO_ERROR + alert (RFC 9001, 4.8).
+pub(crate) fn tls_alert(conn: &quiche::Connection) -> Option<(u8, bool)> {
+    let crypto_errors = TransportError::CryptoError as u64..TransportError::CryptoError as u64 + 0x100;
+
+    let (err, sent) = match (conn.local_error(), conn.peer_error()) {
+        (Some(err), _) => (err, true),
+        (None, Some(err)) => (err, false),
+        (None, None) => return None,
+    };
+
+    if err.is_app || !crypto_errors.contains(&err.error_code) {
+        return None;
+    }
+
+    Some(((err.error_code - TransportError::CryptoError as u64) as u8, sent))
+}
+
+// TLS alert descriptions from the IANA TLS Alerts registry
+pub(crate) fn tls_alert_name(alert: u8) -> &'static str {
+    match alert {
+        0 => "close_notify",
+        10 => "unexpected_message",
+        20 => "bad_record_mac",
+        22 => "record_overflow",
+        40 => "handshake_failure",
+        42 => "bad_certificate",
+        43 => "unsupported_certificate",
+        44 => "certificate_revoked",
+        45 => "certificate_expired",
+        46 => "certificate_unknown",
+        47 => "illegal_parameter",
+        48 => "unknown_ca",
+        49 => "access_denied",
+        50 => "decode_error",
+        51 => "decrypt_error",
+        70 => "protocol_version",
+        71 => "insufficient_security",
+        80 => "internal_error",
+        86 => "inappropriate_fallback",
+        90 => "user_canceled",
+        109 => "missing_extension",
+        110 => "unsupported_extension",
+        112 => "unrecognized_name",
+        113 => "bad_certificate_status_response",
+        115 => "unknown_psk_identity",
+        116 => "certificate_required",
+        120 => "no_application_protocol",
+        _ => "unknown_alert",
+    }
+}
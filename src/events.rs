@@ -0,0 +1,507 @@
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::bindgen_prelude::{Buffer, ToNapiValue};
+use napi::{JsFunction, JsUnknown, NapiValue};
+use napi_derive::napi;
+use std::net::SocketAddr;
+
+use crate::context;
+use crate::error_codes::{tls_alert, tls_alert_name, H3Error, TransportError};
+use crate::http3::HttpHeader;
+use crate::hex_id;
+
+const TLS_NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// An event delivered from the packet loop to the JavaScript callback.
+///
+/// `kind` names the event; the remaining fields are set when they apply to it.
+/// `earlyDataReady` comes the first time 0-RTT data can be sent and
+/// `handshakeDone` the first time the handshake is complete, on servers and
+/// clients alike: the earliest safe moments to send.
+/// Connection events (`connection`, `earlyDataReady`, `handshakeDone`,
+/// `tlsAlert`, `datagram`, `closed`) carry `connId` and `peer`, with `alpn`
+/// added once it has been negotiated; stream events (`data`,
+/// `streamReset`, `streamFinAcked`, `request`, `requestRejected`,
+/// `response`) add `streamId`. `error` carries `message`, plus `connId` and
+/// `peer` when it concerns a single connection. `clockJump` carries `message`
+/// and `offsetMs`. `congestion` adds `type`, `cwndBefore` and `cwndAfter` to
+/// the connection fields, `handshakeRetransmits` adds `message` and
+/// `retransmits`, `loss` adds `lost`, `lostBytes` and `retransmits`, and
+/// `martianPackets` adds `message`, `packets` and `closed`.
+/// `unhandledPacket` carries `peer`, `packetType`, `dcid`, `scid`,
+/// `version` and `length`. `alpnMismatch` adds `message`, `offeredAlpn`
+/// and `action` to the connection fields, and `alpn` when it was accepted.
+/// `datagram` adds `data`, and on HTTP/3 connections, where datagrams are
+/// HTTP Datagrams, the `streamId` of the request they belong to.
+/// `datagramSupport` follows `handshakeDone` with `datagrams`, and
+/// `maxDatagramFrameSize` when the peer accepts them. `requestRejected`
+/// adds `reason`, with the `errorCode` the stream was reset with or the
+/// `status` the request was answered with. `message` events, from streams
+/// given `setMessageFraming()`, add `streamId`, `framing` and `data`, plus
+/// `value` for `ndjson` (or `message` when the line is not JSON); the
+/// stream's framing errors are `error` events with `streamId`. A client
+/// that reconnects under `keepAlive` follows the new connection's
+/// `handshakeDone` with `reconnected`, which adds `previousConnId` and
+/// `resumed`. The final `data` event of a stream given `setStreamDigest()`
+/// adds `digest`, and events about a stream given `setStreamContext()` add
+/// `context`.
+#[napi(object)]
+pub struct QuicEvent {
+    pub kind: String,
+    pub address: Option<String>,
+    /// Hex-encoded connection ID identifying the connection the event is about.
+    pub conn_id: Option<String>,
+    pub peer: Option<String>,
+    /// The negotiated application protocol, on `earlyDataReady` and
+    /// `handshakeDone`.
+    pub alpn: Option<String>,
+    pub message: Option<String>,
+    /// Error code from the CONNECTION_CLOSE that ended the connection, if any.
+    pub error_code: Option<i64>,
+    pub reason: Option<String>,
+    /// Name of the TLS alert that failed the handshake, e.g. `bad_certificate`.
+    pub tls_alert: Option<String>,
+    /// Whether this endpoint sent `tlsAlert` (`false` if the peer did).
+    pub tls_alert_sent: Option<bool>,
+    pub stream_id: Option<i64>,
+    /// On `data` events, everything the stream had ready when it was read,
+    /// however many packets it arrived in.
+    pub data: Option<Buffer>,
+    /// Set on `data` events; `true` once the peer has finished the stream.
+    pub fin: Option<bool>,
+    /// The `:method` and `:path` of a `request`, also present in `headers`.
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub headers: Option<Vec<HttpHeader>>,
+    /// The `:status` of a `response`, or the one a `requestRejected` was
+    /// answered with.
+    pub status: Option<u32>,
+    /// How far the wall clock jumped relative to monotonic time, in milliseconds.
+    pub offset_ms: Option<i64>,
+    /// What the congestion controller reacted to: `loss`, or `ece` for ECN
+    /// congestion marks (which quiche does not yet report).
+    #[napi(js_name = "type")]
+    pub congestion_type: Option<String>,
+    /// Congestion window, in bytes, before and after the reaction.
+    pub cwnd_before: Option<i64>,
+    pub cwnd_after: Option<i64>,
+    /// Packets retransmitted so far during the connection's handshake, or
+    /// since the previous `loss` event.
+    pub retransmits: Option<i64>,
+    /// Packets, and their bytes, declared lost since the previous `loss` event.
+    pub lost: Option<i64>,
+    pub lost_bytes: Option<i64>,
+    /// Packets received for the connection that could not be used.
+    pub packets: Option<i64>,
+    /// Whether the connection had already closed when they arrived.
+    pub closed: Option<bool>,
+    /// `initial`, `retry`, `handshake`, `0rtt`, `versionNegotiation` or
+    /// `short`.
+    pub packet_type: Option<String>,
+    /// Hex-encoded connection IDs from the packet header; short headers
+    /// carry no `scid` or `version`.
+    pub dcid: Option<String>,
+    pub scid: Option<String>,
+    pub version: Option<u32>,
+    /// Datagram length in bytes.
+    pub length: Option<u32>,
+    /// Protocols the client offered through ALPN, in its order of preference.
+    pub offered_alpn: Option<Vec<String>>,
+    /// What `alpnMismatch` did about the connection: `close` or `accept`.
+    pub action: Option<String>,
+    /// Whether the peer accepts DATAGRAM frames.
+    pub datagrams: Option<bool>,
+    /// The largest DATAGRAM frame the peer accepts, in bytes. A datagram's
+    /// payload must also fit a packet; see `dgramMaxWritableLen()`.
+    pub max_datagram_frame_size: Option<i64>,
+    /// How a `message` was framed on its stream: `ndjson` or `length`.
+    pub framing: Option<String>,
+    /// The connection a `reconnected` one replaces, and whether it resumed
+    /// that one's TLS session.
+    pub previous_conn_id: Option<String>,
+    pub resumed: Option<bool>,
+    /// Hex-encoded digest of everything a stream given `setStreamDigest()`
+    /// delivered, on the `data` event that carries its `fin`.
+    pub digest: Option<String>,
+}
+
+impl QuicEvent {
+    fn new(kind: &str) -> QuicEvent {
+        QuicEvent {
+            kind: kind.to_string(),
+            address: None,
+            conn_id: None,
+            peer: None,
+            alpn: None,
+            message: None,
+            error_code: None,
+            reason: None,
+            tls_alert: None,
+            tls_alert_sent: None,
+            stream_id: None,
+            data: None,
+            fin: None,
+            method: None,
+            path: None,
+            headers: None,
+            status: None,
+            offset_ms: None,
+            congestion_type: None,
+            cwnd_before: None,
+            cwnd_after: None,
+            retransmits: None,
+            lost: None,
+            lost_bytes: None,
+            packets: None,
+            closed: None,
+            packet_type: None,
+            dcid: None,
+            scid: None,
+            version: None,
+            length: None,
+            offered_alpn: None,
+            action: None,
+            datagrams: None,
+            max_datagram_frame_size: None,
+            framing: None,
+            previous_conn_id: None,
+            resumed: None,
+            digest: None,
+        }
+    }
+
+    pub fn listening(addr: SocketAddr) -> QuicEvent {
+        QuicEvent { address: Some(addr.to_string()), ..QuicEvent::new("listening") }
+    }
+
+    pub fn error(message: String) -> QuicEvent {
+        QuicEvent { message: Some(message), ..QuicEvent::new("error") }
+    }
+
+    pub fn clock_jump(offset_ms: i64) -> QuicEvent {
+        let direction = if offset_ms > 0 { "forward" } else { "backward" };
+        QuicEvent {
+            message: Some(format!("System clock jumped {} by {} ms", direction, offset_ms.abs())),
+            offset_ms: Some(offset_ms),
+            ..QuicEvent::new("clockJump")
+        }
+    }
+
+    // A packet for no connection that could not have opened one
+    pub fn unhandled_packet(from: SocketAddr, hdr: &quiche::Header, len: usize) -> QuicEvent {
+        let short = hdr.ty == quiche::Type::Short;
+        QuicEvent {
+            peer: Some(from.to_string()),
+            packet_type: Some(packet_type(hdr.ty).to_string()),
+            dcid: Some(hex_id(&hdr.dcid)),
+            scid: if short { None } else { Some(hex_id(&hdr.scid)) },
+            version: if short { None } else { Some(hdr.version) },
+            length: Some(len as u32),
+            ..QuicEvent::new("unhandledPacket")
+        }
+    }
+
+    fn for_connection(kind: &str, conn_id: &str, peer: SocketAddr) -> QuicEvent {
+        QuicEvent {
+            conn_id: Some(conn_id.to_string()),
+            peer: Some(peer.to_string()),
+            ..QuicEvent::new(kind)
+        }
+    }
+
+    pub fn connection(conn_id: &str, peer: SocketAddr) -> QuicEvent {
+        QuicEvent::for_connection("connection", conn_id, peer)
+    }
+
+    pub fn handshake_done(conn_id: &str, peer: SocketAddr, conn: &quiche::Connection) -> QuicEvent {
+        QuicEvent { alpn: negotiated_alpn(conn), ..QuicEvent::for_connection("handshakeDone", conn_id, peer) }
+    }
+
+    pub fn reconnected(conn_id: &str, peer: SocketAddr, previous: &str, conn: &quiche::Connection) -> QuicEvent {
+        QuicEvent {
+            previous_conn_id: Some(previous.to_string()),
+            resumed: Some(conn.is_resumed()),
+            ..QuicEvent::for_connection("reconnected", conn_id, peer)
+        }
+    }
+
+    pub fn datagram_support(conn_id: &str, peer: SocketAddr, conn: &quiche::Connection) -> QuicEvent {
+        let max_frame_size = conn.peer_transport_params().and_then(|params| params.max_datagram_frame_size);
+        QuicEvent {
+            datagrams: Some(max_frame_size.is_some()),
+            max_datagram_frame_size: max_frame_size.map(|size| size as i64),
+            ..QuicEvent::for_connection("datagramSupport", conn_id, peer)
+        }
+    }
+
+    pub fn early_data_ready(conn_id: &str, peer: SocketAddr, conn: &quiche::Connection) -> QuicEvent {
+        QuicEvent { alpn: negotiated_alpn(conn), ..QuicEvent::for_connection("earlyDataReady", conn_id, peer) }
+    }
+
+    pub fn connection_error(conn_id: &str, peer: SocketAddr, message: String) -> QuicEvent {
+        QuicEvent { message: Some(message), ..QuicEvent::for_connection("error", conn_id, peer) }
+    }
+
+    pub fn data(conn_id: &str, peer: SocketAddr, stream_id: u64, data: Vec<u8>, fin: bool) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            data: Some(data.into()),
+            fin: Some(fin),
+            ..QuicEvent::for_connection("data", conn_id, peer)
+        }
+    }
+
+    pub fn message(conn_id: &str, peer: SocketAddr, stream_id: u64, data: Vec<u8>, framing: &str) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            data: Some(data.into()),
+            framing: Some(framing.to_string()),
+            ..QuicEvent::for_connection("message", conn_id, peer)
+        }
+    }
+
+    pub fn stream_error(conn_id: &str, peer: SocketAddr, stream_id: u64, message: String) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            message: Some(message),
+            ..QuicEvent::for_connection("error", conn_id, peer)
+        }
+    }
+
+    pub fn datagram(conn_id: &str, peer: SocketAddr, data: Vec<u8>) -> QuicEvent {
+        QuicEvent { data: Some(data.into()), ..QuicEvent::for_connection("datagram", conn_id, peer) }
+    }
+
+    pub fn request_datagram(conn_id: &str, peer: SocketAddr, stream_id: u64, data: Vec<u8>) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            data: Some(data.into()),
+            ..QuicEvent::for_connection("datagram", conn_id, peer)
+        }
+    }
+
+    pub fn stream_reset(conn_id: &str, peer: SocketAddr, stream_id: u64, error_code: u64) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            error_code: Some(error_code as i64),
+            ..QuicEvent::for_connection("streamReset", conn_id, peer)
+        }
+    }
+
+    pub fn stream_fin_acked(conn_id: &str, peer: SocketAddr, stream_id: u64) -> QuicEvent {
+        QuicEvent { stream_id: Some(stream_id as i64), ..QuicEvent::for_connection("streamFinAcked", conn_id, peer) }
+    }
+
+    pub fn request(conn_id: &str, peer: SocketAddr, stream_id: u64, headers: Vec<HttpHeader>) -> QuicEvent {
+        let pseudo = |name: &str| headers.iter().find(|h| h.name == name).map(|h| h.value.clone());
+
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            method: pseudo(":method"),
+            path: pseudo(":path"),
+            headers: Some(headers),
+            ..QuicEvent::for_connection("request", conn_id, peer)
+        }
+    }
+
+    pub fn response(conn_id: &str, peer: SocketAddr, stream_id: u64, status: u32, headers: Vec<HttpHeader>) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            status: Some(status),
+            headers: Some(headers),
+            ..QuicEvent::for_connection("response", conn_id, peer)
+        }
+    }
+
+    // A request refused as malformed; the stream was reset with H3_MESSAGE_ERROR
+    pub fn request_rejected(
+        conn_id: &str,
+        peer: SocketAddr,
+        stream_id: u64,
+        code: H3Error,
+        reason: &str,
+    ) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            error_code: Some(code as i64),
+            reason: Some(reason.to_string()),
+            ..QuicEvent::for_connection("requestRejected", conn_id, peer)
+        }
+    }
+
+    // A request answered with an error status, without JS seeing it
+    pub fn request_refused(conn_id: &str, peer: SocketAddr, stream_id: u64, status: u32, reason: &str) -> QuicEvent {
+        QuicEvent {
+            stream_id: Some(stream_id as i64),
+            status: Some(status),
+            reason: Some(reason.to_string()),
+            ..QuicEvent::for_connection("requestRejected", conn_id, peer)
+        }
+    }
+
+    // The congestion controller shrank the window after packets were lost
+    pub fn congestion(conn_id: &str, peer: SocketAddr, cwnd_before: usize, cwnd_after: usize) -> QuicEvent {
+        QuicEvent {
+            congestion_type: Some("loss".to_string()),
+            cwnd_before: Some(cwnd_before as i64),
+            cwnd_after: Some(cwnd_after as i64),
+            ..QuicEvent::for_connection("congestion", conn_id, peer)
+        }
+    }
+
+    // The handshake needed more retransmissions than it should
+    pub fn handshake_retransmits(conn_id: &str, peer: SocketAddr, retransmits: usize) -> QuicEvent {
+        QuicEvent {
+            message: Some(format!(
+                "{} handshake packets retransmitted to {}; check for an MTU blackhole or a middlebox dropping QUIC",
+                retransmits, peer
+            )),
+            retransmits: Some(retransmits as i64),
+            ..QuicEvent::for_connection("handshakeRetransmits", conn_id, peer)
+        }
+    }
+
+    // What the loss timer found lost, and what was resent, since the last report
+    pub fn loss(conn_id: &str, peer: SocketAddr, lost: usize, lost_bytes: u64, retransmits: usize) -> QuicEvent {
+        QuicEvent {
+            lost: Some(lost as i64),
+            lost_bytes: Some(lost_bytes as i64),
+            retransmits: Some(retransmits as i64),
+            ..QuicEvent::for_connection("loss", conn_id, peer)
+        }
+    }
+
+    // Packets kept failing decryption, or kept arriving after the connection closed
+    pub fn martian_packets(conn_id: &str, peer: SocketAddr, packets: usize, closed: bool) -> QuicEvent {
+        let message = if closed {
+            format!("{} packets from {} arrived after connection {} closed", packets, peer, conn_id)
+        } else {
+            format!("{} packets from {} on connection {} failed decryption", packets, peer, conn_id)
+        };
+        QuicEvent {
+            message: Some(message),
+            packets: Some(packets as i64),
+            closed: Some(closed),
+            ..QuicEvent::for_connection("martianPackets", conn_id, peer)
+        }
+    }
+
+    // The client offered none of the server's protocols; `accepted` is the
+    // one negotiated anyway under alpnMismatch "accept"
+    pub fn alpn_mismatch(conn_id: &str, peer: SocketAddr, offered: &[Vec<u8>], accepted: Option<&[u8]>) -> QuicEvent {
+        let offered: Vec<String> = offered.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect();
+        let accepted = accepted.map(|p| String::from_utf8_lossy(p).into_owned());
+        let message = match &accepted {
+            Some(alpn) => format!("{} offered no supported ALPN in {:?}; accepting {:?}", peer, offered, alpn),
+            None => format!("{} offered no supported ALPN in {:?}; closing", peer, offered),
+        };
+        QuicEvent {
+            message: Some(message),
+            action: Some(if accepted.is_some() { "accept" } else { "close" }.to_string()),
+            alpn: accepted,
+            offered_alpn: Some(offered),
+            ..QuicEvent::for_connection("alpnMismatch", conn_id, peer)
+        }
+    }
+
+    // Describes the TLS alert that failed the connection's handshake, if any
+    pub fn tls_alert(conn_id: &str, peer: SocketAddr, conn: &quiche::Connection) -> Option<QuicEvent> {
+        let (alert, sent) = tls_alert(conn)?;
+        let name = tls_alert_name(alert);
+
+        let mut message = if sent {
+            format!("TLS alert {} ({}) sent to {}", name, alert, peer)
+        } else {
+            format!("TLS alert {} ({}) received from {}", name, alert, peer)
+        };
+        if alert == TLS_NO_APPLICATION_PROTOCOL {
+            message.push_str(": no ALPN protocol in common");
+        }
+
+        Some(QuicEvent {
+            message: Some(message),
+            error_code: Some(TransportError::CryptoError as i64 + alert as i64),
+            tls_alert: Some(name.to_string()),
+            tls_alert_sent: Some(sent),
+            ..QuicEvent::for_connection("tlsAlert", conn_id, peer)
+        })
+    }
+
+    // Reports the CONNECTION_CLOSE that ended the connection, preferring the
+    // peer's if both sides sent one
+    pub fn closed(conn_id: &str, peer: SocketAddr, conn: &quiche::Connection) -> QuicEvent {
+        let mut event = QuicEvent::for_connection("closed", conn_id, peer);
+
+        if let Some(err) = conn.peer_error().or_else(|| conn.local_error()) {
+            event.error_code = Some(err.error_code as i64);
+            event.reason = Some(String::from_utf8_lossy(&err.reason).into_owned());
+        }
+
+        event
+    }
+}
+
+fn packet_type(ty: quiche::Type) -> &'static str {
+    match ty {
+        quiche::Type::Initial => "initial",
+        quiche::Type::Retry => "retry",
+        quiche::Type::Handshake => "handshake",
+        quiche::Type::ZeroRTT => "0rtt",
+        quiche::Type::VersionNegotiation => "versionNegotiation",
+        quiche::Type::Short => "short",
+    }
+}
+
+// The application protocol the handshake settled on, if any yet
+pub(crate) fn negotiated_alpn(conn: &quiche::Connection) -> Option<String> {
+    match conn.application_proto() {
+        [] => None,
+        proto => Some(String::from_utf8_lossy(proto).into_owned()),
+    }
+}
+
+pub type EventCallback = ThreadsafeFunction<QuicEvent, ErrorStrategy::Fatal>;
+
+// Parses an `ndjson` message on the JS thread, where its value has to live
+const PARSE_SCRIPT: &str = "(event) => {
+    try {
+        event.value = JSON.parse(event.data.toString());
+    } catch (error) {
+        event.message = error.message;
+    }
+    return event;
+}";
+
+// Wraps a JS function so the packet loop thread can call it with QuicEvents
+pub fn event_callback(callback: JsFunction) -> napi::Result<EventCallback> {
+    callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<QuicEvent>| {
+        let ndjson = ctx.value.framing.as_deref() == Some("ndjson");
+        let kind = ctx.value.kind.clone();
+        let (conn_id, stream_id, fin) = (ctx.value.conn_id.clone(), ctx.value.stream_id, ctx.value.fin == Some(true));
+        let event = unsafe {
+            let value = QuicEvent::to_napi_value(ctx.env.raw(), ctx.value)?;
+            JsUnknown::from_raw_unchecked(ctx.env.raw(), value)
+        };
+        if let Some(conn_id) = conn_id {
+            context::attach(ctx.env, &event, &kind, &conn_id, stream_id, fin)?;
+        }
+        if !ndjson {
+            return Ok(vec![event]);
+        }
+        let parse: JsFunction = ctx.env.run_script(PARSE_SCRIPT)?;
+        Ok(vec![parse.call(None, &[event])?])
+    })
+}
+
+// Where the packet loops deliver events: the JS callback, or a recorder in tests
+pub trait EmitEvent {
+    fn emit(&self, event: QuicEvent);
+}
+
+impl EmitEvent for EventCallback {
+    // Queues the event for the JS thread without ever blocking the packet loop
+    fn emit(&self, event: QuicEvent) {
+        self.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+    }
+}
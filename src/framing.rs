@@ -0,0 +1,297 @@
+use napi::bindgen_prelude::{Buffer, Either3};
+use napi::{Env, JsFunction, JsObject, JsUnknown, ValueType};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+use crate::events::{EmitEvent, QuicEvent};
+
+// Messages longer than this fail the stream unless setMessageFraming() says
+// otherwise
+const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+// Bytes in a `length` message's big-endian size prefix
+const LENGTH_PREFIX: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Framing {
+    // One JSON value per line
+    Ndjson,
+    // Each message preceded by its length
+    Length,
+}
+
+impl Framing {
+    pub(crate) fn parse(name: &str) -> napi::Result<Framing> {
+        match name {
+            "ndjson" => Ok(Framing::Ndjson),
+            "length" => Ok(Framing::Length),
+            other => Err(napi::Error::from_reason(format!(
+                "Unknown framing {:?}; expected \"ndjson\" or \"length\"",
+                other
+            ))),
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Framing::Ndjson => "ndjson",
+            Framing::Length => "length",
+        }
+    }
+
+    fn frame(self, message: &[u8]) -> napi::Result<Vec<u8>> {
+        match self {
+            Framing::Ndjson if message.contains(&b'\n') => {
+                Err(napi::Error::from_reason("NDJSON messages must not contain newlines"))
+            }
+            Framing::Ndjson => Ok([message, b"\n"].concat()),
+            Framing::Length => {
+                let len = u32::try_from(message.len())
+                    .map_err(|_| napi::Error::from_reason("Message is too long for a 4-byte length prefix"))?;
+                Ok([&len.to_be_bytes()[..], message].concat())
+            }
+        }
+    }
+
+    // Takes the next whole message off the front of `partial`
+    fn next(self, partial: &mut Vec<u8>, max: usize) -> Result<Option<Vec<u8>>, String> {
+        let (start, end, skip) = match self {
+            Framing::Ndjson => match partial.iter().position(|&b| b == b'\n') {
+                Some(end) => (0, end, end + 1),
+                None if partial.len() > max => return Err(format!("Message exceeds {} bytes", max)),
+                None => return Ok(None),
+            },
+            Framing::Length => {
+                if partial.len() < LENGTH_PREFIX {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes([partial[0], partial[1], partial[2], partial[3]]) as usize;
+                if len > max {
+                    return Err(format!("Message of {} bytes exceeds {}", len, max));
+                }
+                if partial.len() < LENGTH_PREFIX + len {
+                    return Ok(None);
+                }
+                (LENGTH_PREFIX, LENGTH_PREFIX + len, LENGTH_PREFIX + len)
+            }
+        };
+        if end - start > max {
+            return Err(format!("Message exceeds {} bytes", max));
+        }
+
+        let message = partial[start..end].to_vec();
+        partial.drain(..skip);
+        Ok(Some(message))
+    }
+}
+
+// The framing layer over one stream
+struct Framer {
+    framing: Framing,
+    max_message_bytes: usize,
+    // Received bytes that do not make up a whole message yet
+    partial: Vec<u8>,
+    // Framed messages the stream has not accepted yet, and whether to finish
+    // the stream once they are all sent
+    outgoing: Vec<u8>,
+    fin: bool,
+    fin_sent: bool,
+    // Set once received data could not be framed; the rest is discarded
+    failed: bool,
+    // Set once the peer finished or reset its side
+    ended: bool,
+}
+
+// Streams of one connection that carry whole messages rather than bytes
+#[derive(Default)]
+pub(crate) struct Framers {
+    framers: HashMap<u64, Framer>,
+}
+
+impl Framers {
+    pub(crate) fn contains(&self, stream_id: u64) -> bool {
+        self.framers.contains_key(&stream_id)
+    }
+
+    // Bytes of framed messages not yet accepted by their streams, and of
+    // received messages not yet whole
+    pub(crate) fn buffered(&self) -> (usize, usize) {
+        let outgoing = self.framers.values().map(|framer| framer.outgoing.len()).sum();
+        let partial = self.framers.values().map(|framer| framer.partial.len()).sum();
+        (outgoing, partial)
+    }
+
+    // Frames the stream from now on. Data already delivered as `data` events
+    // is not framed again.
+    pub(crate) fn set(&mut self, stream_id: u64, framing: Framing, max_message_bytes: Option<u32>) -> napi::Result<()> {
+        if self.contains(stream_id) {
+            return Err(napi::Error::from_reason(format!("Stream {} is already framed", stream_id)));
+        }
+
+        let framer = Framer {
+            framing,
+            max_message_bytes: max_message_bytes.map_or(MAX_MESSAGE_BYTES, |max| max as usize),
+            partial: Vec::new(),
+            outgoing: Vec::new(),
+            fin: false,
+            fin_sent: false,
+            failed: false,
+            ended: false,
+        };
+        self.framers.insert(stream_id, framer);
+        Ok(())
+    }
+
+    // Queues one message and sends as much of the queue as flow control
+    // allows. Returns how many bytes are still queued on the stream.
+    pub(crate) fn send(
+        &mut self,
+        conn: &mut quiche::Connection,
+        stream_id: u64,
+        message: &[u8],
+        fin: bool,
+    ) -> napi::Result<u32> {
+        let framer = self
+            .framers
+            .get_mut(&stream_id)
+            .ok_or_else(|| napi::Error::from_reason(format!("Stream {} has no message framing", stream_id)))?;
+        if framer.fin || framer.fin_sent {
+            return Err(napi::Error::from_reason(format!("Stream {} has already been finished", stream_id)));
+        }
+
+        let frame = framer.framing.frame(message)?;
+        framer.outgoing.extend_from_slice(&frame);
+        framer.fin = fin;
+        flush_stream(conn, stream_id, framer).map_err(crate::quiche_err_to_napi)?;
+        Ok(framer.outgoing.len() as u32)
+    }
+
+    // Sends queued messages as far as flow control allows
+    pub(crate) fn flush(&mut self, conn: &mut quiche::Connection) {
+        for (&stream_id, framer) in self.framers.iter_mut() {
+            if let Err(e) = flush_stream(conn, stream_id, framer) {
+                eprintln!("Failed to send messages on stream {}: {:?}", stream_id, e);
+                framer.outgoing.clear();
+            }
+        }
+    }
+
+    // Reads the framed streams, delivering each whole message as a `message`
+    // event. The stream's end still arrives as an empty `data` event with
+    // `fin`, once everything before it was delivered.
+    pub(crate) fn read(
+        &mut self,
+        conn: &mut quiche::Connection,
+        conn_id: &str,
+        peer: SocketAddr,
+        buf: &mut [u8],
+        events: &dyn EmitEvent,
+    ) {
+        let readable: Vec<u64> = conn.readable().filter(|id| self.contains(*id)).collect();
+
+        for stream_id in readable {
+            let framer = self.framers.get_mut(&stream_id).unwrap();
+            loop {
+                match conn.stream_recv(stream_id, buf) {
+                    Ok((len, fin)) => {
+                        if !framer.failed {
+                            framer.partial.extend_from_slice(&buf[..len]);
+                            deliver(conn, conn_id, peer, stream_id, framer, events);
+                        }
+                        if fin {
+                            if !framer.partial.is_empty() && !framer.failed {
+                                let message = "Stream ended in the middle of a message".to_string();
+                                events.emit(QuicEvent::stream_error(conn_id, peer, stream_id, message));
+                            }
+                            events.emit(QuicEvent::data(conn_id, peer, stream_id, Vec::new(), true));
+                            framer.ended = true;
+                            break;
+                        }
+                    }
+                    Err(quiche::Error::Done) => break,
+                    Err(quiche::Error::StreamReset(code)) => {
+                        events.emit(QuicEvent::stream_reset(conn_id, peer, stream_id, code));
+                        framer.ended = true;
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to read stream {}: {:?}", stream_id, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Forget streams done in both directions
+        self.framers.retain(|&stream_id, framer| {
+            !framer.ended || !(framer.fin_sent || conn.stream_capacity(stream_id).is_err())
+        });
+    }
+}
+
+// Writes what the stream accepts of the framer's queue, then its FIN once
+// the queue is empty
+fn flush_stream(conn: &mut quiche::Connection, stream_id: u64, framer: &mut Framer) -> quiche::Result<()> {
+    if framer.outgoing.is_empty() && !framer.fin {
+        return Ok(());
+    }
+
+    match conn.stream_send(stream_id, &framer.outgoing, framer.fin) {
+        Ok(written) => {
+            framer.outgoing.drain(..written);
+            if framer.outgoing.is_empty() && framer.fin {
+                framer.fin = false;
+                framer.fin_sent = true;
+            }
+            Ok(())
+        }
+        Err(quiche::Error::Done) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Emits every whole message the framer holds. Malformed input emits an
+// `error` event and asks the peer to stop sending.
+fn deliver(
+    conn: &mut quiche::Connection,
+    conn_id: &str,
+    peer: SocketAddr,
+    stream_id: u64,
+    framer: &mut Framer,
+    events: &dyn EmitEvent,
+) {
+    loop {
+        match framer.framing.next(&mut framer.partial, framer.max_message_bytes) {
+            Ok(Some(message)) => {
+                events.emit(QuicEvent::message(conn_id, peer, stream_id, message, framer.framing.name()))
+            }
+            Ok(None) => return,
+            Err(reason) => {
+                framer.failed = true;
+                framer.partial = Vec::new();
+                let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, 0);
+                events.emit(QuicEvent::stream_error(conn_id, peer, stream_id, reason));
+                return;
+            }
+        }
+    }
+}
+
+// The bytes of a message given to sendMessage(): buffers as they are,
+// strings as UTF-8, and anything else as its JSON text
+pub(crate) fn message_bytes(env: Env, message: Either3<Buffer, String, JsUnknown>) -> napi::Result<Vec<u8>> {
+    match message {
+        Either3::A(buffer) => Ok(buffer.to_vec()),
+        Either3::B(text) => Ok(text.into_bytes()),
+        Either3::C(value) => {
+            let json: JsObject = env.get_global()?.get_named_property("JSON")?;
+            let stringify: JsFunction = json.get_named_property("stringify")?;
+            let text = stringify.call(Some(&json), &[value])?;
+            if text.get_type()? != ValueType::String {
+                return Err(napi::Error::from_reason("Message cannot be converted to JSON"));
+            }
+            Ok(text.coerce_to_string()?.into_utf8()?.as_slice().to_vec())
+        }
+    }
+}
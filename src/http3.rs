@@ -0,0 +1,633 @@
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+use quiche::h3::{self, NameValue};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error_codes::H3Error;
+use crate::events::{EmitEvent, QuicEvent};
+use crate::json::{JsonObject, ToJson};
+use crate::sink::Sinks;
+
+// Per-field overhead counted in a header section's size (RFC 9114, section 4.2.2)
+const FIELD_OVERHEAD: usize = 32;
+
+// Methods response metrics are kept under by name; the rest count as OTHER,
+// so that clients cannot grow the table
+const METRIC_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+// Hop-by-hop headers that HTTP/3 forbids (RFC 9114, section 4.2)
+const CONNECTION_SPECIFIC_HEADERS: &[&[u8]] =
+    &[b"connection", b"keep-alive", b"proxy-connection", b"transfer-encoding", b"upgrade"];
+
+/// An HTTP/3 header field. Pseudo-headers use their literal names, e.g.
+/// `:method` or `:status`.
+#[napi(object)]
+#[derive(Clone)]
+pub struct HttpHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl From<&h3::Header> for HttpHeader {
+    fn from(header: &h3::Header) -> Self {
+        HttpHeader {
+            name: String::from_utf8_lossy(header.name()).into_owned(),
+            value: String::from_utf8_lossy(header.value()).into_owned(),
+        }
+    }
+}
+
+/// Options for `QuicClient.h3Request()`.
+#[napi(object)]
+pub struct H3RequestOptions {
+    /// Request method, `GET` by default.
+    pub method: Option<String>,
+    pub path: String,
+    /// Additional header fields, sent after the pseudo-headers.
+    pub headers: Option<Vec<HttpHeader>>,
+    pub body: Option<Buffer>,
+}
+
+/// The head of an HTTP/3 response. The body follows as `data` events for
+/// `streamId`, ending with one whose `fin` is set.
+#[napi(object)]
+pub struct H3Response {
+    pub stream_id: i64,
+    pub status: u32,
+    pub headers: Vec<HttpHeader>,
+}
+
+// Frames an HTTP Datagram (RFC 9297) for a request stream: the stream's ID
+// divided by four, as a QUIC variable-length integer, then the payload
+pub(crate) fn encode_datagram(stream_id: u64, payload: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if stream_id & 0x3 != 0 {
+        return Err("HTTP datagrams belong to client-initiated bidirectional streams");
+    }
+
+    let quarter = stream_id >> 2;
+    let mut datagram = match quarter {
+        0..=0x3f => vec![quarter as u8],
+        0x40..=0x3fff => (quarter as u16 | 0x4000).to_be_bytes().to_vec(),
+        0x4000..=0x3fff_ffff => (quarter as u32 | 0x8000_0000).to_be_bytes().to_vec(),
+        _ => (quarter | 0xc000_0000_0000_0000).to_be_bytes().to_vec(),
+    };
+    datagram.extend_from_slice(payload);
+    Ok(datagram)
+}
+
+// Splits an HTTP Datagram into its request stream's ID and payload, or None
+// if it is too short to hold the quarter stream ID or names no possible stream
+pub(crate) fn decode_datagram(datagram: &[u8]) -> Option<(u64, &[u8])> {
+    let len = 1 << (datagram.first()? >> 6);
+    let prefix = datagram.get(..len)?;
+    let quarter = prefix[1..].iter().fold((prefix[0] & 0x3f) as u64, |value, &b| value << 8 | b as u64);
+    (quarter < 1 << 60).then(|| (quarter << 2, &datagram[len..]))
+}
+
+pub(crate) fn to_h3_headers(headers: &[HttpHeader]) -> Vec<h3::Header> {
+    headers.iter().map(|h| h3::Header::new(h.name.as_bytes(), h.value.as_bytes())).collect()
+}
+
+// What the Http3Settings of a connection's ALPN allow of its requests
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RequestPolicy {
+    // Header fields and bytes a request head may carry
+    pub(crate) max_headers: Option<usize>,
+    pub(crate) max_header_bytes: Option<usize>,
+    // Body bytes a request may carry, and how long its body may stall
+    pub(crate) max_body_bytes: Option<u64>,
+    pub(crate) body_timeout: Option<Duration>,
+    // Whether extended CONNECT is advertised, letting requests carry :protocol
+    pub(crate) extended_connect: bool,
+}
+
+impl RequestPolicy {
+    // Whether a request head is over the header limits
+    fn oversized(&self, headers: &[h3::Header]) -> bool {
+        let bytes = || headers.iter().map(|h| h.name().len() + h.value().len() + FIELD_OVERHEAD).sum::<usize>();
+        self.max_headers.is_some_and(|max| headers.len() > max)
+            || self.max_header_bytes.is_some_and(|max| bytes() > max)
+    }
+}
+
+/// HTTP/3 responses sent with `sendResponse()` for one status class and
+/// request method, as listed in `ServerMetrics.responses`. A response
+/// counts once its `fin` has been written.
+#[napi(object)]
+pub struct ResponseMetrics {
+    /// `1xx` to `5xx`.
+    pub status_class: String,
+    /// The request's `:method`, or `OTHER` for methods beyond those of RFC
+    /// 9110 and PATCH.
+    pub method: String,
+    pub count: i64,
+    /// Milliseconds from the request head arriving to the response's `fin`
+    /// being written, summed over the responses, and the longest of them.
+    pub total_duration_ms: f64,
+    pub max_duration_ms: f64,
+    /// Response body bytes written.
+    pub body_bytes: i64,
+}
+
+impl ToJson for ResponseMetrics {
+    fn write_json(&self, out: &mut String) {
+        JsonObject::new()
+            .field("statusClass", self.status_class.as_str())
+            .field("method", self.method.as_str())
+            .field("count", self.count)
+            .field("totalDurationMs", self.total_duration_ms)
+            .field("maxDurationMs", self.max_duration_ms)
+            .field("bodyBytes", self.body_bytes)
+            .write_json(out)
+    }
+}
+
+// HTTP/3 counters of a server, across its connections
+#[derive(Default)]
+pub(crate) struct H3Metrics {
+    // Requests answered 431 for their header limits
+    pub(crate) oversized_request_heads: AtomicU64,
+    // Requests answered 413 or 408 for their body limits
+    pub(crate) oversized_request_bodies: AtomicU64,
+    pub(crate) request_body_timeouts: AtomicU64,
+    // Responses finished, by status class and method
+    responses: Mutex<BTreeMap<(u16, &'static str), ResponseTotals>>,
+}
+
+#[derive(Default)]
+struct ResponseTotals {
+    count: u64,
+    total_duration: Duration,
+    max_duration: Duration,
+    body_bytes: u64,
+}
+
+impl H3Metrics {
+    fn record(&self, exchange: &Exchange, status: u16) {
+        let duration = exchange.started.elapsed();
+        let mut responses = self.responses.lock().unwrap();
+        let totals = responses.entry((status / 100, exchange.method)).or_default();
+        totals.count += 1;
+        totals.total_duration += duration;
+        totals.max_duration = totals.max_duration.max(duration);
+        totals.body_bytes += exchange.body_bytes;
+    }
+
+    pub(crate) fn responses(&self) -> Vec<ResponseMetrics> {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let responses = self.responses.lock().unwrap();
+        responses
+            .iter()
+            .map(|(&(class, method), totals)| ResponseMetrics {
+                status_class: format!("{}xx", class),
+                method: method.to_string(),
+                count: totals.count as i64,
+                total_duration_ms: ms(totals.total_duration),
+                max_duration_ms: ms(totals.max_duration),
+                body_bytes: totals.body_bytes as i64,
+            })
+            .collect()
+    }
+}
+
+// A request being answered, timed for the response metrics
+struct Exchange {
+    started: Instant,
+    method: &'static str,
+    // From the response head, once sent
+    status: Option<u16>,
+    body_bytes: u64,
+}
+
+impl Exchange {
+    fn new(headers: &[h3::Header]) -> Self {
+        let method = headers.iter().find(|h| h.name() == b":method").map(|h| h.value()).unwrap_or_default();
+        let method = METRIC_METHODS.iter().find(|m| m.as_bytes() == method).copied().unwrap_or("OTHER");
+        Exchange { started: Instant::now(), method, status: None, body_bytes: 0 }
+    }
+}
+
+// Per-connection state for checking that request bodies match their framing
+#[derive(Default)]
+pub(crate) struct RequestTracker {
+    // Limits of the connection's ALPN, set when HTTP/3 starts on it
+    pub(crate) policy: RequestPolicy,
+    // Streams whose request head has been delivered
+    open: HashSet<u64>,
+    // Body bytes still expected on streams whose request carried content-length
+    remaining: HashMap<u64, u64>,
+    // Body bytes received so far, under maxRequestBodyBytes
+    received: HashMap<u64, u64>,
+    // When bodies not yet complete have stalled too long, under requestBodyTimeoutMs
+    body_deadlines: HashMap<u64, Instant>,
+    // Streams whose request was rejected; anything further on them is dropped
+    rejected: HashSet<u64>,
+    // Request streams not yet retired by quiche, i.e. still being answered
+    active: HashSet<u64>,
+    // First request stream ID not yet accepted, as announced in GOAWAY
+    next_request: u64,
+    // Set once GOAWAY has been sent; requests from this ID on are refused
+    goaway: Option<u64>,
+    // Requests delivered to JavaScript and not yet answered in full
+    exchanges: HashMap<u64, Exchange>,
+}
+
+impl RequestTracker {
+    // Stops checking a request body against content-length once it is being
+    // piped to a file, where maxBytes bounds it instead. Returns false if the
+    // body has already ended.
+    pub(crate) fn pipe(&mut self, stream_id: u64) -> bool {
+        self.remaining.remove(&stream_id);
+        self.forget_body(stream_id);
+        self.open.contains(&stream_id)
+    }
+
+    // Notes the status of a response head sendResponse() has sent
+    pub(crate) fn note_response(&mut self, stream_id: u64, headers: &[HttpHeader]) {
+        let status = headers.iter().find(|h| h.name == ":status").and_then(|h| h.value.parse::<u16>().ok());
+        if let Some(exchange) = self.exchanges.get_mut(&stream_id) {
+            exchange.status = status.filter(|status| (100..600).contains(status));
+        }
+    }
+
+    // Counts response body bytes written, and the response towards the
+    // metrics once its FIN has been
+    pub(crate) fn note_body(&mut self, stream_id: u64, written: u32, fin_written: bool, metrics: &H3Metrics) {
+        let Some(exchange) = self.exchanges.get_mut(&stream_id) else {
+            return;
+        };
+        exchange.body_bytes += u64::from(written);
+        if fin_written {
+            let exchange = self.exchanges.remove(&stream_id).unwrap();
+            if let Some(status) = exchange.status {
+                metrics.record(&exchange, status);
+            }
+        }
+    }
+
+    // Stops applying the body limits to a stream
+    fn forget_body(&mut self, stream_id: u64) {
+        self.received.remove(&stream_id);
+        self.body_deadlines.remove(&stream_id);
+    }
+
+    // Counts body bytes against maxRequestBodyBytes and pushes the stall
+    // deadline back. Returns false once the body is over the limit.
+    fn body_progress(&mut self, stream_id: u64, len: usize) -> bool {
+        if let (Some(deadline), Some(timeout)) = (self.body_deadlines.get_mut(&stream_id), self.policy.body_timeout) {
+            *deadline = Instant::now() + timeout;
+        }
+        match (self.received.get_mut(&stream_id), self.policy.max_body_bytes) {
+            (Some(received), Some(max)) => {
+                *received += len as u64;
+                *received <= max
+            }
+            _ => true,
+        }
+    }
+
+    // Sends GOAWAY so the client opens no further requests on this connection,
+    // unless it has already been sent
+    pub(crate) fn send_goaway(
+        &mut self,
+        h3: &mut h3::Connection,
+        conn: &mut quiche::Connection,
+    ) -> h3::Result<()> {
+        if self.goaway.is_some() {
+            return Ok(());
+        }
+        h3.send_goaway(conn, self.next_request)?;
+        self.goaway = Some(self.next_request);
+        Ok(())
+    }
+
+    // Whether every accepted request has been answered and its stream retired.
+    // quiche forgets a stream once both directions are complete, after which
+    // its capacity can no longer be queried.
+    pub(crate) fn drained(&mut self, conn: &quiche::Connection) -> bool {
+        self.active.retain(|&stream_id| conn.stream_capacity(stream_id).is_ok());
+        self.active.is_empty()
+    }
+}
+
+// Checks a request head against RFC 9114, section 4.3.1, and RFC 9220 where
+// the policy allows extended CONNECT, and returns its content-length.
+// Anything ambiguous is refused rather than passed on, so a proxy in front
+// of another HTTP implementation cannot be desynchronised.
+fn validate_request(headers: &[h3::Header], policy: RequestPolicy) -> Result<Option<u64>, &'static str> {
+    let mut pseudo: HashMap<&[u8], &[u8]> = HashMap::new();
+    let mut seen_regular = false;
+    let mut content_length = None;
+
+    for header in headers {
+        let (name, value) = (header.name(), header.value());
+
+        if name.is_empty() || name.iter().any(|b| b.is_ascii_uppercase()) {
+            return Err("header names must be lowercase and non-empty");
+        }
+        if value.iter().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+            return Err("header value contains CR, LF or NUL");
+        }
+
+        if name[0] == b':' {
+            if seen_regular {
+                return Err("pseudo-header after regular header");
+            }
+            if !matches!(name, b":method" | b":scheme" | b":authority" | b":path" | b":protocol") {
+                return Err("unknown pseudo-header");
+            }
+            if pseudo.insert(name, value).is_some() {
+                return Err("duplicate pseudo-header");
+            }
+            continue;
+        }
+        seen_regular = true;
+
+        if CONNECTION_SPECIFIC_HEADERS.contains(&name) {
+            return Err("connection-specific header");
+        }
+        if name == b"te" && value != b"trailers" {
+            return Err("te header other than \"trailers\"");
+        }
+        if name == b"content-length" {
+            let len = std::str::from_utf8(value)
+                .ok()
+                .filter(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or("malformed content-length")?;
+            if content_length.replace(len).is_some_and(|prev| prev != len) {
+                return Err("conflicting content-length headers");
+            }
+        }
+    }
+
+    let method = pseudo.get(&b":method"[..]).ok_or("missing :method")?;
+
+    // Only extended CONNECT (RFC 9220) carries :protocol, and only where it is
+    // advertised; unlike plain CONNECT it names a resource as other requests do
+    if pseudo.contains_key(&b":protocol"[..]) {
+        if !policy.extended_connect {
+            return Err(":protocol without extended CONNECT");
+        }
+        if *method != b"CONNECT" {
+            return Err(":protocol on a method other than CONNECT");
+        }
+        let present = |name: &[u8]| pseudo.get(name).is_some_and(|v| !v.is_empty());
+        if !(present(b":scheme") && present(b":path") && present(b":authority")) {
+            return Err("extended CONNECT without :scheme, :path and :authority");
+        }
+        return Ok(content_length);
+    }
+
+    if *method == b"CONNECT" {
+        if pseudo.get(&b":authority"[..]).is_none_or(|a| a.is_empty()) {
+            return Err("CONNECT without :authority");
+        }
+        if pseudo.contains_key(&b":scheme"[..]) || pseudo.contains_key(&b":path"[..]) {
+            return Err("CONNECT with :scheme or :path");
+        }
+        return Ok(content_length);
+    }
+
+    if pseudo.get(&b":scheme"[..]).is_none_or(|s| s.is_empty()) {
+        return Err("missing :scheme");
+    }
+    match pseudo.get(&b":path"[..]) {
+        Some(path) if path.starts_with(b"/") => (),
+        Some(path) if *path == b"*" && *method == b"OPTIONS" => (),
+        _ => return Err("missing or malformed :path"),
+    }
+
+    Ok(content_length)
+}
+
+// Refuses a request with `code` (H3_MESSAGE_ERROR unless it was merely
+// refused) and reports it to JavaScript
+#[allow(clippy::too_many_arguments)]
+fn reject_request(
+    conn: &mut quiche::Connection,
+    requests: &mut RequestTracker,
+    stream_id: u64,
+    code: H3Error,
+    reason: &str,
+    conn_id: &str,
+    peer: SocketAddr,
+    events: &dyn EmitEvent,
+) {
+    eprintln!("Rejecting request on stream {} from {:?}: {}", stream_id, peer, reason);
+
+    let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, code as u64);
+    let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Write, code as u64);
+
+    requests.open.remove(&stream_id);
+    requests.remaining.remove(&stream_id);
+    requests.active.remove(&stream_id);
+    requests.forget_body(stream_id);
+    requests.exchanges.remove(&stream_id);
+    requests.rejected.insert(stream_id);
+    events.emit(QuicEvent::request_rejected(conn_id, peer, stream_id, code, reason));
+}
+
+// Answers a request over its limits with `status` (431 for its head, RFC
+// 6585; 413 or 408 for its body) and stops reading it, or resets it with
+// H3_MESSAGE_ERROR if the response cannot be sent, as when the application
+// has already started one
+#[allow(clippy::too_many_arguments)]
+fn refuse_request(
+    h3: &mut h3::Connection,
+    conn: &mut quiche::Connection,
+    requests: &mut RequestTracker,
+    stream_id: u64,
+    status: u32,
+    reason: &str,
+    conn_id: &str,
+    peer: SocketAddr,
+    events: &dyn EmitEvent,
+) {
+    let headers = [h3::Header::new(b":status", status.to_string().as_bytes())];
+    if h3.send_response(conn, stream_id, &headers, true).is_err() {
+        reject_request(conn, requests, stream_id, H3Error::MessageError, reason, conn_id, peer, events);
+        return;
+    }
+
+    eprintln!("Answering {} to request on stream {} from {:?}", status, stream_id, peer);
+    // The response is complete, so the rest of the request is not wanted
+    let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, H3Error::NoError as u64);
+    requests.open.remove(&stream_id);
+    requests.remaining.remove(&stream_id);
+    requests.forget_body(stream_id);
+    requests.exchanges.remove(&stream_id);
+    requests.rejected.insert(stream_id);
+    events.emit(QuicEvent::request_refused(conn_id, peer, stream_id, status, reason));
+}
+
+// Answers 408 to requests whose bodies have stalled past
+// requestBodyTimeoutMs. Returns whether there were any.
+pub(crate) fn expire_request_bodies(
+    h3: &mut h3::Connection,
+    conn: &mut quiche::Connection,
+    requests: &mut RequestTracker,
+    conn_id: &str,
+    peer: SocketAddr,
+    metrics: &H3Metrics,
+    events: &dyn EmitEvent,
+) -> bool {
+    let now = Instant::now();
+    let expired: Vec<u64> = requests.body_deadlines.iter().filter(|(_, at)| **at <= now).map(|(id, _)| *id).collect();
+    for &stream_id in &expired {
+        metrics.request_body_timeouts.fetch_add(1, Ordering::Relaxed);
+        let reason = "request body timed out";
+        refuse_request(h3, conn, requests, stream_id, 408, reason, conn_id, peer, events);
+    }
+    !expired.is_empty()
+}
+
+// Handles every pending HTTP/3 event on the connection, delivering request
+// heads as `request` events and bodies as `data` events, unless piped. Malformed requests
+// are refused with a `requestRejected` event instead. `buf` is scratch space
+// for reading bodies.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn poll_h3(
+    h3: &mut h3::Connection,
+    conn: &mut quiche::Connection,
+    requests: &mut RequestTracker,
+    sinks: &mut Sinks,
+    conn_id: &str,
+    peer: SocketAddr,
+    buf: &mut [u8],
+    metrics: &H3Metrics,
+    events: &dyn EmitEvent,
+) {
+    loop {
+        match h3.poll(conn) {
+            Ok((stream_id, event)) if requests.rejected.contains(&stream_id) => {
+                if matches!(event, h3::Event::Finished | h3::Event::Reset(_)) {
+                    requests.rejected.remove(&stream_id);
+                }
+            }
+            Ok((stream_id, h3::Event::Headers { list, .. })) if requests.open.contains(&stream_id) => {
+                if list.iter().any(|h| h.name().starts_with(b":")) {
+                    let reason = "pseudo-header in trailers";
+                    reject_request(conn, requests, stream_id, H3Error::MessageError, reason, conn_id, peer, events);
+                } else {
+                    println!("Ignoring trailers on stream {} from {:?}", stream_id, peer);
+                }
+            }
+            Ok((stream_id, h3::Event::Headers { .. })) if requests.goaway.is_some_and(|id| stream_id >= id) => {
+                let reason = "request after GOAWAY";
+                reject_request(conn, requests, stream_id, H3Error::RequestRejected, reason, conn_id, peer, events);
+            }
+            Ok((stream_id, h3::Event::Headers { list, .. })) if requests.policy.oversized(&list) => {
+                metrics.oversized_request_heads.fetch_add(1, Ordering::Relaxed);
+                let reason = "request header fields too large";
+                refuse_request(h3, conn, requests, stream_id, 431, reason, conn_id, peer, events);
+            }
+            Ok((stream_id, h3::Event::Headers { list, .. })) => match validate_request(&list, requests.policy) {
+                Ok(Some(len)) if requests.policy.max_body_bytes.is_some_and(|max| len > max) => {
+                    requests.next_request = requests.next_request.max(stream_id + 4);
+                    metrics.oversized_request_bodies.fetch_add(1, Ordering::Relaxed);
+                    let reason = "request body too large";
+                    refuse_request(h3, conn, requests, stream_id, 413, reason, conn_id, peer, events);
+                }
+                Ok(content_length) => {
+                    requests.open.insert(stream_id);
+                    requests.active.insert(stream_id);
+                    requests.next_request = requests.next_request.max(stream_id + 4);
+                    if let Some(len) = content_length {
+                        requests.remaining.insert(stream_id, len);
+                    }
+                    if requests.policy.max_body_bytes.is_some() {
+                        requests.received.insert(stream_id, 0);
+                    }
+                    if let Some(timeout) = requests.policy.body_timeout {
+                        requests.body_deadlines.insert(stream_id, Instant::now() + timeout);
+                    }
+                    requests.exchanges.insert(stream_id, Exchange::new(&list));
+                    let headers = list.iter().map(HttpHeader::from).collect();
+                    events.emit(QuicEvent::request(conn_id, peer, stream_id, headers));
+                }
+                Err(reason) => {
+                    reject_request(conn, requests, stream_id, H3Error::MessageError, reason, conn_id, peer, events)
+                }
+            },
+            // Bodies piped to files are read by Sinks::pump() as the files keep up
+            Ok((stream_id, h3::Event::Data)) if sinks.contains(stream_id) => (),
+            Ok((stream_id, h3::Event::Finished)) if sinks.contains(stream_id) => {
+                requests.open.remove(&stream_id);
+                sinks.finish(stream_id);
+            }
+            Ok((stream_id, h3::Event::Data)) => {
+                // Everything readable goes out as one event, ahead of any rejection
+                let mut body = Vec::new();
+                let flush = |body: &mut Vec<u8>| {
+                    if !body.is_empty() {
+                        events.emit(QuicEvent::data(conn_id, peer, stream_id, std::mem::take(body), false));
+                    }
+                };
+                loop {
+                    match h3.recv_body(conn, stream_id, buf) {
+                        Ok(len) => {
+                            if let Some(remaining) = requests.remaining.get_mut(&stream_id) {
+                                if len as u64 > *remaining {
+                                    flush(&mut body);
+                                    let reason = "body longer than content-length";
+                                    let code = H3Error::MessageError;
+                                    reject_request(conn, requests, stream_id, code, reason, conn_id, peer, events);
+                                    break;
+                                }
+                                *remaining -= len as u64;
+                            }
+                            if !requests.body_progress(stream_id, len) {
+                                flush(&mut body);
+                                metrics.oversized_request_bodies.fetch_add(1, Ordering::Relaxed);
+                                let reason = "request body too large";
+                                refuse_request(h3, conn, requests, stream_id, 413, reason, conn_id, peer, events);
+                                break;
+                            }
+                            body.extend_from_slice(&buf[..len]);
+                        }
+                        Err(h3::Error::Done) => break,
+                        Err(e) => {
+                            eprintln!("Failed to read body on stream {}: {:?}", stream_id, e);
+                            break;
+                        }
+                    }
+                }
+                flush(&mut body);
+            }
+            Ok((stream_id, h3::Event::Finished)) => {
+                requests.open.remove(&stream_id);
+                requests.forget_body(stream_id);
+                if requests.remaining.remove(&stream_id).is_some_and(|left| left > 0) {
+                    let reason = "body shorter than content-length";
+                    reject_request(conn, requests, stream_id, H3Error::MessageError, reason, conn_id, peer, events);
+                    continue;
+                }
+                events.emit(QuicEvent::data(conn_id, peer, stream_id, Vec::new(), true));
+            }
+            Ok((stream_id, h3::Event::Reset(code))) => {
+                requests.open.remove(&stream_id);
+                requests.remaining.remove(&stream_id);
+                requests.active.remove(&stream_id);
+                requests.forget_body(stream_id);
+                requests.exchanges.remove(&stream_id);
+                sinks.fail(stream_id, format!("Stream reset with code {}", code));
+                events.emit(QuicEvent::stream_reset(conn_id, peer, stream_id, code));
+            }
+            Ok((_, h3::Event::PriorityUpdate)) => (),
+            Ok((id, h3::Event::GoAway)) => {
+                println!("Received GOAWAY ({}) from {:?}", id, peer);
+            }
+            Err(h3::Error::Done) => break,
+            Err(e) => {
+                eprintln!("HTTP/3 error: {:?}", e);
+                events.emit(QuicEvent::connection_error(conn_id, peer, format!("{:?}", e)));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use napi::bindgen_prelude::*;
+use napi::{sys, JsDeferred, JsObject, JsSymbol};
+use napi_derive::napi;
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+type IterResolver = Box<dyn FnOnce(Env) -> Result<JsObject> + Send>;
+type IterDeferred = JsDeferred<JsObject, IterResolver>;
+
+/// A connection yielded by `QuicServer.incoming()`.
+#[napi(object)]
+pub struct IncomingConnection {
+    pub conn_id: String,
+    pub peer: String,
+    /// The server name the client asked for through SNI, if any.
+    pub server_name: Option<String>,
+    /// The application protocol the handshake will settle on, as far as the
+    /// ClientHello tells.
+    pub alpn: Option<String>,
+}
+
+/// What an `onAccept()` callback may answer with instead of a boolean, to
+/// treat the connection according to its class (say, its `alpn` or
+/// `serverName`).
+#[napi(object)]
+pub struct AcceptDecision {
+    /// `false` refuses the connection as answering `false` does.
+    pub accept: Option<bool>,
+    /// Closes the connection, with NO_ERROR and reason `idle timeout`, once
+    /// nothing has been received on it for this many milliseconds. This
+    /// only shortens the `maxIdleTimeout` negotiated in the handshake, so
+    /// give `config` the longest any class needs.
+    pub max_idle_timeout_ms: Option<u32>,
+}
+
+// Items produced by a packet loop and consumed by an async iterator on the
+// JS thread. Items pushed while nobody is waiting are buffered.
+pub(crate) struct AsyncQueue<T> {
+    state: Mutex<QueueState<T>>,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    waiters: VecDeque<IterDeferred>,
+    closed: bool,
+}
+
+impl<T: ToNapiValue + Send + 'static> AsyncQueue<T> {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(AsyncQueue {
+            state: Mutex::new(QueueState { items: VecDeque::new(), waiters: VecDeque::new(), closed: false }),
+        })
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        match state.waiters.pop_front() {
+            Some(waiter) => waiter.resolve(Box::new(move |env| iter_result(env, Some(item)))),
+            None => state.items.push_back(item),
+        }
+    }
+
+    // Ends iteration once buffered items are consumed
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for waiter in state.waiters.drain(..) {
+            waiter.resolve(Box::new(|env| iter_result::<T>(env, None)));
+        }
+    }
+
+    fn next(&self, env: Env) -> Result<JsObject> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(item) = state.items.pop_front() {
+            return resolved(env, Box::new(move |env| iter_result(env, Some(item))));
+        }
+        if state.closed {
+            return resolved(env, Box::new(|env| iter_result::<T>(env, None)));
+        }
+
+        let (deferred, promise) = env.create_deferred()?;
+        state.waiters.push_back(deferred);
+        Ok(promise)
+    }
+
+    // Called when a `for await` loop exits early: drop anything buffered
+    fn finish(&self, env: Env) -> Result<JsObject> {
+        self.state.lock().unwrap().items.clear();
+        self.close();
+        resolved(env, Box::new(|env| iter_result::<T>(env, None)))
+    }
+}
+
+// Builds an iterator result object, `{ value, done }`
+fn iter_result<T: ToNapiValue>(env: Env, value: Option<T>) -> Result<JsObject> {
+    let mut result = env.create_object()?;
+    result.set("done", value.is_none())?;
+    if let Some(value) = value {
+        result.set("value", value)?;
+    }
+    Ok(result)
+}
+
+// Returns a promise that settles with `resolver`'s result on the next tick
+fn resolved(env: Env, resolver: IterResolver) -> Result<JsObject> {
+    let (deferred, promise) = env.create_deferred()?;
+    deferred.resolve(resolver);
+    Ok(promise)
+}
+
+// Serves as [Symbol.asyncIterator]: an iterator is its own iterable
+unsafe extern "C" fn return_this(env: sys::napi_env, info: sys::napi_callback_info) -> sys::napi_value {
+    let mut this = ptr::null_mut();
+    sys::napi_get_cb_info(env, info, ptr::null_mut(), ptr::null_mut(), &mut this, ptr::null_mut());
+    this
+}
+
+// Makes an iterator usable with `for await` by giving it Symbol.asyncIterator
+fn make_async_iterable(env: Env, mut iterator: JsObject) -> Result<JsObject> {
+    let symbol: JsSymbol =
+        env.get_global()?.get_named_property::<JsObject>("Symbol")?.get_named_property("asyncIterator")?;
+    iterator.set_property(symbol, env.create_function("asyncIterator", return_this)?)?;
+    Ok(iterator)
+}
+
+/// Async iterator over connections accepted by a server.
+#[napi]
+pub struct ConnectionIterator {
+    queue: Arc<AsyncQueue<IncomingConnection>>,
+}
+
+#[napi]
+impl ConnectionIterator {
+    #[napi(ts_return_type = "Promise<IteratorResult<IncomingConnection>>")]
+    pub fn next(&self, env: Env) -> Result<JsObject> {
+        self.queue.next(env)
+    }
+
+    #[napi(js_name = "return", ts_return_type = "Promise<IteratorResult<IncomingConnection>>")]
+    pub fn finish(&self, env: Env) -> Result<JsObject> {
+        self.queue.finish(env)
+    }
+}
+
+/// Async iterator over stream IDs opened by a peer.
+#[napi]
+pub struct StreamIterator {
+    queue: Arc<AsyncQueue<i64>>,
+}
+
+#[napi]
+impl StreamIterator {
+    #[napi(ts_return_type = "Promise<IteratorResult<number>>")]
+    pub fn next(&self, env: Env) -> Result<JsObject> {
+        self.queue.next(env)
+    }
+
+    #[napi(js_name = "return", ts_return_type = "Promise<IteratorResult<number>>")]
+    pub fn finish(&self, env: Env) -> Result<JsObject> {
+        self.queue.finish(env)
+    }
+}
+
+pub(crate) fn connection_iterator(env: Env, queue: Arc<AsyncQueue<IncomingConnection>>) -> Result<JsObject> {
+    let iterator = ConnectionIterator { queue }.into_instance(env)?;
+    make_async_iterable(env, iterator.as_object(env))
+}
+
+pub(crate) fn stream_iterator(env: Env, queue: Arc<AsyncQueue<i64>>) -> Result<JsObject> {
+    let iterator = StreamIterator { queue }.into_instance(env)?;
+    make_async_iterable(env, iterator.as_object(env))
+}
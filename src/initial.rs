@@ -0,0 +1,136 @@
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+// Connection attempts held for onInitial at once; beyond this, Initials get
+// the native decision straight away
+const MAX_HELD: usize = 1024;
+
+// Datagrams held per attempt, enough for a ClientHello spread over several
+const MAX_DATAGRAMS: usize = 8;
+
+/// A connection attempt offered to an `onInitial()` callback.
+#[napi(object)]
+pub struct InitialPacket {
+    pub peer: String,
+    /// The destination connection ID the client picked, in hex.
+    pub dcid: String,
+    /// Whether the Initial carries an address validation token, as it does
+    /// once the client has been sent a Retry.
+    pub token_present: bool,
+}
+
+// What to do with an Initial that would open a connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Accept,
+    Retry,
+    Drop,
+}
+
+impl Verdict {
+    pub(crate) fn parse(answer: &str) -> Option<Verdict> {
+        match answer {
+            "accept" => Some(Verdict::Accept),
+            "retry" => Some(Verdict::Retry),
+            "drop" => Some(Verdict::Drop),
+            _ => None,
+        }
+    }
+}
+
+// Initials held back until onInitial answers for them, by peer and DCID.
+// Datagrams arriving for an attempt meanwhile join the first one.
+#[derive(Default)]
+pub(crate) struct HeldInitials {
+    held: HashMap<(SocketAddr, Vec<u8>), Held>,
+}
+
+struct Held {
+    datagrams: Vec<Vec<u8>>,
+    deadline: Instant,
+    // None until answered, and for answers that name no verdict
+    verdict: Option<Option<Verdict>>,
+}
+
+// Whether an Initial was held, and if so whether it starts a new attempt
+#[derive(Debug, PartialEq)]
+pub(crate) enum Hold {
+    New,
+    Joined,
+    Full,
+}
+
+impl HeldInitials {
+    pub(crate) fn hold(&mut self, peer: SocketAddr, dcid: &[u8], datagram: &[u8], deadline: Instant) -> Hold {
+        if let Some(held) = self.held.get_mut(&(peer, dcid.to_vec())) {
+            // Retransmissions past the cap are dropped; the client repeats them
+            if held.datagrams.len() < MAX_DATAGRAMS {
+                held.datagrams.push(datagram.to_vec());
+            }
+            return Hold::Joined;
+        }
+        if self.held.len() >= MAX_HELD {
+            return Hold::Full;
+        }
+        let held = Held { datagrams: vec![datagram.to_vec()], deadline, verdict: None };
+        self.held.insert((peer, dcid.to_vec()), held);
+        Hold::New
+    }
+
+    // Records onInitial's answer for an attempt still held
+    pub(crate) fn decide(&mut self, peer: SocketAddr, dcid: &[u8], verdict: Option<Verdict>) {
+        if let Some(held) = self.held.get_mut(&(peer, dcid.to_vec())) {
+            held.verdict.get_or_insert(verdict);
+        }
+    }
+
+    // Releases the datagrams of attempts answered for or past their
+    // deadline, in arrival order, with the verdict to apply (None for the
+    // native decision)
+    pub(crate) fn release(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<u8>, Option<Verdict>)> {
+        let mut released = Vec::new();
+        self.held.retain(|(peer, _), held| {
+            let verdict = match held.verdict {
+                Some(verdict) => verdict,
+                None if now >= held.deadline => None,
+                None => return true,
+            };
+            released.extend(held.datagrams.drain(..).map(|datagram| (*peer, datagram, verdict)));
+            false
+        });
+        released
+    }
+
+    pub(crate) fn memory(&self) -> usize {
+        self.held.iter().map(|((_, dcid), held)| dcid.len() + held.datagrams.iter().map(Vec::len).sum::<usize>()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn releases_answered_and_expired_attempts() {
+        let now = Instant::now();
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let mut held = HeldInitials::default();
+        assert_eq!(held.hold(a, b"x", b"one", now + Duration::from_secs(1)), Hold::New);
+        assert_eq!(held.hold(a, b"x", b"two", now + Duration::from_secs(1)), Hold::Joined);
+        assert_eq!(held.hold(b, b"y", b"three", now + Duration::from_secs(1)), Hold::New);
+        assert!(held.release(now).is_empty());
+
+        held.decide(a, b"x", Some(Verdict::Drop));
+        let released = held.release(now);
+        assert_eq!(released.len(), 2);
+        assert!(released.iter().all(|(peer, _, verdict)| *peer == a && *verdict == Some(Verdict::Drop)));
+        assert_eq!(released[1].1, b"two");
+
+        let released = held.release(now + Duration::from_secs(1));
+        assert_eq!(released, vec![(b, b"three".to_vec(), None)]);
+        assert_eq!(held.memory(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+// A small JSON writer for statusJson(). The documents are a few levels of
+// objects, arrays, strings and numbers, not worth a serializer dependency.
+
+pub(crate) trait ToJson {
+    fn write_json(&self, out: &mut String);
+}
+
+// An object under construction: `JsonObject::new().field("a", 1)`
+pub(crate) struct JsonObject(String);
+
+impl JsonObject {
+    pub(crate) fn new() -> JsonObject {
+        JsonObject(String::from("{"))
+    }
+
+    pub(crate) fn field(mut self, name: &str, value: impl ToJson) -> JsonObject {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        name.write_json(&mut self.0);
+        self.0.push(':');
+        value.write_json(&mut self.0);
+        self
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+impl ToJson for JsonObject {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(&self.0);
+        out.push('}');
+    }
+}
+
+impl ToJson for str {
+    fn write_json(&self, out: &mut String) {
+        out.push('"');
+        for c in self.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
+
+impl ToJson for String {
+    fn write_json(&self, out: &mut String) {
+        self.as_str().write_json(out)
+    }
+}
+
+impl ToJson for bool {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+macro_rules! integer_to_json {
+    ($($ty:ty),*) => {
+        $(impl ToJson for $ty {
+            fn write_json(&self, out: &mut String) {
+                out.push_str(&self.to_string());
+            }
+        })*
+    };
+}
+
+integer_to_json!(u32, u64, usize, i64);
+
+// Non-finite numbers have no JSON form and are written as null
+impl ToJson for f64 {
+    fn write_json(&self, out: &mut String) {
+        match self.is_finite() {
+            true => out.push_str(&self.to_string()),
+            false => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn write_json(&self, out: &mut String) {
+        out.push('[');
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            value.write_json(out);
+        }
+        out.push(']');
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn write_json(&self, out: &mut String) {
+        self.as_slice().write_json(out)
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn write_json(&self, out: &mut String) {
+        (**self).write_json(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_nested_values() {
+        let json = JsonObject::new()
+            .field("name", "a \"quoted\"\n\u{1}name")
+            .field("count", 3u32)
+            .field("rtt", 1.5)
+            .field("missing", None::<u32>)
+            .field("list", vec![JsonObject::new().field("ok", true), JsonObject::new()])
+            .finish();
+        assert_eq!(
+            json,
+            r#"{"name":"a \"quoted\"\n\u0001name","count":3,"rtt":1.5,"missing":null,"list":[{"ok":true},{}]}"#
+        );
+    }
+}
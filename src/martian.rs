@@ -0,0 +1,118 @@
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// How long packets for a closed connection are still attributed to it
+const CLOSED_LIFETIME: Duration = Duration::from_secs(60);
+// Closed connections remembered at once
+const MAX_CLOSED: usize = 4096;
+
+// RFC 9000, section 10.3: 5 unpredictable bytes and the 16-byte token at
+// least, and smaller than the packet that triggered it so that two
+// endpoints cannot keep resetting each other
+const MIN_RESET_LEN: usize = 21;
+const MAX_RESET_LEN: usize = 43;
+
+// What the server does once a connection reaches martianThreshold
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum MartianAction {
+    // Emit a martianPackets event
+    Event,
+    // Emit the event and answer with a stateless reset
+    Reset,
+}
+
+impl MartianAction {
+    pub(crate) fn parse(action: Option<&str>) -> napi::Result<Self> {
+        match action {
+            None | Some("event") => Ok(MartianAction::Event),
+            Some("reset") => Ok(MartianAction::Reset),
+            Some(other) => Err(napi::Error::from_reason(format!(
+                "martianAction must be \"event\" or \"reset\", not {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+// A connection that closed recently, and the packets seen for it since
+struct Closed {
+    id: String,
+    peer: SocketAddr,
+    closed_at: Instant,
+    packets: usize,
+}
+
+// Stateless reset tokens, and the connections packets may still arrive for
+// after they closed. Tokens are an HMAC of the connection ID under a
+// per-process key, so the server can reset connections it no longer has
+// state for, until it restarts.
+pub(crate) struct Martians {
+    key: hmac::Key,
+    rng: SystemRandom,
+    closed: HashMap<Vec<u8>, Closed>,
+}
+
+impl Martians {
+    pub(crate) fn new() -> napi::Result<Self> {
+        let rng = SystemRandom::new();
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+            .map_err(|_| napi::Error::from_reason("Failed to generate stateless reset key"))?;
+        Ok(Martians { key, rng, closed: HashMap::new() })
+    }
+
+    // Bytes held for recently closed connections
+    pub(crate) fn memory(&self) -> usize {
+        self.closed.iter().map(|(cid, closed)| cid.len() + closed.id.len() + std::mem::size_of::<Closed>()).sum()
+    }
+
+    // The stateless reset token advertised for connection ID `cid`
+    pub(crate) fn reset_token(&self, cid: &[u8]) -> u128 {
+        let tag = hmac::sign(&self.key, cid);
+        let mut token = [0; 16];
+        token.copy_from_slice(&tag.as_ref()[..16]);
+        u128::from_be_bytes(token)
+    }
+
+    // Writes a stateless reset for `cid` into `out`, in answer to a packet
+    // `received` bytes long. Returns its length, or None if the packet was
+    // too short to answer without risking a loop.
+    pub(crate) fn stateless_reset(&self, cid: &[u8], received: usize, out: &mut [u8]) -> Option<usize> {
+        if received <= MIN_RESET_LEN {
+            return None;
+        }
+        let len = (received - 1).min(MAX_RESET_LEN);
+
+        self.rng.fill(&mut out[..len - 16]).ok()?;
+        // A short header with the fixed bit set
+        out[0] = out[0] & 0x3f | 0x40;
+        out[len - 16..len].copy_from_slice(&self.reset_token(cid).to_be_bytes());
+        Some(len)
+    }
+
+    // Remembers a connection that has closed
+    pub(crate) fn closed(&mut self, cid: &[u8], id: &str, peer: SocketAddr) {
+        if self.closed.len() >= MAX_CLOSED {
+            self.closed.retain(|_, c| c.closed_at.elapsed() < CLOSED_LIFETIME);
+            if self.closed.len() >= MAX_CLOSED {
+                return;
+            }
+        }
+        let closed = Closed { id: id.to_string(), peer, closed_at: Instant::now(), packets: 0 };
+        self.closed.insert(cid.to_vec(), closed);
+    }
+
+    // Counts a packet addressed to `cid`. Returns the connection's hex ID,
+    // its peer and the packets seen since it closed, if it closed recently.
+    pub(crate) fn packet_for(&mut self, cid: &[u8]) -> Option<(String, SocketAddr, usize)> {
+        let closed = self.closed.get_mut(cid)?;
+        if closed.closed_at.elapsed() >= CLOSED_LIFETIME {
+            self.closed.remove(cid);
+            return None;
+        }
+        closed.packets += 1;
+        Some((closed.id.clone(), closed.peer, closed.packets))
+    }
+}
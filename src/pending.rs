@@ -0,0 +1,103 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::AtomicU64;
+
+use napi::Result;
+
+use crate::{send_datagram, DatagramPolicy};
+
+// Stream writes and datagrams a client makes before its connection can carry
+// them, sent in order once the handshake completes, or as soon as 0-RTT
+// lets them go out early. Writes on a stream still holding some wait behind
+// them.
+pub(crate) struct PendingWrites {
+    streams: BTreeMap<u64, PendingStream>,
+    datagrams: VecDeque<Vec<u8>>,
+    // Datagrams held before datagramSend() returns false
+    max_datagrams: usize,
+}
+
+#[derive(Default)]
+struct PendingStream {
+    data: Vec<u8>,
+    fin: bool,
+}
+
+impl PendingWrites {
+    pub(crate) fn new(max_datagrams: usize) -> Self {
+        PendingWrites { streams: BTreeMap::new(), datagrams: VecDeque::new(), max_datagrams }
+    }
+
+    // Whether a write on `stream_id` has to be held
+    pub(crate) fn holds_stream(&self, conn: &quiche::Connection, stream_id: u64) -> bool {
+        !carries_streams(conn) || self.streams.contains_key(&stream_id)
+    }
+
+    // Whether a datagram has to be held
+    pub(crate) fn holds_datagrams(&self, conn: &quiche::Connection) -> bool {
+        !carries_datagrams(conn) || !self.datagrams.is_empty()
+    }
+
+    pub(crate) fn stream_send(&mut self, stream_id: u64, data: &[u8], fin: bool) -> Result<()> {
+        let stream = self.streams.entry(stream_id).or_default();
+        if stream.fin && (fin || !data.is_empty()) {
+            return Err(napi::Error::from_reason(format!("Stream {} has already been finished", stream_id)));
+        }
+        stream.data.extend_from_slice(data);
+        stream.fin = fin;
+        Ok(())
+    }
+
+    // Holds a datagram, or returns false when too many are held already
+    pub(crate) fn datagram_send(&mut self, data: &[u8]) -> bool {
+        if self.datagrams.len() >= self.max_datagrams {
+            return false;
+        }
+        self.datagrams.push_back(data.to_vec());
+        true
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.streams.is_empty() && self.datagrams.is_empty()
+    }
+
+    // Hands what the connection can carry now to quiche. Stream data that
+    // flow control holds back stays for the next call.
+    pub(crate) fn flush(&mut self, conn: &mut quiche::Connection, policy: DatagramPolicy, drops: &AtomicU64) {
+        if carries_streams(conn) {
+            self.streams.retain(|&stream_id, stream| match conn.stream_send(stream_id, &stream.data, stream.fin) {
+                Ok(written) => {
+                    stream.data.drain(..written);
+                    !stream.data.is_empty()
+                }
+                Err(quiche::Error::Done) => true,
+                Err(e) => {
+                    eprintln!("Dropping data held for stream {}: {:?}", stream_id, e);
+                    false
+                }
+            });
+        }
+
+        if carries_datagrams(conn) {
+            while let Some(datagram) = self.datagrams.pop_front() {
+                match send_datagram(conn, &datagram, policy, drops) {
+                    Ok(true) => (),
+                    // quiche's queue is full; the rest go once it drains
+                    Ok(false) => {
+                        self.datagrams.push_front(datagram);
+                        break;
+                    }
+                    Err(e) => eprintln!("Dropping a held datagram: {}", e.reason),
+                }
+            }
+        }
+    }
+}
+
+fn carries_streams(conn: &quiche::Connection) -> bool {
+    conn.is_established() || conn.is_in_early_data()
+}
+
+// 0-RTT carries datagrams only if the resumed session allowed them
+fn carries_datagrams(conn: &quiche::Connection) -> bool {
+    conn.is_established() || (conn.is_in_early_data() && conn.dgram_max_writable_len().is_some())
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::json::JsonObject;
+
+// Where the packet loop spends its time, as far as `profilePhases` tells.
+// quiche encrypts and decrypts packets inline, so while a connection
+// handshakes its receiving and sending count as Crypto (TLS dominates it
+// then), and afterwards as Recv and Send.
+#[derive(Clone, Copy)]
+pub(crate) enum Phase {
+    Recv,
+    Crypto,
+    Send,
+}
+
+impl Phase {
+    // The phase receiving (or sending, when `send`) falls in on `conn`
+    pub(crate) fn of(conn: &quiche::Connection, send: bool) -> Phase {
+        match (conn.is_established(), send) {
+            (false, _) => Phase::Crypto,
+            (true, false) => Phase::Recv,
+            (true, true) => Phase::Send,
+        }
+    }
+}
+
+// Nanoseconds spent in each phase
+#[derive(Default)]
+pub(crate) struct PhaseTimes {
+    nanos: [AtomicU64; 3],
+}
+
+impl PhaseTimes {
+    pub(crate) fn nanos(&self, phase: Phase) -> i64 {
+        self.nanos[phase as usize].load(Ordering::Relaxed) as i64
+    }
+
+    pub(crate) fn json(&self) -> JsonObject {
+        JsonObject::new()
+            .field("recvNs", self.nanos(Phase::Recv))
+            .field("cryptoNs", self.nanos(Phase::Crypto))
+            .field("sendNs", self.nanos(Phase::Send))
+    }
+}
+
+// Adds timings to a connection's times and to its server's totals
+#[derive(Clone, Copy)]
+pub(crate) struct Profile<'a> {
+    pub(crate) conn: &'a PhaseTimes,
+    pub(crate) total: &'a PhaseTimes,
+}
+
+impl Profile<'_> {
+    // Counts the time since `start` towards `phase`
+    pub(crate) fn add(&self, phase: Phase, start: Instant) {
+        let nanos = start.elapsed().as_nanos() as u64;
+        self.conn.nanos[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+        self.total.nanos[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+    }
+}
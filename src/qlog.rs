@@ -0,0 +1,230 @@
+use napi_derive::napi;
+use std::path::PathBuf;
+
+#[cfg(any(feature = "qlog", test))]
+use std::fs::File;
+#[cfg(any(feature = "qlog", test))]
+use std::io::{self, BufWriter, Write};
+#[cfg(any(feature = "qlog", test))]
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+// Starts every JSON-SEQ record quiche writes
+#[cfg(any(feature = "qlog", test))]
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// How `qlogDir` traces are written, to keep always-on tracing affordable.
+#[napi(object)]
+#[derive(Clone)]
+pub struct QlogOutput {
+    /// `"zstd"` to compress each file on the fly, as `.sqlog.zst`, through
+    /// the `zstd` command, which must be on the PATH. Uncompressed by default.
+    pub compression: Option<String>,
+    /// Starts a new file once a trace has written this many bytes, before
+    /// compression: `<role>-<connId>.sqlog`, then `.1.sqlog`, `.2.sqlog` and
+    /// so on, each beginning on a record boundary. Unlimited by default.
+    pub max_file_bytes: Option<i64>,
+}
+
+// Where qlogDir traces go, and how they are written
+#[cfg_attr(not(feature = "qlog"), allow(dead_code))]
+pub(crate) struct QlogDir {
+    pub(crate) dir: PathBuf,
+    pub(crate) compress: bool,
+    pub(crate) max_file_bytes: Option<u64>,
+}
+
+impl QlogDir {
+    pub(crate) fn new(dir: PathBuf, output: Option<&QlogOutput>) -> napi::Result<Self> {
+        let compress = match output.and_then(|output| output.compression.as_deref()) {
+            None => false,
+            Some("zstd") => true,
+            Some(other) => {
+                return Err(napi::Error::from_reason(format!("Unknown qlog compression {:?}; expected \"zstd\"", other)))
+            }
+        };
+        let max_file_bytes = match output.and_then(|output| output.max_file_bytes) {
+            Some(bytes) if bytes <= 0 => return Err(napi::Error::from_reason("qlog maxFileBytes must be positive")),
+            bytes => bytes.map(|bytes| bytes as u64),
+        };
+        Ok(QlogDir { dir, compress, max_file_bytes })
+    }
+}
+
+// A trace written to a series of files under a QlogDir, rotated by size
+// and optionally piped through zstd
+#[cfg(any(feature = "qlog", test))]
+pub(crate) struct TraceWriter {
+    // The first file's path without its `.sqlog`
+    base: PathBuf,
+    compress: bool,
+    max_file_bytes: Option<u64>,
+    // Files started so far, and bytes written to the last one
+    files: u32,
+    written: u64,
+    // Taken when dropped
+    output: Option<Output>,
+}
+
+#[cfg(any(feature = "qlog", test))]
+enum Output {
+    File(BufWriter<File>),
+    Zstd(Child, ChildStdin),
+}
+
+#[cfg(any(feature = "qlog", test))]
+impl Output {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Output::File(file) => file,
+            Output::Zstd(_, stdin) => stdin,
+        }
+    }
+
+    // Flushes the file, or lets zstd finish its frame and exit
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::File(mut file) => file.flush(),
+            Output::Zstd(mut child, stdin) => {
+                drop(stdin);
+                child.wait().map(drop)
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "qlog", test))]
+impl TraceWriter {
+    pub(crate) fn create(dir: &QlogDir, name: &str) -> io::Result<Self> {
+        let base = dir.dir.join(name);
+        let output = Some(open(&base, 0, dir.compress)?);
+        Ok(TraceWriter {
+            base,
+            compress: dir.compress,
+            max_file_bytes: dir.max_file_bytes,
+            files: 1,
+            written: 0,
+            output,
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        self.output.as_mut().expect("qlog trace used after it finished").writer()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let next = open(&self.base, self.files, self.compress)?;
+        if let Some(output) = self.output.replace(next) {
+            output.finish()?;
+        }
+        self.files += 1;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+// Opens the `index`th file of a trace
+#[cfg(any(feature = "qlog", test))]
+fn open(base: &std::path::Path, index: u32, compress: bool) -> io::Result<Output> {
+    let mut name = base.as_os_str().to_owned();
+    if index > 0 {
+        name.push(format!(".{}", index));
+    }
+    name.push(if compress { ".sqlog.zst" } else { ".sqlog" });
+    let file = File::create(name)?;
+    if !compress {
+        return Ok(Output::File(BufWriter::new(file)));
+    }
+
+    let mut child = Command::new("zstd").arg("-q").arg("-c").stdin(Stdio::piped()).stdout(file).spawn()?;
+    let stdin = child.stdin.take().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "zstd has no stdin"))?;
+    Ok(Output::Zstd(child, stdin))
+}
+
+#[cfg(any(feature = "qlog", test))]
+impl Write for TraceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only between records, so that every file holds whole ones
+        let full = self.max_file_bytes.is_some_and(|max| self.written >= max);
+        if full && buf.first() == Some(&RECORD_SEPARATOR) {
+            self.rotate()?;
+        }
+        let written = self.writer().write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+#[cfg(any(feature = "qlog", test))]
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        let Some(output) = self.output.take() else {
+            return;
+        };
+        if let Err(e) = output.finish() {
+            eprintln!("Failed to finish qlog trace {:?}: {}", self.base, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(name: &str, output: QlogOutput) -> (PathBuf, TraceWriter) {
+        let dir = std::env::temp_dir().join(format!("qlog-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let qlog_dir = QlogDir::new(dir.clone(), Some(&output)).unwrap();
+        let writer = TraceWriter::create(&qlog_dir, "server-ab").unwrap();
+        (dir, writer)
+    }
+
+    #[test]
+    fn rotates_between_records() {
+        let (dir, mut writer) = trace("rotate", QlogOutput { compression: None, max_file_bytes: Some(8) });
+        for record in ["\x1e{\"a\":1}\n", "\x1e{\"b\":2}\n", "\x1e{\"c\":3}\n"] {
+            // quiche may write a record in pieces; only its start can rotate
+            let (head, tail) = record.as_bytes().split_at(4);
+            writer.write_all(head).unwrap();
+            writer.write_all(tail).unwrap();
+        }
+        drop(writer);
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server-ab.sqlog"), "\x1e{\"a\":1}\n");
+        assert_eq!(read("server-ab.1.sqlog"), "\x1e{\"b\":2}\n");
+        assert_eq!(read("server-ab.2.sqlog"), "\x1e{\"c\":3}\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compresses_through_zstd() {
+        if Command::new("zstd").arg("--version").output().is_err() {
+            eprintln!("zstd is not installed; skipping");
+            return;
+        }
+        let output = QlogOutput { compression: Some("zstd".to_string()), max_file_bytes: None };
+        let (dir, mut writer) = trace("zstd", output);
+        let record = b"\x1e{\"name\":\"transport:packet_sent\"}\n";
+        writer.write_all(&record.repeat(1000)).unwrap();
+        drop(writer);
+
+        let path = dir.join("server-ab.sqlog.zst");
+        let compressed = std::fs::read(&path).unwrap();
+        assert_eq!(&compressed[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        let plain = Command::new("zstd").arg("-dqc").arg(&path).output().unwrap().stdout;
+        assert_eq!(plain, record.repeat(1000));
+        assert!(compressed.len() < plain.len() / 10);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_compression() {
+        let output = QlogOutput { compression: Some("gzip".to_string()), max_file_bytes: None };
+        assert!(QlogDir::new(PathBuf::new(), Some(&output)).is_err());
+    }
+}
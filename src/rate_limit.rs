@@ -0,0 +1,101 @@
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+// Source addresses tracked before idle buckets are pruned
+const MAX_TRACKED_SOURCES: usize = 65536;
+
+/// Token-bucket limits on Initial packets that would start a new handshake.
+/// Packets for existing connections are never limited.
+#[napi(object)]
+pub struct InitialRateLimit {
+    /// Initials accepted per second from a single IP address.
+    pub per_ip_per_second: Option<u32>,
+    /// Initials a single IP address may send in a burst (default: the rate).
+    pub per_ip_burst: Option<u32>,
+    /// Initials accepted per second across all sources.
+    pub global_per_second: Option<u32>,
+    /// Initials accepted in a burst across all sources (default: the rate).
+    pub global_burst: Option<u32>,
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: Option<u32>, now: Instant) -> Self {
+        let burst = f64::from(burst.unwrap_or(rate).max(1));
+        TokenBucket { rate: f64::from(rate), burst, tokens: burst, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Applies InitialRateLimit on the packet loop
+pub(crate) struct InitialLimiter {
+    per_ip: Option<(u32, Option<u32>)>,
+    sources: HashMap<IpAddr, TokenBucket>,
+    global: Option<TokenBucket>,
+}
+
+impl InitialLimiter {
+    pub(crate) fn new(limits: &InitialRateLimit) -> Self {
+        let now = Instant::now();
+        InitialLimiter {
+            per_ip: limits.per_ip_per_second.map(|rate| (rate, limits.per_ip_burst)),
+            sources: HashMap::new(),
+            global: limits.global_per_second.map(|rate| TokenBucket::new(rate, limits.global_burst, now)),
+        }
+    }
+
+    // Bytes held for per-IP buckets
+    pub(crate) fn memory(&self) -> usize {
+        self.sources.len() * std::mem::size_of::<(IpAddr, TokenBucket)>()
+    }
+
+    // Whether an Initial from `ip` may be processed. The per-IP limit is
+    // checked first so one flooding source cannot drain the global budget.
+    pub(crate) fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        if let Some((rate, burst)) = self.per_ip {
+            if self.sources.len() >= MAX_TRACKED_SOURCES && !self.sources.contains_key(&ip) {
+                self.prune(now);
+            }
+
+            let bucket = self.sources.entry(ip).or_insert_with(|| TokenBucket::new(rate, burst, now));
+            if !bucket.take(now) {
+                return false;
+            }
+        }
+
+        self.global.as_mut().is_none_or(|global| global.take(now))
+    }
+
+    // Forgets sources whose buckets have refilled, as they hold no state
+    // that a fresh bucket would not
+    fn prune(&mut self, now: Instant) {
+        self.sources.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.burst
+        });
+    }
+}
@@ -0,0 +1,78 @@
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// How long a client has to echo a Retry token. It only has to survive one
+// round trip, so this is generous.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+const TAG_LEN: usize = 32;
+
+// Mints and verifies address validation tokens for Retry packets. A token
+// is `issued (8) | odcid len (1) | odcid | tag`, where the HMAC tag also
+// covers the client's address and the connection ID the Retry told it to
+// use, so a token cannot be replayed from elsewhere or for another
+// connection. The key is per-process: tokens die with the server.
+pub(crate) struct RetryTokens {
+    key: hmac::Key,
+    epoch: Instant,
+}
+
+impl RetryTokens {
+    pub(crate) fn new() -> napi::Result<Self> {
+        let rng = SystemRandom::new();
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+            .map_err(|_| napi::Error::from_reason("Failed to generate retry token key"))?;
+        Ok(RetryTokens { key, epoch: Instant::now() })
+    }
+
+    pub(crate) fn mint(&self, odcid: &[u8], peer: SocketAddr, scid: &[u8]) -> Vec<u8> {
+        let issued = self.epoch.elapsed().as_millis() as u64;
+
+        let mut token = Vec::with_capacity(9 + odcid.len() + TAG_LEN);
+        token.extend_from_slice(&issued.to_be_bytes());
+        token.push(odcid.len() as u8);
+        token.extend_from_slice(odcid);
+
+        let tag = hmac::sign(&self.key, &signed_data(&token, peer, scid));
+        token.extend_from_slice(tag.as_ref());
+        token
+    }
+
+    // Returns the original destination connection ID if `token` was minted
+    // by this server for `peer`, for packets sent to `dcid`, and has not expired
+    pub(crate) fn validate(
+        &self,
+        token: &[u8],
+        peer: SocketAddr,
+        dcid: &[u8],
+    ) -> Option<quiche::ConnectionId<'static>> {
+        let (body, tag) = token.split_at(token.len().checked_sub(TAG_LEN)?);
+        let (issued, rest) = body.split_first_chunk::<8>()?;
+        let (&odcid_len, odcid) = rest.split_first()?;
+        if odcid.len() != odcid_len as usize || odcid.len() > quiche::MAX_CONN_ID_LEN {
+            return None;
+        }
+
+        hmac::verify(&self.key, &signed_data(body, peer, dcid), tag).ok()?;
+
+        let age = (self.epoch.elapsed().as_millis() as u64).saturating_sub(u64::from_be_bytes(*issued));
+        if age > TOKEN_LIFETIME.as_millis() as u64 {
+            return None;
+        }
+
+        Some(odcid.to_vec().into())
+    }
+}
+
+fn signed_data(body: &[u8], peer: SocketAddr, cid: &[u8]) -> Vec<u8> {
+    let mut data = body.to_vec();
+    match peer.ip() {
+        IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+    }
+    data.extend_from_slice(&peer.port().to_be_bytes());
+    data.extend_from_slice(cid);
+    data
+}
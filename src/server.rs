@@ -0,0 +1,4419 @@
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{JsDeferred, JsFunction, JsObject, JsUnknown};
+use napi_derive::napi;
+use quiche::{self, Config, RecvInfo};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::callback::{guard, CallbackResult};
+use crate::cid::ConnectionIds;
+use crate::clock::ClockWatch;
+use crate::congestion::CongestionWatch;
+use crate::context;
+use crate::digest::{Digesting, Digests};
+use crate::config::{h3_config, request_policy, Http3Settings, QuicConfig};
+use crate::error_codes::{H3Error, TransportError};
+use crate::events::{event_callback, negotiated_alpn, EmitEvent, EventCallback, QuicEvent};
+use crate::framing::{message_bytes, Framers, Framing};
+use crate::martian::{MartianAction, Martians};
+use crate::qlog::{QlogDir, QlogOutput};
+use crate::phases::{Phase, PhaseTimes, Profile};
+use crate::json::{JsonObject, ToJson};
+use crate::incoming::{connection_iterator, stream_iterator, AcceptDecision, AsyncQueue, IncomingConnection};
+use crate::initial::{HeldInitials, Hold, InitialPacket, Verdict};
+use crate::http3::{
+    expire_request_bodies, poll_h3, to_h3_headers, H3Metrics, HttpHeader, RequestPolicy, RequestTracker,
+    ResponseMetrics,
+};
+use crate::rate_limit::{InitialLimiter, InitialRateLimit};
+use crate::retry::RetryTokens;
+use crate::sink::{PipeOptions, Sinks};
+use crate::sni::{ClientHello, ClientHellos, Hello, ServerCertificate};
+use crate::transport::{self, Transport};
+use crate::{
+    apply_datagram_options, apply_pacing_rate, build_server_config, close_code, close_connection, coalescing_window,
+    enable_qlog, flush_egress, flush_egress_profiled, h3_err_to_napi, hex_id, identity, io_err_to_napi, is_collected,
+    load_trust_anchors, open_keylog, parse_hex_id, peer_cert_chain, qlog_dir, quiche_err_to_napi, read_datagrams,
+    read_streams, send_datagram, send_request_datagram, set_stream_priority, start_qlog, stop_qlog, stream_readable_fin,
+    stream_shutdown, to_stream_id, DEFAULT_DGRAM_QUEUE_LEN, DatagramOptions, DatagramPolicy, PemFile, MAX_DATAGRAM_SIZE,
+    RECV_BUFFER_SIZE,
+};
+
+// Upper bound on how long the loop blocks in recv before checking for
+// stop/close; it wakes sooner when a connection timer is due
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Default time between scheduler rounds
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(100);
+// Default time onAccept has to answer before a connection is refused
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+// Default onInitial timeout, about when clients retransmit their first Initial
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+// Default time drain() lets in-flight requests finish
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Default time, in seconds, clients may cache an Alt-Svc advertisement
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+// Sets Alt-Svc on responses of a Node HTTP server, unless the app already has
+const ALT_SVC_SCRIPT: &str = "(value) => (req, res) => {
+    if (!res.headersSent && !res.hasHeader('alt-svc')) {
+        res.setHeader('alt-svc', value);
+    }
+}";
+
+// Default handshake retransmissions that trigger a handshakeRetransmits event
+const HANDSHAKE_RETRANSMIT_THRESHOLD: usize = 3;
+
+// BoringSSL's session ticket key size
+const TICKET_KEY_LEN: usize = 48;
+
+// Most unhandledPacket events emitted a second, so a scan cannot flood the JS thread
+const MAX_UNHANDLED_EVENTS: usize = 100;
+
+// Default unusable packets per connection before martianAction is taken
+const MARTIAN_THRESHOLD: usize = 10;
+// AEAD tags of recently processed packets kept per connection, so that a
+// duplicated or replayed datagram is not mistaken for one under other keys
+const RECENT_TAGS: usize = 64;
+// AEAD tag length of every QUIC v1 cipher suite
+const TAG_LEN: usize = 16;
+
+// TLS alert sent when requireClientCert is set and the client sent no certificate
+const TLS_CERTIFICATE_REQUIRED: i64 = 116;
+
+const QUIC_V1: u32 = 0x00000001; // Manually specify QUIC v1
+// Most versions a Version Negotiation packet lists, which keeps it small
+const MAX_VERSIONS: usize = 64;
+
+struct Client {
+    conn: quiche::Connection,
+    // Hex form of the connection ID, as reported to JavaScript
+    id: String,
+    peer: SocketAddr,
+    // Set once the handshake has completed and 0-RTT keys are usable, respectively
+    handshake_done: bool,
+    early_data_ready: bool,
+    tls_alert_reported: bool,
+    // HTTP/3 layer, created once the connection can carry application data
+    h3: Option<quiche::h3::Connection>,
+    requests: RequestTracker,
+    // Set once JS calls incomingStreams() for this connection
+    incoming_streams: Option<Arc<AsyncQueue<i64>>>,
+    // Next client-initiated bidi and uni stream IDs not yet announced, and
+    // the announced ones quiche has not let go of yet
+    next_peer_streams: [u64; 2],
+    peer_streams: HashSet<u64>,
+    admission: Admission,
+    // Streams being written to files by pipeToFile()
+    sinks: Sinks,
+    // Streams carrying messages framed by setMessageFraming()
+    framers: Framers,
+    // Streams whose data setStreamDigest() hashes
+    digests: Digests,
+    congestion: CongestionWatch,
+    // Packets retransmitted before the handshake completed, and whether
+    // that has been reported
+    handshake_retransmits: usize,
+    retransmits_reported: bool,
+    // quiche's lost packets, lost bytes and retransmissions at the last
+    // loss event
+    loss_reported: (usize, u64, usize),
+    // Short-header datagrams in a row since the handshake that yielded no
+    // usable packet, and whether that has been acted on. Any packet that
+    // processes starts the count over.
+    undecryptable: usize,
+    martian_reported: bool,
+    recent_tags: VecDeque<[u8; TAG_LEN]>,
+    // Time spent on this connection by phase, under `profilePhases`
+    phases: Option<PhaseTimes>,
+    // Whether the client has proven it owns its address, by a Retry token or
+    // a Handshake packet, lifting the anti-amplification limit
+    address_validated: bool,
+    // Streams JS has written to but not finished
+    sending: HashSet<u64>,
+    // When the connection was accepted, for maxConnectionAgeMs
+    accepted_at: Instant,
+    // From onAccept(): how long the connection may go without receiving a
+    // packet, and when it last did
+    idle_timeout: Option<Duration>,
+    last_received: Instant,
+    // Set once a raw connection winds down
+    winding_down: Option<WindDown>,
+    // Set by closeConnection() with a timeout until the close is sent
+    graceful_close: Option<GracefulClose>,
+    // Streams whose FIN JS has written, until the peer has acknowledged it
+    finishing: HashSet<u64>,
+    // Window streamSend() writes are held back for, so that tiny ones share
+    // packets, and whether the last JS call left some held
+    coalescing: Option<Duration>,
+    held: bool,
+}
+
+// What the server does for a client that offers none of its protocols
+#[derive(Clone, Copy, PartialEq)]
+enum AlpnMismatch {
+    // Let the handshake fail with no_application_protocol
+    Close,
+    // Negotiate the client's first choice anyway
+    Accept,
+}
+
+impl AlpnMismatch {
+    fn parse(policy: Option<&str>) -> napi::Result<Self> {
+        match policy {
+            None | Some("close") => Ok(AlpnMismatch::Close),
+            Some("accept") => Ok(AlpnMismatch::Accept),
+            Some(other) => Err(napi::Error::from_reason(format!(
+                "alpnMismatch must be \"close\" or \"accept\", not {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+// Whether a connection's application data may be delivered to JavaScript
+#[derive(Clone, Copy, PartialEq)]
+enum Admission {
+    Admitted,
+    // Waiting for the onAccept callback until the deadline
+    Pending(Instant),
+    // The callback has answered, but the loop has not acted on it yet
+    Decided(bool),
+    // Closed with CONNECTION_REFUSED
+    Refused,
+}
+
+impl Client {
+    // Counts retransmissions until the handshake completes. A few are normal
+    // loss; many usually mean an MTU blackhole or a middlebox dropping
+    // handshake packets, so crossing the threshold is reported once.
+    fn count_handshake_retransmits(&mut self, shared: &Shared, events: &dyn EmitEvent) {
+        if self.conn.is_established() {
+            return;
+        }
+
+        let retransmits = self.conn.stats().retrans;
+        let new = retransmits.saturating_sub(self.handshake_retransmits);
+        if new == 0 {
+            return;
+        }
+        self.handshake_retransmits = retransmits;
+        shared.metrics.handshake_retransmits.fetch_add(new as u64, Ordering::Relaxed);
+
+        let threshold = shared.handshake_retransmit_threshold;
+        if !self.retransmits_reported && threshold > 0 && retransmits >= threshold {
+            self.retransmits_reported = true;
+            let event = QuicEvent::handshake_retransmits(&self.id, self.peer, retransmits);
+            eprintln!("{}", event.message.as_deref().unwrap_or_default());
+            events.emit(event);
+        }
+    }
+
+    // Notes the outcome of a datagram on an established connection: one that
+    // yielded a packet starts the undecryptable count over. Returns whether
+    // the connection should be dropped with a stateless reset.
+    fn check_decryption(
+        &mut self,
+        shared: &Shared,
+        tag: Option<[u8; TAG_LEN]>,
+        processed: bool,
+        len: usize,
+        events: &dyn EmitEvent,
+    ) -> bool {
+        if processed {
+            self.undecryptable = 0;
+            if let Some(tag) = tag {
+                if self.recent_tags.len() == RECENT_TAGS {
+                    self.recent_tags.pop_front();
+                }
+                self.recent_tags.push_back(tag);
+            }
+            return false;
+        }
+
+        match tag {
+            // A replay of a packet quiche already took; it drops those silently too
+            Some(tag) if !self.recent_tags.contains(&tag) => self.count_undecryptable(shared, len, events),
+            // Long-header packets may simply be late, their keys discarded
+            _ => false,
+        }
+    }
+
+    // Counts a datagram that yielded no packet, and takes martianAction once
+    // the threshold is reached
+    fn count_undecryptable(&mut self, shared: &Shared, len: usize, events: &dyn EmitEvent) -> bool {
+        self.undecryptable += 1;
+        shared.metrics.undecryptable_datagrams.fetch_add(1, Ordering::Relaxed);
+        if shared.log_martian_packets {
+            eprintln!("Undecryptable datagram of {} bytes on connection {} from {:?}", len, self.id, self.peer);
+        }
+
+        let threshold = shared.martian_threshold;
+        if self.martian_reported || threshold == 0 || self.undecryptable < threshold {
+            return false;
+        }
+        self.martian_reported = true;
+        events.emit(QuicEvent::martian_packets(&self.id, self.peer, self.undecryptable, false));
+        shared.martian_action == MartianAction::Reset
+    }
+
+    // Emits a `loss` event once the loss timer has declared packets lost or
+    // their frames have been resent, with the change since the last one
+    fn report_loss(&mut self, events: &dyn EmitEvent) {
+        let stats = self.conn.stats();
+        let (lost, lost_bytes, retrans) = self.loss_reported;
+        if stats.lost == lost && stats.retrans == retrans {
+            return;
+        }
+        self.loss_reported = (stats.lost, stats.lost_bytes, stats.retrans);
+        events.emit(QuicEvent::loss(
+            &self.id,
+            self.peer,
+            stats.lost - lost,
+            stats.lost_bytes - lost_bytes,
+            stats.retrans - retrans,
+        ));
+    }
+
+    // Emits a `congestion` event if the window shrank since the last check
+    fn report_congestion(&mut self, events: &dyn EmitEvent) {
+        if let Some((before, after)) = self.congestion.check(&self.conn) {
+            events.emit(QuicEvent::congestion(&self.id, self.peer, before, after));
+        }
+    }
+
+    // Notes a write JS made to a stream: until its FIN, the stream may be
+    // blocked; after it, its acknowledgement is awaited
+    fn note_write(&mut self, stream_id: u64, fin_written: bool) {
+        if fin_written {
+            self.sending.remove(&stream_id);
+            self.finishing.insert(stream_id);
+        } else {
+            self.sending.insert(stream_id);
+        }
+    }
+
+    // Writes to a stream for streamSend(). Within a coalescing window the
+    // packets are left for the loop to send, with whatever follows them.
+    fn stream_send(&mut self, stream_id: u64, data: &[u8], fin: bool) -> Result<u32> {
+        let written = match self.conn.stream_send(stream_id, data, fin) {
+            Ok(written) => written,
+            Err(quiche::Error::Done) => 0,
+            Err(e) => return Err(quiche_err_to_napi(e)),
+        };
+        self.note_write(stream_id, fin && written == data.len());
+        self.held = self.coalescing.is_some();
+        Ok(written as u32)
+    }
+
+    // Why the connection may not be sending, as far as quiche shows it
+    fn blocked_state(&mut self) -> BlockedState {
+        let conn = &self.conn;
+        let amplification_limited = !(self.address_validated || conn.is_established());
+
+        let mut blocked_streams = Vec::new();
+        self.sending.retain(|&stream_id| match conn.stream_capacity(stream_id) {
+            Ok(0) => {
+                blocked_streams.push(stream_id as i64);
+                true
+            }
+            Ok(_) => true,
+            // Reset, stopped or collected: nothing more is sent on it
+            Err(_) => false,
+        });
+        blocked_streams.sort_unstable();
+
+        BlockedState {
+            amplification_limited,
+            connection_blocked: !self.sending.is_empty() && blocked_streams.len() == self.sending.len(),
+            app_limited: !amplification_limited && blocked_streams.is_empty(),
+            blocked_streams,
+            congestion_window: conn.path_stats().find(|p| p.active).map_or(0, |p| p.cwnd as i64),
+        }
+    }
+
+    // Emits `streamFinAcked` for finished streams quiche has let go of. It
+    // keeps a stream until the peer has acknowledged everything sent on it,
+    // FIN included, and everything received on it has been read; quiche
+    // 0.22 exposes neither half on its own, nor when the FIN left in a packet.
+    fn report_acked_fins(&mut self, events: &dyn EmitEvent) {
+        let (conn, id, peer) = (&self.conn, &self.id, self.peer);
+        self.finishing.retain(|&stream_id| match conn.stream_capacity(stream_id) {
+            Ok(_) => true,
+            Err(quiche::Error::InvalidStreamState(_)) => {
+                events.emit(QuicEvent::stream_fin_acked(id, peer, stream_id));
+                false
+            }
+            // Stopped by the peer, which streamReset or the application reports
+            Err(_) => false,
+        });
+    }
+
+    // Bytes of the connection's own state and stream ID sets
+    fn memory(&self) -> usize {
+        let streams = self.peer_streams.capacity() + self.sending.capacity() + self.finishing.capacity();
+        std::mem::size_of::<Client>() + self.id.len() + streams * std::mem::size_of::<u64>()
+    }
+
+    // Winds the connection down and returns whether it may be closed: an
+    // HTTP/3 client is sent GOAWAY and may be closed once its requests are
+    // answered, a raw one once the streams open now are done, and either at
+    // once with `expired`. Streams a raw peer opens from now on are refused
+    // with `stream_error_code`.
+    fn wind_down(&mut self, expired: bool, stream_error_code: u64) -> bool {
+        let idle = match &mut self.h3 {
+            Some(h3) => {
+                if let Err(e) = self.requests.send_goaway(h3, &mut self.conn) {
+                    eprintln!("Failed to send GOAWAY to {:?}: {:?}", self.peer, e);
+                }
+                self.requests.drained(&self.conn)
+            }
+            None => {
+                let (conn, next_peer_streams) = (&self.conn, self.next_peer_streams);
+                let streams = self.peer_streams.iter().chain(&self.sending).chain(&self.finishing);
+                let wind_down = self.winding_down.get_or_insert_with(|| WindDown {
+                    open: streams.copied().filter(|&id| !is_collected(conn, id)).collect(),
+                    refuse_from: next_peer_streams,
+                    code: stream_error_code,
+                });
+                wind_down.open.retain(|&id| !is_collected(conn, id));
+                wind_down.open.is_empty()
+            }
+        };
+        idle || expired
+    }
+
+    // Closes a wound-down connection with NO_ERROR, in HTTP/3's code space
+    // if it speaks HTTP/3
+    fn close_wound_down(&mut self, reason: &[u8]) {
+        let code = match self.h3 {
+            Some(_) => H3Error::NoError as u64,
+            None => TransportError::NoError as u64,
+        };
+        let _ = self.conn.close(self.h3.is_some(), code, reason);
+    }
+
+    // Delivers whatever application data the connection has buffered
+    fn serve(&mut self, shared: &Shared, buf: &mut [u8], events: &dyn EmitEvent) {
+        if !(self.conn.is_established() || self.conn.is_in_early_data()) {
+            return;
+        }
+
+        let h3_config = shared.h3_configs.get(self.conn.application_proto());
+        if let (None, Some((h3_config, policy))) = (&self.h3, h3_config) {
+            match quiche::h3::Connection::with_transport(&mut self.conn, h3_config) {
+                Ok(h3) => {
+                    self.h3 = Some(h3);
+                    self.requests.policy = *policy;
+                }
+                Err(e) => eprintln!("Failed to start HTTP/3 on connection from {:?}: {:?}", self.peer, e),
+            }
+        }
+
+        read_datagrams(&mut self.conn, &self.id, self.peer, buf, events, self.h3.is_some());
+
+        if self.h3.is_none() {
+            self.refuse_streams();
+        }
+        self.announce_streams();
+
+        let events = &Digesting::new(&mut self.digests, events);
+        match &mut self.h3 {
+            Some(h3) => {
+                let (requests, sinks, metrics) = (&mut self.requests, &mut self.sinks, &shared.metrics.h3);
+                poll_h3(h3, &mut self.conn, requests, sinks, &self.id, self.peer, buf, metrics, events);
+                self.sinks.pump(&mut self.conn, Some(h3), buf);
+            }
+            None => {
+                self.sinks.pump(&mut self.conn, None, buf);
+                self.framers.flush(&mut self.conn);
+                self.framers.read(&mut self.conn, &self.id, self.peer, buf, events);
+                let (sinks, framers) = (&self.sinks, &self.framers);
+                read_streams(&mut self.conn, &self.id, self.peer, buf, events, |id| {
+                    sinks.contains(id) || framers.contains(id)
+                });
+            }
+        }
+    }
+
+    // Stops streams the peer opened after the connection began winding down,
+    // before they are announced or read
+    fn refuse_streams(&mut self) {
+        let Some(wind_down) = &self.winding_down else {
+            return;
+        };
+
+        let refused: Vec<u64> = self
+            .conn
+            .readable()
+            .filter(|&id| id & 0x1 == 0 && id >= wind_down.refuse_from[(id >> 1 & 0x1) as usize])
+            .collect();
+        for stream_id in refused {
+            println!("Refusing stream {} from {:?} while winding down", stream_id, self.peer);
+            let _ = self.conn.stream_shutdown(stream_id, quiche::Shutdown::Read, wind_down.code);
+            // Bidirectional streams are reset in our direction too
+            if stream_id & 0x2 == 0 {
+                let _ = self.conn.stream_shutdown(stream_id, quiche::Shutdown::Write, wind_down.code);
+            }
+        }
+    }
+
+    // Notes streams the peer has opened since the last packet, announcing
+    // them on raw connections. Opening a stream implicitly opens every
+    // lower-numbered one of the same type.
+    fn announce_streams(&mut self) {
+        let queue = self.incoming_streams.as_ref().filter(|_| self.h3.is_none());
+        let mut opened = false;
+        for stream_id in self.conn.readable() {
+            // Server-initiated streams have the low bit set
+            if stream_id & 0x1 != 0 {
+                continue;
+            }
+
+            let next = &mut self.next_peer_streams[(stream_id >> 1 & 0x1) as usize];
+            while *next <= stream_id {
+                if let Some(queue) = queue {
+                    queue.push(*next as i64);
+                }
+                self.peer_streams.insert(*next);
+                opened = true;
+                *next += 4;
+            }
+        }
+
+        // Kept to the streams still open, which MAX_STREAMS bounds
+        if opened {
+            let conn = &self.conn;
+            self.peer_streams.retain(|&id| !is_collected(conn, id));
+        }
+    }
+
+    // Counts the streams still open; see StreamCounts
+    fn stream_counts(&mut self) -> StreamCounts {
+        let conn = &self.conn;
+        self.peer_streams.retain(|&id| !is_collected(conn, id));
+
+        let mut counts = StreamCounts {
+            peer_bidi: 0,
+            peer_uni: 0,
+            local_bidi: 0,
+            local_uni: 0,
+            bidi_left: conn.peer_streams_left_bidi() as i64,
+            uni_left: conn.peer_streams_left_uni() as i64,
+        };
+        let local = self.sending.iter().chain(&self.finishing).filter(|&&id| id & 0x1 != 0 && !is_collected(conn, id));
+        for &stream_id in self.peer_streams.iter().chain(local) {
+            let count = match (stream_id & 0x1 != 0, stream_id & 0x2 != 0) {
+                (false, false) => &mut counts.peer_bidi,
+                (false, true) => &mut counts.peer_uni,
+                (true, false) => &mut counts.local_bidi,
+                (true, true) => &mut counts.local_uni,
+            };
+            *count += 1;
+        }
+        counts
+    }
+
+    // When the idle timeout onAccept() gave the connection runs out, unless
+    // it is closing anyway
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.idle_timeout.filter(|_| !is_closing(&self.conn)).map(|timeout| self.last_received + timeout)
+    }
+
+    // Summarizes the connection for statusJson()
+    // Where this connection's timings go, if profiling
+    fn profile<'a>(&'a self, shared: &'a Shared) -> Option<Profile<'a>> {
+        self.phases.as_ref().map(|conn| Profile { conn, total: &shared.metrics.phases })
+    }
+
+    // flush_egress, timed under `profilePhases`
+    fn flush(&mut self, shared: &Shared, out: &mut [u8]) {
+        let profile = self.phases.as_ref().map(|conn| Profile { conn, total: &shared.metrics.phases });
+        flush_egress_profiled(shared.socket.as_ref(), &mut self.conn, out, profile);
+    }
+
+    fn status(&mut self) -> JsonObject {
+        let counts = self.stream_counts();
+        let stats = self.conn.stats();
+        let path = self.conn.path_stats().next();
+        let state = match self.admission {
+            Admission::Pending(_) | Admission::Decided(_) => "admitting",
+            Admission::Refused => "refused",
+            Admission::Admitted if self.conn.is_closed() => "closed",
+            Admission::Admitted if self.conn.is_draining() => "draining",
+            Admission::Admitted if self.winding_down.is_some() => "windingDown",
+            Admission::Admitted if self.conn.is_established() => "established",
+            Admission::Admitted => "handshaking",
+        };
+
+        let packets = JsonObject::new()
+            .field("sent", stats.sent)
+            .field("received", stats.recv)
+            .field("lost", stats.lost)
+            .field("retransmitted", stats.retrans);
+        let bytes = JsonObject::new()
+            .field("sent", stats.sent_bytes)
+            .field("received", stats.recv_bytes)
+            .field("lost", stats.lost_bytes);
+        let streams = JsonObject::new()
+            .field("peerBidi", counts.peer_bidi)
+            .field("peerUni", counts.peer_uni)
+            .field("localBidi", counts.local_bidi)
+            .field("localUni", counts.local_uni)
+            .field("bidiLeft", counts.bidi_left)
+            .field("uniLeft", counts.uni_left);
+
+        JsonObject::new()
+            .field("connId", &self.id)
+            .field("peer", self.peer.to_string())
+            .field("state", state)
+            .field("alpn", negotiated_alpn(&self.conn))
+            .field("http3", self.h3.is_some())
+            .field("ageMs", self.accepted_at.elapsed().as_millis() as u64)
+            .field("rttMs", path.as_ref().map(|path| path.rtt.as_secs_f64() * 1000.0))
+            .field("cwnd", path.as_ref().map(|path| path.cwnd))
+            .field("packets", packets)
+            .field("bytes", bytes)
+            .field("streams", streams)
+            .field("phases", self.phases.as_ref().map(PhaseTimes::json))
+    }
+}
+
+type ClientMap = HashMap<quiche::ConnectionId<'static>, Client>;
+
+// A raw connection winding down for drain() or maxConnectionAgeMs
+struct WindDown {
+    // Streams open when it began, until quiche lets go of them
+    open: HashSet<u64>,
+    // Client-initiated bidi and uni streams from these IDs on are refused
+    refuse_from: [u64; 2],
+    // Application error code they are refused with
+    code: u64,
+}
+
+// A closeConnection() waiting for the connection to wind down first
+struct GracefulClose {
+    deadline: Instant,
+    application_error: bool,
+    error_code: u64,
+    reason: Vec<u8>,
+}
+
+// A connection whose handshake is in progress
+struct Handshake {
+    // The connection ID we chose, which keys the client map
+    scid: quiche::ConnectionId<'static>,
+    // The DCID of the client's first Initial
+    odcid: quiche::ConnectionId<'static>,
+}
+
+/// Options for constructing a `QuicServer`.
+#[napi(object)]
+#[derive(Default)]
+pub struct QuicServerOptions {
+    /// PEM files holding the certificate chain and its private key. Either
+    /// these or `cert` and `key` are required.
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// The certificate chain and private key themselves, as PEM, or as a
+    /// DER Buffer (a single certificate; a PKCS#8 key). They are kept in
+    /// anonymous memory, never on disk, which needs Linux.
+    pub cert: Option<Either<String, Buffer>>,
+    pub key: Option<Either<String, Buffer>>,
+    /// Address to listen on, `0.0.0.0` by default.
+    pub host: Option<String>,
+    /// UDP port to listen on, 443 by default.
+    pub port: Option<u32>,
+    /// Network device to restrict the socket to (Linux only).
+    pub bind_device: Option<String>,
+    /// Log TLS alerts that fail handshakes to stderr (default `true`). They
+    /// are always reported as `tlsAlert` events.
+    pub log_tls_alerts: Option<bool>,
+    /// Caps each connection's sending rate, in bytes per second, whatever the
+    /// congestion controller allows. Enforced on Linux with `SO_TXTIME`,
+    /// which needs the `fq` qdisc on the egress interface.
+    pub max_pacing_rate_bps: Option<i64>,
+    /// Enables DATAGRAM frames; off unless given.
+    pub datagrams: Option<DatagramOptions>,
+    /// Transport parameters and congestion control settings.
+    pub config: Option<QuicConfig>,
+    /// Single-peer mode for point-to-point links: only datagrams from this
+    /// address (`"ip"` or `"ip:port"`) are processed, and unsupported
+    /// versions are dropped without version negotiation.
+    pub pinned_peer: Option<String>,
+    /// QUIC versions to accept, for interop testing. Long-header packets of
+    /// any other version are answered with a Version Negotiation packet
+    /// listing these in order. Reserved versions (`0x?a?a?a?a`) may be
+    /// listed to grease that packet; at least one must be a version quiche
+    /// supports. `[1]` (QUIC v1) by default.
+    pub versions: Option<Vec<u32>>,
+    /// Limits on Initial packets that start handshakes, as a first line of
+    /// defence against handshake floods. Unlimited by default.
+    pub initial_rate_limit: Option<InitialRateLimit>,
+    /// Validate client addresses with a stateless Retry round trip before
+    /// accepting a connection, so spoofed-source floods cannot create
+    /// connection state (default `false`).
+    pub retry: Option<bool>,
+    /// Key, at least 16 bytes, to encrypt the connection IDs the server picks
+    /// with, so on-path observers cannot correlate them with one another or
+    /// read anything they carry for this server. Unencrypted by default.
+    pub cid_key: Option<Buffer>,
+    /// Number, up to 65535, embedded in every connection ID the server
+    /// picks, so that a dispatcher in front of several servers sharing a
+    /// port can tell which one owns a connection. See `cidWorkerId()`.
+    pub worker_id: Option<u32>,
+    /// Directory to write a qlog trace (`server-<connId>.sqlog`) of every
+    /// connection into. Requires building with the `qlog` cargo feature.
+    pub qlog_dir: Option<String>,
+    /// Compression and rotation of the `qlogDir` traces.
+    pub qlog_output: Option<QlogOutput>,
+    /// File to append TLS secrets to in NSS key log format, so captures can
+    /// be decrypted in Wireshark. Defaults to `$SSLKEYLOGFILE` when set.
+    pub keylog_file: Option<String>,
+    /// PEM bundle of CA certificates that client certificates must chain to.
+    pub ca_file: Option<String>,
+    /// Directory of hashed CA certificates (as made by `openssl rehash`)
+    /// that client certificates may chain to.
+    pub ca_dir: Option<String>,
+    /// Ask clients for a certificate and fail the handshake if the one they
+    /// send does not verify (default `false`). Clients may still send none.
+    pub verify_client: Option<bool>,
+    /// Close connections whose client sent no certificate with the TLS
+    /// `certificate_required` alert. Implies `verifyClient`.
+    pub require_client_cert: Option<bool>,
+    /// Application protocols to accept, in order of preference; `["h3"]`
+    /// by default. The one a connection negotiates decides whether it is
+    /// served as HTTP/3 (see `http3`) or as raw streams, and is reported by
+    /// `alpn()` and on `handshakeDone`.
+    pub alpn: Option<Vec<String>>,
+    /// What to do with a client that offers none of `alpn`: `"close"`
+    /// (default) fails its handshake with the TLS `no_application_protocol`
+    /// alert, `"accept"` negotiates the client's first choice anyway and
+    /// serves it as raw streams. Either way an `alpnMismatch` event reports
+    /// the protocols it offered.
+    pub alpn_mismatch: Option<String>,
+    /// Further certificates, chosen by the server name (SNI) the client asks
+    /// for. Clients that ask for none of them, or send no SNI, get
+    /// `certPath`/`keyPath`.
+    pub certificates: Option<Vec<ServerCertificate>>,
+    /// Retransmissions during one handshake after which a
+    /// `handshakeRetransmits` event is emitted (default 3; 0 disables it).
+    pub handshake_retransmit_threshold: Option<u32>,
+    /// HTTP/3 settings keyed by ALPN. Connections that negotiate a listed
+    /// protocol speak HTTP/3 with its settings; `h3` does so with default
+    /// settings unless listed. Other protocols carry raw streams.
+    pub http3: Option<HashMap<String, Http3Settings>>,
+    /// Datagrams in a row on an established connection that none of its
+    /// packets could be decrypted from (duplicates aside), or packets for a
+    /// connection that closed in the last minute, after which
+    /// `martianAction` is taken for it
+    /// (default 10; 0 disables it). They are counted in `metrics()` either way.
+    pub martian_threshold: Option<u32>,
+    /// `"event"` (default) emits a `martianPackets` event. `"reset"` also
+    /// answers with a stateless reset, which drops a live connection whose
+    /// keys no longer match the peer's. Reset tokens only outlive the
+    /// connection while the server process does.
+    pub martian_action: Option<String>,
+    /// Log each such packet, and any other non-Initial packet for an unknown
+    /// connection, to stderr (default `false`).
+    pub log_martian_packets: Option<bool>,
+    /// Emit an `unhandledPacket` event with the header of every non-Initial
+    /// packet for an unknown connection, to watch scanning or misrouted
+    /// traffic (default `false`). At most 100 are emitted a second.
+    pub unhandled_packet_events: Option<bool>,
+    /// Close connections once they are this old, so that clients reconnect
+    /// and a load balancer can spread them over other servers again. They
+    /// wind down as under `drain()`, with up to 30 s to finish what they
+    /// have started. Unlimited by default.
+    pub max_connection_age_ms: Option<u32>,
+    /// Application error code that streams a raw connection's client opens
+    /// while it winds down (see `drain()`) are refused with, by
+    /// STOP_SENDING and RESET_STREAM (default 0). HTTP/3 requests are
+    /// refused with H3_REQUEST_REJECTED, as RFC 9114 requires.
+    pub drain_stream_error_code: Option<i64>,
+    /// Window, in microseconds, for which each connection holds back what
+    /// `streamSend()` queues, so that many tiny writes leave in a few full
+    /// packets rather than one each. 0 (default) sends every write at once.
+    /// See `setWriteCoalescing()`.
+    pub write_coalescing_us: Option<u32>,
+    /// Time how long the packet loop spends receiving, in the TLS handshake,
+    /// and sending, per connection (in `statusJson()`) and in total (in
+    /// `metrics()`), to tell where CPU goes when the loop saturates a core.
+    /// Handshaking connections count as `crypto` throughout. Off by default.
+    pub profile_phases: Option<bool>,
+    /// Session ticket key: 48 bytes, as from `crypto.randomBytes(48)`.
+    /// Servers sharing it accept each other's tickets, so resumption and
+    /// 0-RTT survive restarts and work behind a load balancer. Without it,
+    /// each process generates and rotates a key of its own. See
+    /// `setTicketKey()` for rotation.
+    pub ticket_key: Option<Buffer>,
+}
+
+/// A site served from the same port as the rest, passed to `addVirtualHost()`.
+#[napi(object, object_to_js = false)]
+pub struct VirtualHost {
+    /// Certificate and key for the host name: paths, or PEM or DER content.
+    pub cert: Either<String, Buffer>,
+    pub key: Either<String, Buffer>,
+    /// Receives the events of the host's connections, as the callback given
+    /// to the constructor does for the others.
+    pub handler: JsFunction,
+}
+
+/// A bound socket address, shaped like the result of Node's `server.address()`.
+#[napi(object)]
+pub struct SocketAddress {
+    pub address: String,
+    pub family: String,
+    pub port: u32,
+}
+
+impl From<SocketAddr> for SocketAddress {
+    fn from(addr: SocketAddr) -> Self {
+        let family = if addr.is_ipv4() { "IPv4" } else { "IPv6" };
+        SocketAddress { address: addr.ip().to_string(), family: family.to_string(), port: addr.port() as u32 }
+    }
+}
+
+/// Why a connection may not be sending, as returned by `blockedState()`.
+///
+/// quiche lets a stream take only as much data as the peer's flow control
+/// limits and the free part of the congestion window both allow, and tells
+/// just the smaller, so the two show up together as blocked streams.
+#[napi(object)]
+pub struct BlockedState {
+    /// The client has not proven it owns its address yet, so the server may
+    /// send it no more than three times what it has received (RFC 9000,
+    /// section 8.1).
+    pub amplification_limited: bool,
+    /// Every stream JS is writing to is blocked, which points at a limit on
+    /// the whole connection: the peer's MAX_DATA, or the congestion window.
+    pub connection_blocked: bool,
+    /// Streams JS is writing to that can take no more data for now: the
+    /// peer has not raised its limit for them, or the congestion window is
+    /// full.
+    pub blocked_streams: Vec<i64>,
+    /// Congestion window of the path in use, in bytes.
+    pub congestion_window: i64,
+    /// None of the limits above holds: the connection sends what it is
+    /// given, as fast as congestion control allows.
+    pub app_limited: bool,
+}
+
+/// A connection's open streams, as returned by `streamCounts()`. On HTTP/3
+/// connections they include its control and QPACK streams.
+#[napi(object)]
+pub struct StreamCounts {
+    /// Bidirectional and unidirectional streams the client opened.
+    pub peer_bidi: u32,
+    pub peer_uni: u32,
+    /// Streams the server opened by writing to them.
+    pub local_bidi: u32,
+    pub local_uni: u32,
+    /// Further bidirectional and unidirectional streams the server may open
+    /// before the client raises its limit with MAX_STREAMS.
+    pub bidi_left: i64,
+    pub uni_left: i64,
+}
+
+// How a running packet loop should wind down
+#[derive(Clone, Copy, PartialEq)]
+enum Shutdown {
+    // Send CONNECTION_CLOSE to every peer before exiting
+    Graceful,
+    // As Graceful, once the drain close() started is over
+    Flush,
+    // Exit straight away and leave peers to time out
+    Immediate,
+}
+
+// State shared between the JS thread and the packet loop. The loop polls the
+// steering flags at least every POLL_INTERVAL, and holds `clients` only while
+// handling a packet so JS writes can interleave with receives.
+struct Shared {
+    socket: Box<dyn Transport + Send + Sync>,
+    clients: Mutex<ClientMap>,
+    accepting: AtomicBool,
+    shutdown: Mutex<Option<Shutdown>>,
+    metrics: Arc<Metrics>,
+    log_tls_alerts: bool,
+    // Set once JS calls incoming()
+    incoming: Mutex<Option<Arc<AsyncQueue<IncomingConnection>>>>,
+    pinned_peer: Option<PinnedPeer>,
+    // Versions listed in Version Negotiation packets; the ones quiche
+    // supports are accepted
+    versions: Vec<u32>,
+    initial_limiter: Option<Mutex<InitialLimiter>>,
+    // Mints and checks the tokens of Retry packets, which are sent to every
+    // new client when `retry` is set and to those onInitial picks otherwise
+    retry: RetryTokens,
+    retry_all: bool,
+    // Set by onInitial(), with the Initials awaiting its answer
+    initial_hook: Mutex<Option<InitialHook>>,
+    held_initials: Mutex<HeldInitials>,
+    // Picks the connection IDs handed out, in Retry packets or on accepting
+    cids: ConnectionIds,
+    // Set by onSchedule()
+    scheduler: Mutex<Option<Scheduler>>,
+    qlog_dir: Option<QlogDir>,
+    // Set by onAccept()
+    acceptor: Mutex<Option<Acceptor>>,
+    keylog: Option<File>,
+    // Set by drain() until every connection is closing
+    drain: Mutex<Option<Drain>>,
+    require_client_cert: bool,
+    // HTTP/3 configuration for each ALPN served as HTTP/3
+    h3_configs: H3Configs,
+    // Whether the server's `datagrams` enable DATAGRAM frames, the queue
+    // lengths to enable them with, and the ALPNs whose http3 settings say
+    // otherwise
+    datagrams: bool,
+    datagram_queues: (usize, usize),
+    alpn_datagrams: HashMap<Vec<u8>, bool>,
+    handshake_retransmit_threshold: usize,
+    // Configurations from reloadCertificates() not yet picked up by the loop,
+    // keyed by the server name they replace (None for the default)
+    reloads: Mutex<Vec<(Option<String>, Config)>>,
+    // Virtual hosts from addVirtualHost() not yet picked up by the loop
+    new_hosts: Mutex<Vec<(String, Config, Handler)>>,
+    // Handlers of connections to virtual hosts, by hex connection ID
+    routes: Mutex<HashMap<String, Handler>>,
+    // Reset tokens and recently closed connections
+    martians: Mutex<Martians>,
+    martian_threshold: usize,
+    martian_action: MartianAction,
+    log_martian_packets: bool,
+    unhandled_packet_events: bool,
+    max_connection_age: Option<Duration>,
+    drain_stream_error_code: u64,
+    // Coalescing window new connections start with
+    write_coalescing: Option<Duration>,
+    profile_phases: bool,
+    datagram_policy: DatagramPolicy,
+    // Protocols every configuration offers, and what to do for clients that
+    // offer none of them
+    alpn: Vec<Vec<u8>>,
+    alpn_mismatch: AlpnMismatch,
+    // Key from setTicketKey() not yet picked up by the loop
+    ticket_key: Mutex<Option<Vec<u8>>>,
+    // Bytes of ClientHellos the loop is reassembling, as of its last pass
+    hello_bytes: AtomicUsize,
+}
+
+type DrainDeferred = JsDeferred<(), Box<dyn FnOnce(Env) -> Result<()> + Send>>;
+
+struct Drain {
+    // Connections still serving requests are closed regardless at this point
+    deadline: Instant,
+    // Promises returned by drain()
+    waiters: Vec<DrainDeferred>,
+}
+
+// A JS admission check installed with onAccept()
+struct Acceptor {
+    callback: ThreadsafeFunction<IncomingConnection, ErrorStrategy::Fatal>,
+    timeout: Duration,
+}
+
+// A JS Initial check installed with onInitial()
+struct InitialHook {
+    callback: ThreadsafeFunction<InitialPacket, ErrorStrategy::Fatal>,
+    timeout: Duration,
+}
+
+// A JS stream scheduler installed with onSchedule()
+struct Scheduler {
+    callback: ThreadsafeFunction<ScheduleRound, ErrorStrategy::Fatal>,
+    interval: Duration,
+    last_round: Instant,
+}
+
+// One connection's writable streams, offered to the scheduler
+struct ScheduleRound {
+    conn_id: String,
+    stream_ids: Vec<i64>,
+}
+
+// The only peer a single-peer server talks to
+#[derive(Clone, Copy)]
+enum PinnedPeer {
+    Addr(SocketAddr),
+    // Any port on this address
+    Ip(IpAddr),
+}
+
+impl PinnedPeer {
+    fn parse(peer: &str) -> Option<PinnedPeer> {
+        peer.parse()
+            .map(PinnedPeer::Addr)
+            .or_else(|_| peer.parse().map(PinnedPeer::Ip))
+            .ok()
+    }
+
+    fn matches(&self, from: SocketAddr) -> bool {
+        match *self {
+            PinnedPeer::Addr(addr) => addr == from,
+            PinnedPeer::Ip(ip) => ip == from.ip(),
+        }
+    }
+}
+
+// quiche configurations for the default certificate and each entry of
+// `certificates`
+struct ServerConfigs {
+    default: Config,
+    // Keyed by lowercased server name, which may start with `*.`
+    by_name: Vec<(String, Config)>,
+    // Event handlers of the names among `by_name` that are virtual hosts
+    handlers: HashMap<String, Handler>,
+}
+
+// Where a virtual host's connections send their events
+type Handler = Arc<dyn EmitEvent + Send + Sync>;
+
+impl ServerConfigs {
+    // Swaps in a configuration from reloadCertificates() or updateConfig()
+    fn replace(&mut self, server_name: Option<String>, config: Config) {
+        match server_name {
+            None => self.default = config,
+            Some(name) => {
+                if let Some(entry) = self.by_name.iter_mut().find(|(n, _)| *n == name) {
+                    entry.1 = config;
+                }
+            }
+        }
+    }
+
+    // Applies a key from setTicketKey() to every certificate's configuration
+    fn set_ticket_key(&mut self, key: &[u8]) -> quiche::Result<()> {
+        self.default.set_ticket_key(key)?;
+        for (_, config) in &mut self.by_name {
+            config.set_ticket_key(key)?;
+        }
+        Ok(())
+    }
+
+    // Serves a virtual host from addVirtualHost()
+    fn add_host(&mut self, server_name: String, config: Config, handler: Handler) {
+        self.handlers.insert(server_name.clone(), handler);
+        self.by_name.push((server_name, config));
+    }
+
+    // The entry whose certificate covers the name the client asked for,
+    // preferring an exact match to a wildcard
+    fn position(&self, server_name: Option<&str>) -> Option<usize> {
+        let wildcard = server_name.and_then(|name| name.split_once('.')).map(|(_, parent)| format!("*.{}", parent));
+        self.by_name
+            .iter()
+            .position(|(name, _)| Some(name.as_str()) == server_name)
+            .or_else(|| self.by_name.iter().position(|(name, _)| Some(name) == wildcard.as_ref()))
+    }
+
+    // The handler of the virtual host a client asked for, if it is one
+    fn handler(&self, server_name: Option<&str>) -> Option<Handler> {
+        let (name, _) = &self.by_name[self.position(server_name)?];
+        self.handlers.get(name).cloned()
+    }
+
+    // Picks the configuration for the name the client asked for
+    fn select(&mut self, server_name: Option<&str>) -> &mut Config {
+        match self.position(server_name) {
+            Some(i) => &mut self.by_name[i].1,
+            None => &mut self.default,
+        }
+    }
+}
+
+// Checks a session ticket key: a 16-byte name, then 16-byte HMAC and AES keys
+fn ticket_key(key: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != TICKET_KEY_LEN {
+        return Err(napi::Error::from_reason(format!(
+            "Session ticket keys are {} bytes, not {}",
+            TICKET_KEY_LEN,
+            key.len()
+        )));
+    }
+    Ok(key.to_vec())
+}
+
+// Percent-encodes an ALPN for use as an Alt-Svc protocol-id (RFC 7838)
+fn alt_svc_token(alpn: &str) -> String {
+    alpn.bytes()
+        .map(|b| match b {
+            b if b.is_ascii_alphanumeric() || b"!#$&'*+-.^_`|~".contains(&b) => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// HTTP/3 configuration and request limits, by ALPN
+type H3Configs = HashMap<Vec<u8>, (quiche::h3::Config, RequestPolicy)>;
+
+// Builds the HTTP/3 configuration of each ALPN that is served as HTTP/3
+fn h3_configs(alpn: &[String], http3: Option<&HashMap<String, Http3Settings>>) -> Result<H3Configs> {
+    let mut configs = HashMap::new();
+    if alpn.iter().any(|proto| proto == "h3") {
+        configs.insert(b"h3".to_vec(), (h3_config(&Http3Settings::default())?, RequestPolicy::default()));
+    }
+
+    for (proto, settings) in http3.into_iter().flatten() {
+        if !alpn.contains(proto) {
+            return Err(napi::Error::from_reason(format!("http3 configures {:?}, which is not in alpn", proto)));
+        }
+        configs.insert(proto.as_bytes().to_vec(), (h3_config(settings)?, request_policy(settings)?));
+    }
+    Ok(configs)
+}
+
+/// Counters maintained by the packet loop, as returned by `metrics()`.
+#[napi(object)]
+pub struct ServerMetrics {
+    /// Datagrams dropped because they did not fit the receive buffer.
+    pub oversized_datagrams: i64,
+    /// Datagrams dropped because no QUIC header could be parsed from them.
+    pub malformed_datagrams: i64,
+    /// Datagrams dropped in single-peer mode because they came from elsewhere.
+    pub foreign_datagrams: i64,
+    /// Outgoing datagrams dropped by the `drop-when-congested` policy.
+    pub congestion_dropped_datagrams: i64,
+    /// Initial packets dropped by `initialRateLimit`.
+    pub rate_limited_initials: i64,
+    /// Retry packets sent to unvalidated clients.
+    pub retries_sent: i64,
+    /// Initial packets dropped on the word of `onInitial()`.
+    pub dropped_initials: i64,
+    /// Initial packets dropped because their retry token was invalid or expired.
+    pub invalid_tokens: i64,
+    /// Packets retransmitted during handshakes, across all connections.
+    pub handshake_retransmits: i64,
+    /// Datagrams on established connections that held no packet quiche
+    /// could decrypt, other than replays of packets it already processed.
+    pub undecryptable_datagrams: i64,
+    /// Packets other than Initials addressed to no live connection.
+    pub martian_packets: i64,
+    /// Stateless resets sent under `martianAction: "reset"`.
+    pub stateless_resets: i64,
+    /// HTTP/3 requests answered 431 for exceeding `maxRequestHeaders` or
+    /// `maxRequestHeaderBytes`.
+    pub oversized_request_heads: i64,
+    /// HTTP/3 requests answered 413 for exceeding `maxRequestBodyBytes`, and
+    /// 408 for stalling past `requestBodyTimeoutMs`.
+    pub oversized_request_bodies: i64,
+    pub request_body_timeouts: i64,
+    /// HTTP/3 responses by status class and method, for request rates,
+    /// error rates and durations without timing every handler.
+    pub responses: Vec<ResponseMetrics>,
+    /// Nanoseconds the packet loop spent receiving on established
+    /// connections, handshaking, and sending on established connections,
+    /// under `profilePhases`.
+    pub recv_ns: i64,
+    pub crypto_ns: i64,
+    pub send_ns: i64,
+}
+
+impl ToJson for ServerMetrics {
+    fn write_json(&self, out: &mut String) {
+        JsonObject::new()
+            .field("oversizedDatagrams", self.oversized_datagrams)
+            .field("malformedDatagrams", self.malformed_datagrams)
+            .field("foreignDatagrams", self.foreign_datagrams)
+            .field("congestionDroppedDatagrams", self.congestion_dropped_datagrams)
+            .field("rateLimitedInitials", self.rate_limited_initials)
+            .field("retriesSent", self.retries_sent)
+            .field("droppedInitials", self.dropped_initials)
+            .field("invalidTokens", self.invalid_tokens)
+            .field("handshakeRetransmits", self.handshake_retransmits)
+            .field("undecryptableDatagrams", self.undecryptable_datagrams)
+            .field("martianPackets", self.martian_packets)
+            .field("statelessResets", self.stateless_resets)
+            .field("oversizedRequestHeads", self.oversized_request_heads)
+            .field("oversizedRequestBodies", self.oversized_request_bodies)
+            .field("requestBodyTimeouts", self.request_body_timeouts)
+            .field("responses", &self.responses)
+            .field("recvNs", self.recv_ns)
+            .field("cryptoNs", self.crypto_ns)
+            .field("sendNs", self.send_ns)
+            .write_json(out)
+    }
+}
+
+/// Native memory a running server holds, in bytes, as returned by
+/// `memoryUsage()`. None of it shows in Node's heap statistics. quiche
+/// reports no sizes for the stream and packet buffers inside a connection,
+/// so `connections` covers each connection's fixed-size state only.
+#[napi(object)]
+pub struct MemoryUsage {
+    pub connection_count: u32,
+    /// Per-connection state, with the stream ID sets kept alongside it.
+    pub connections: i64,
+    /// Framed messages not yet accepted by their streams, and data read for
+    /// `pipeToFile()` not yet handed to its writer.
+    pub pending_writes: i64,
+    /// Framed messages not yet whole, ClientHellos spread over several
+    /// Initials not yet complete, and Initials awaiting `onInitial()`.
+    pub reassembly: i64,
+    /// Stateless reset records of closed connections, per-address
+    /// `initialRateLimit` buckets, and the packet tags kept to spot replays.
+    pub token_caches: i64,
+    /// The sum of the above.
+    pub total: i64,
+}
+
+// Lives on the QuicServer rather than the running loop so counts survive close()
+#[derive(Default)]
+struct Metrics {
+    oversized_datagrams: AtomicU64,
+    malformed_datagrams: AtomicU64,
+    foreign_datagrams: AtomicU64,
+    congestion_dropped_datagrams: AtomicU64,
+    rate_limited_initials: AtomicU64,
+    retries_sent: AtomicU64,
+    dropped_initials: AtomicU64,
+    invalid_tokens: AtomicU64,
+    handshake_retransmits: AtomicU64,
+    undecryptable_datagrams: AtomicU64,
+    martian_packets: AtomicU64,
+    stateless_resets: AtomicU64,
+    h3: H3Metrics,
+    phases: PhaseTimes,
+}
+
+impl Metrics {
+    fn snapshot(&self) -> ServerMetrics {
+        ServerMetrics {
+            oversized_datagrams: self.oversized_datagrams.load(Ordering::Relaxed) as i64,
+            malformed_datagrams: self.malformed_datagrams.load(Ordering::Relaxed) as i64,
+            foreign_datagrams: self.foreign_datagrams.load(Ordering::Relaxed) as i64,
+            congestion_dropped_datagrams: self.congestion_dropped_datagrams.load(Ordering::Relaxed) as i64,
+            rate_limited_initials: self.rate_limited_initials.load(Ordering::Relaxed) as i64,
+            retries_sent: self.retries_sent.load(Ordering::Relaxed) as i64,
+            dropped_initials: self.dropped_initials.load(Ordering::Relaxed) as i64,
+            invalid_tokens: self.invalid_tokens.load(Ordering::Relaxed) as i64,
+            handshake_retransmits: self.handshake_retransmits.load(Ordering::Relaxed) as i64,
+            undecryptable_datagrams: self.undecryptable_datagrams.load(Ordering::Relaxed) as i64,
+            martian_packets: self.martian_packets.load(Ordering::Relaxed) as i64,
+            stateless_resets: self.stateless_resets.load(Ordering::Relaxed) as i64,
+            oversized_request_heads: self.h3.oversized_request_heads.load(Ordering::Relaxed) as i64,
+            oversized_request_bodies: self.h3.oversized_request_bodies.load(Ordering::Relaxed) as i64,
+            request_body_timeouts: self.h3.request_body_timeouts.load(Ordering::Relaxed) as i64,
+            responses: self.h3.responses(),
+            recv_ns: self.phases.nanos(Phase::Recv),
+            crypto_ns: self.phases.nanos(Phase::Crypto),
+            send_ns: self.phases.nanos(Phase::Send),
+        }
+    }
+}
+
+struct Running {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    thread: JoinHandle<()>,
+}
+
+enum State {
+    Idle,
+    Running(Running),
+    Closed,
+}
+
+/// A QUIC server whose packet loop runs on a background thread.
+///
+/// Events are delivered to the callback given to the constructor. The server
+/// only keeps the Node process alive between `start()` and `close()`.
+#[napi]
+pub struct QuicServer {
+    options: QuicServerOptions,
+    events: EventCallback,
+    metrics: Arc<Metrics>,
+    state: State,
+    // Current session ticket key, from ticketKey or setTicketKey()
+    ticket_key: Option<Vec<u8>>,
+    // From addVirtualHost(), served again whenever the server starts
+    hosts: Vec<(String, VirtualHostFiles, Handler)>,
+    // From reloadCertificates(), by server name, for updateConfig() to keep
+    reloaded: HashMap<Option<String>, VirtualHostFiles>,
+}
+
+// A virtual host's certificate and key, kept to build its configuration
+struct VirtualHostFiles {
+    cert: Either<String, Buffer>,
+    key: Either<String, Buffer>,
+}
+
+impl VirtualHostFiles {
+    // Strings are paths, Buffers content
+    fn identity(&self) -> Result<Option<(PemFile, PemFile)>> {
+        let (cert_path, cert) = match &self.cert {
+            Either::A(path) => (Some(path.as_str()), None),
+            Either::B(_) => (None, Some(&self.cert)),
+        };
+        let (key_path, key) = match &self.key {
+            Either::A(path) => (Some(path.as_str()), None),
+            Either::B(_) => (None, Some(&self.key)),
+        };
+        identity(cert_path, cert, key_path, key)
+    }
+}
+
+// Hands each event to the handler of its connection's virtual host, or to
+// the server's callback
+struct Router<'a> {
+    routes: &'a Mutex<HashMap<String, Handler>>,
+    fallback: &'a dyn EmitEvent,
+}
+
+impl EmitEvent for Router<'_> {
+    fn emit(&self, event: QuicEvent) {
+        let handler = event.conn_id.as_ref().and_then(|id| self.routes.lock().unwrap().get(id).cloned());
+        match handler {
+            Some(handler) => handler.emit(event),
+            None => self.fallback.emit(event),
+        }
+    }
+}
+
+#[napi]
+impl QuicServer {
+    #[napi(constructor)]
+    pub fn new(env: Env, options: QuicServerOptions, callback: JsFunction) -> napi::Result<Self> {
+        let mut events = event_callback(callback)?;
+        events.unref(&env)?;
+
+        let ticket_key = options.ticket_key.as_ref().map(|key| ticket_key(key)).transpose()?;
+        Ok(QuicServer {
+            options,
+            events,
+            metrics: Arc::default(),
+            state: State::Idle,
+            ticket_key,
+            hosts: Vec::new(),
+            reloaded: HashMap::new(),
+        })
+    }
+
+    /// Binds the socket and starts the packet loop. On a stopped server this
+    /// resumes accepting new connections.
+    #[napi]
+    pub fn start(&mut self, env: Env) -> napi::Result<()> {
+        match &self.state {
+            State::Idle => (),
+            State::Running(running) => {
+                running.shared.accepting.store(true, Ordering::SeqCst);
+                return Ok(());
+            }
+            State::Closed => return Err(napi::Error::from_reason("QuicServer has been closed")),
+        }
+
+        let host = self.options.host.as_deref().unwrap_or("0.0.0.0");
+        let port = u16::try_from(self.options.port.unwrap_or(443))
+            .map_err(|_| napi::Error::from_reason("port must be between 0 and 65535"))?;
+
+        let socket = transport::bind_udp((host, port), self.options.bind_device.as_deref())
+            .map_err(io_err_to_napi)?;
+        let socket: Box<dyn Transport + Send + Sync> = if self.options.max_pacing_rate_bps.is_some() {
+            transport::paced(socket)
+        } else {
+            Box::new(socket)
+        };
+        let (mut configs, shared) = prepare(&self.options, self.ticket_key.as_deref(), self.metrics.clone(), socket)?;
+        for (server_name, files, handler) in &self.hosts {
+            let mut config = self.build_config(files.identity()?)?;
+            if shared.keylog.is_some() {
+                config.log_keys();
+            }
+            configs.add_host(server_name.clone(), config, handler.clone());
+        }
+
+        let running = spawn(configs, self.events.clone(), shared)?;
+        self.events.refer(&env)?;
+        self.state = State::Running(running);
+        Ok(())
+    }
+
+    /// Stops accepting new connections. Established connections keep being
+    /// served until the server is closed.
+    #[napi]
+    pub fn stop(&self) {
+        if let State::Running(running) = &self.state {
+            running.shared.accepting.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Winds the server down for a restart. New connections are refused,
+    /// HTTP/3 clients are sent GOAWAY so they open no further requests, and
+    /// each connection is closed once its in-flight requests have been
+    /// answered, or when `timeoutMs` (default 30000) runs out. Raw
+    /// (non-HTTP/3) connections are closed once the streams open when the
+    /// drain began are done; streams their clients open afterwards are
+    /// refused with `drainStreamErrorCode`. The promise resolves once every
+    /// connection is closing; `close()` then releases the socket.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn drain(&self, env: Env, timeout_ms: Option<u32>) -> Result<JsObject> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        running.shared.accepting.store(false, Ordering::SeqCst);
+
+        let (deferred, promise) = env.create_deferred()?;
+        let deadline = Instant::now() + timeout_ms.map_or(DRAIN_TIMEOUT, |ms| Duration::from_millis(ms.into()));
+        let mut drain = running.shared.drain.lock().unwrap();
+        let drain = drain.get_or_insert_with(|| Drain { deadline, waiters: Vec::new() });
+        drain.deadline = drain.deadline.min(deadline);
+        drain.waiters.push(deferred);
+        Ok(promise)
+    }
+
+    /// Shuts the server down and releases its socket. With `gracefully`, every
+    /// connection is sent a CONNECTION_CLOSE first; otherwise peers are left to
+    /// time out. A graceful close with `timeoutMs` first winds connections
+    /// down as `drain()` does, so that data already written is acknowledged
+    /// before they are closed; the call blocks for up to `timeoutMs`. A
+    /// closed server cannot be started again.
+    #[napi]
+    pub fn close(&mut self, env: Env, gracefully: bool, timeout_ms: Option<u32>) -> napi::Result<()> {
+        let running = match std::mem::replace(&mut self.state, State::Closed) {
+            State::Running(running) => running,
+            _ => return Ok(()),
+        };
+
+        let mode = match (gracefully, timeout_ms) {
+            (true, Some(ms)) => {
+                running.shared.accepting.store(false, Ordering::SeqCst);
+                let deadline = Instant::now() + Duration::from_millis(ms.into());
+                let mut drain = running.shared.drain.lock().unwrap();
+                let drain = drain.get_or_insert_with(|| Drain { deadline, waiters: Vec::new() });
+                drain.deadline = drain.deadline.min(deadline);
+                Shutdown::Flush
+            }
+            (true, None) => Shutdown::Graceful,
+            (false, _) => Shutdown::Immediate,
+        };
+        *running.shared.shutdown.lock().unwrap() = Some(mode);
+
+        running
+            .thread
+            .join()
+            .map_err(|_| napi::Error::from_reason("QUIC server thread panicked"))?;
+
+        if let Some(incoming) = running.shared.incoming.lock().unwrap().as_ref() {
+            incoming.close();
+        }
+        running.shared.scheduler.lock().unwrap().take();
+        running.shared.acceptor.lock().unwrap().take();
+        running.shared.initial_hook.lock().unwrap().take();
+        if let Some(drain) = running.shared.drain.lock().unwrap().take() {
+            drain.finish();
+        }
+        self.events.unref(&env)
+    }
+
+    /// Returns an async iterator over connections accepted from now on, for
+    /// use with `for await`. Iteration ends when the server is closed.
+    #[napi(ts_return_type = "AsyncIterableIterator<IncomingConnection>")]
+    pub fn incoming(&self, env: Env) -> Result<JsObject> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let queue = running.shared.incoming.lock().unwrap().get_or_insert_with(AsyncQueue::new).clone();
+        connection_iterator(env, queue)
+    }
+
+    /// Returns an async iterator over the IDs of streams the peer opens from
+    /// now on. Only raw (non-HTTP/3) connections announce streams; iteration
+    /// ends when the connection closes.
+    #[napi(ts_return_type = "AsyncIterableIterator<number>")]
+    pub fn incoming_streams(&self, env: Env, conn_id: String) -> Result<JsObject> {
+        let queue = self.with_client(&conn_id, |client| {
+            Ok(client.incoming_streams.get_or_insert_with(AsyncQueue::new).clone())
+        })?;
+        stream_iterator(env, queue)
+    }
+
+    /// Installs an admission check. `callback` is called with each new
+    /// connection and may return a Promise; until it fulfils, no stream,
+    /// request or datagram events are delivered for the connection. If it
+    /// rejects, fulfils with `false`, or takes longer than `timeoutMs`
+    /// (default 10000), the connection is closed with CONNECTION_REFUSED.
+    /// Answering with an `AcceptDecision` instead of `true` sets the
+    /// connection's own idle timeout. Pass `null` to accept every
+    /// connection again.
+    #[napi(
+        ts_args_type = "callback: ((connection: IncomingConnection) => boolean | AcceptDecision | Promise<boolean | AcceptDecision | void> | void) | null, timeoutMs?: number"
+    )]
+    pub fn on_accept(&self, env: Env, callback: Option<JsFunction>, timeout_ms: Option<u32>) -> Result<()> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let acceptor = match callback {
+            Some(callback) => {
+                let mut callback: ThreadsafeFunction<IncomingConnection, ErrorStrategy::Fatal> =
+                    guard(&env, callback)?.create_threadsafe_function(
+                        0,
+                        |ctx: ThreadSafeCallContext<IncomingConnection>| Ok(vec![ctx.value]),
+                    )?;
+                callback.unref(&env)?;
+
+                let timeout = timeout_ms.map_or(ACCEPT_TIMEOUT, |ms| Duration::from_millis(ms.into()));
+                Some(Acceptor { callback, timeout })
+            }
+            None => None,
+        };
+
+        *running.shared.acceptor.lock().unwrap() = acceptor;
+        Ok(())
+    }
+
+    /// Installs a check on connection attempts, run before any handshake
+    /// state exists for them. `callback` is called with each Initial that
+    /// would open a connection and answers `"accept"`, `"retry"` to first
+    /// validate the client's address with a Retry round trip, or `"drop"`,
+    /// or a Promise of one. The Initial is held meanwhile. Other answers,
+    /// rejections, and taking longer than `timeoutMs` (default 1000) leave
+    /// the decision to the `retry` option. Initials with a token are
+    /// accepted only if it is valid. Pass `null` to remove it.
+    #[napi(
+        ts_args_type = "callback: ((initial: InitialPacket) => 'accept' | 'retry' | 'drop' | Promise<'accept' | 'retry' | 'drop'>) | null, timeoutMs?: number"
+    )]
+    pub fn on_initial(&self, env: Env, callback: Option<JsFunction>, timeout_ms: Option<u32>) -> Result<()> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let hook = match callback {
+            Some(callback) => {
+                let mut callback: ThreadsafeFunction<InitialPacket, ErrorStrategy::Fatal> =
+                    guard(&env, callback)?.create_threadsafe_function(
+                        0,
+                        |ctx: ThreadSafeCallContext<InitialPacket>| Ok(vec![ctx.value]),
+                    )?;
+                callback.unref(&env)?;
+
+                let timeout = timeout_ms.map_or(INITIAL_TIMEOUT, |ms| Duration::from_millis(ms.into()));
+                Some(InitialHook { callback, timeout })
+            }
+            None => None,
+        };
+
+        *running.shared.initial_hook.lock().unwrap() = hook;
+        Ok(())
+    }
+
+    /// Installs a stream scheduler. Every `intervalMs` (default 100, rounded
+    /// up to the packet loop's 50 ms tick), `callback` is called with the
+    /// writable streams of each established connection and returns them in
+    /// the order they should get capacity. Earlier streams are given more
+    /// urgent priorities, with the eighth and later sharing the least urgent
+    /// one; streams left out keep their priority. Pass `null` to remove it.
+    #[napi(
+        ts_args_type = "callback: ((connId: string, streamIds: number[]) => number[] | undefined) | null, intervalMs?: number"
+    )]
+    pub fn on_schedule(&self, env: Env, callback: Option<JsFunction>, interval_ms: Option<u32>) -> Result<()> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let scheduler = match callback {
+            Some(callback) => {
+                let mut callback: ThreadsafeFunction<ScheduleRound, ErrorStrategy::Fatal> =
+                    guard(&env, callback)?.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<ScheduleRound>| {
+                        let mut stream_ids = ctx.env.create_array_with_length(ctx.value.stream_ids.len())?;
+                        for (i, &id) in ctx.value.stream_ids.iter().enumerate() {
+                            stream_ids.set_element(i as u32, ctx.env.create_int64(id)?)?;
+                        }
+                        let conn_id = ctx.env.create_string(&ctx.value.conn_id)?;
+                        Ok(vec![conn_id.into_unknown(), stream_ids.into_unknown()])
+                    })?;
+                // Scheduling alone should not keep the process alive
+                callback.unref(&env)?;
+
+                let interval = interval_ms.map_or(SCHEDULE_INTERVAL, |ms| Duration::from_millis(ms.into()));
+                Some(Scheduler { callback, interval, last_round: Instant::now() })
+            }
+            None => None,
+        };
+
+        *running.shared.scheduler.lock().unwrap() = scheduler;
+        Ok(())
+    }
+
+    #[napi]
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Sums up the native memory the server holds, which Node's heap
+    /// statistics miss, so that leaks in it can be seen.
+    #[napi]
+    pub fn memory_usage(&self) -> Result<MemoryUsage> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+        Ok(memory_usage(&running.shared))
+    }
+
+    /// A JSON document describing the server, for an admin endpoint or a
+    /// dump to disk: `listener` holds its state, address and configuration,
+    /// `connections` a summary of each connection (state, ALPN, RTT,
+    /// congestion window, packet, byte and stream counts), and `metrics`
+    /// what `metrics()` returns.
+    #[napi]
+    pub fn status_json(&self) -> String {
+        let options = &self.options;
+        let running = match &self.state {
+            State::Running(running) => Some(running),
+            _ => None,
+        };
+        let state = match self.state {
+            State::Idle => "idle",
+            State::Running(_) => "running",
+            State::Closed => "closed",
+        };
+
+        let mut http3: Vec<&String> = options.http3.iter().flat_map(|http3| http3.keys()).collect();
+        http3.sort();
+        let hosts: Vec<&String> = self.hosts.iter().map(|(name, _, _)| name).collect();
+        let mut listener = JsonObject::new()
+            .field("state", state)
+            .field("address", running.map(|running| running.local_addr.ip().to_string()))
+            .field("port", running.map(|running| running.local_addr.port() as u32))
+            .field("bindDevice", options.bind_device.as_deref())
+            .field("alpn", options.alpn.clone().unwrap_or_else(|| vec!["h3".to_string()]))
+            .field("http3", http3)
+            .field("alpnMismatch", options.alpn_mismatch.as_deref().unwrap_or("close"))
+            .field("virtualHosts", hosts)
+            .field("pinnedPeer", options.pinned_peer.as_deref())
+            .field("versions", options.versions.clone().unwrap_or_else(|| vec![QUIC_V1]))
+            .field("retry", options.retry.unwrap_or(false))
+            .field("initialRateLimit", options.initial_rate_limit.is_some())
+            .field("requireClientCert", options.require_client_cert.unwrap_or(false))
+            .field("martianAction", options.martian_action.as_deref().unwrap_or("event"))
+            .field("maxConnectionAgeMs", options.max_connection_age_ms)
+            .field("writeCoalescingUs", options.write_coalescing_us)
+            .field("qlogDir", options.qlog_dir.as_deref());
+
+        let mut connections = Vec::new();
+        if let Some(running) = running {
+            let shared = &running.shared;
+            listener = listener
+                .field("accepting", shared.accepting.load(Ordering::Relaxed))
+                .field("draining", shared.drain.lock().unwrap().is_some());
+            let mut clients = shared.clients.lock().unwrap();
+            connections.extend(clients.values_mut().map(Client::status));
+        }
+
+        JsonObject::new()
+            .field("listener", listener)
+            .field("connections", connections)
+            .field("metrics", self.metrics.snapshot())
+            .finish()
+    }
+
+    /// Queues `data` on a stream of an accepted connection and returns how many
+    /// bytes were accepted, which is less than `data.length` when flow control
+    /// is exhausted. Once a stream finished with `fin` has been acknowledged
+    /// by the peer, and what it sent on the stream has been read, a
+    /// `streamFinAcked` event says its data is no longer needed.
+    #[napi]
+    pub fn stream_send(&self, conn_id: String, stream_id: i64, data: Buffer, fin: bool) -> Result<u32> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| client.stream_send(stream_id, &data, fin))
+    }
+
+    /// Sets how long, in microseconds, a connection holds back what
+    /// `streamSend()` queues before sending it, batching tiny writes into
+    /// fewer packets at the cost of that much latency; 0 turns it off. Any
+    /// other call on the connection sends what is held at once. The packet
+    /// loop wakes at least once a window while one is set, and no more
+    /// often than every millisecond, so shorter windows act as one.
+    #[napi]
+    pub fn set_write_coalescing(&self, conn_id: String, window_us: u32) -> Result<()> {
+        self.with_client(&conn_id, |client| {
+            client.coalescing = coalescing_window(Some(window_us));
+            Ok(())
+        })
+    }
+
+    /// Hashes what a stream delivers in `data` events from now on, request
+    /// bodies included, with `sha256`, `sha384` or `sha512`. The event that
+    /// carries the stream's `fin` adds the hex-encoded `digest`, so a file
+    /// transfer can be checked without hashing it in JavaScript.
+    #[napi(ts_args_type = "connId: string, streamId: number, algorithm: 'sha256' | 'sha384' | 'sha512'")]
+    pub fn set_stream_digest(&self, conn_id: String, stream_id: i64, algorithm: String) -> Result<()> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| client.digests.start(stream_id, &algorithm))
+    }
+
+    /// Keeps `context` with a stream, to be handed back as the `context` of
+    /// every event about it, so that routing state needs no map keyed by
+    /// connection and stream. It is let go of after the stream's last
+    /// event: `streamFinAcked`, `streamReset`, `requestRejected`, the `fin`
+    /// of a unidirectional stream, or the connection's `closed`. Pass `null`
+    /// to let go of it sooner.
+    #[napi(ts_args_type = "connId: string, streamId: number, context: object | null")]
+    pub fn set_stream_context(
+        &self,
+        env: Env,
+        conn_id: String,
+        stream_id: i64,
+        context: Option<JsObject>,
+    ) -> Result<()> {
+        to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |_| Ok(()))?;
+        context::set(env, &conn_id, stream_id, context)
+    }
+
+    /// Carries whole messages on a raw stream from now on: `ndjson` for one
+    /// JSON value per line, or `length` for messages preceded by their
+    /// length as a 4-byte big-endian integer. Each message received arrives
+    /// as a `message` event, parsed into `value` for `ndjson`, and the end of
+    /// the stream as an empty `data` event with `fin`. A message longer than
+    /// `maxMessageBytes` (default 16 MiB), or one cut short by FIN, fails
+    /// the stream with an `error` event and stops reading it.
+    #[napi(ts_args_type = "connId: string, streamId: number, framing: 'ndjson' | 'length', maxMessageBytes?: number")]
+    pub fn set_message_framing(
+        &self,
+        conn_id: String,
+        stream_id: i64,
+        framing: String,
+        max_message_bytes: Option<u32>,
+    ) -> Result<()> {
+        let stream_id = to_stream_id(stream_id)?;
+        let framing = Framing::parse(&framing)?;
+        self.with_client(&conn_id, |client| {
+            if client.h3.is_some() {
+                return Err(napi::Error::from_reason("Message framing is only available on raw QUIC connections"));
+            }
+            client.framers.set(stream_id, framing, max_message_bytes)
+        })
+    }
+
+    /// Sends one message on a stream given `setMessageFraming()`: a Buffer
+    /// as is, a string as UTF-8, or anything else as its `JSON.stringify()`
+    /// text. Messages the stream has no room for yet are queued natively and
+    /// sent in order as flow control allows; `fin` finishes the stream after
+    /// this one. Returns how many bytes are still queued on the stream.
+    #[napi(ts_args_type = "connId: string, streamId: number, message: unknown, fin?: boolean")]
+    pub fn send_message(
+        &self,
+        env: Env,
+        conn_id: String,
+        stream_id: i64,
+        message: Either3<Buffer, String, JsUnknown>,
+        fin: Option<bool>,
+    ) -> Result<u32> {
+        let stream_id = to_stream_id(stream_id)?;
+        let message = message_bytes(env, message)?;
+        self.with_client(&conn_id, |client| {
+            client.framers.send(&mut client.conn, stream_id, &message, fin.unwrap_or(false))
+        })
+    }
+
+    /// Whether the client's FIN has been read on a stream, with everything
+    /// before it: the stream will yield no more `data` events (or request
+    /// body). Also `true` for streams that are gone, or not opened yet.
+    #[napi]
+    pub fn stream_finished(&self, conn_id: String, stream_id: i64) -> Result<bool> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| Ok(client.conn.stream_finished(stream_id)))
+    }
+
+    /// Whether a stream has data, or its end, waiting to be read, as while
+    /// `onAccept()` has not answered or `pipeToFile()` is behind. `false`
+    /// while `streamFinished()` is too means nothing has arrived yet.
+    #[napi]
+    pub fn stream_readable_fin(&self, conn_id: String, stream_id: i64) -> Result<bool> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| Ok(stream_readable_fin(&client.conn, stream_id)))
+    }
+
+    /// Counts a connection's open streams by who opened them and their
+    /// direction, and how many more the server may open. See `StreamCounts`.
+    #[napi]
+    pub fn stream_counts(&self, conn_id: String) -> Result<StreamCounts> {
+        self.with_client(&conn_id, |client| Ok(client.stream_counts()))
+    }
+
+    /// Tells what may be holding a connection's sending back: address
+    /// validation, flow control or congestion on the streams written to, or
+    /// nothing but the application itself. See `BlockedState`.
+    #[napi]
+    pub fn blocked_state(&self, conn_id: String) -> Result<BlockedState> {
+        self.with_client(&conn_id, |client| Ok(client.blocked_state()))
+    }
+
+    /// Queues `data` as a DATAGRAM frame. Returns `false` if it was dropped
+    /// because the send queue is full.
+    #[napi]
+    pub fn datagram_send(&self, conn_id: String, data: Buffer) -> Result<bool> {
+        let (policy, drops) = (self.datagram_policy(), &self.metrics.congestion_dropped_datagrams);
+        self.with_client(&conn_id, |client| send_datagram(&mut client.conn, &data, policy, drops))
+    }
+
+    /// Queues `data` as an HTTP Datagram (RFC 9297) tied to a request stream
+    /// of an HTTP/3 connection. Returns `false` if it was dropped because
+    /// the send queue is full. The client's arrive as `datagram` events with
+    /// the request's `streamId`.
+    #[napi]
+    pub fn send_request_datagram(&self, conn_id: String, stream_id: i64, data: Buffer) -> Result<bool> {
+        let stream_id = to_stream_id(stream_id)?;
+        let (policy, drops) = (self.datagram_policy(), &self.metrics.congestion_dropped_datagrams);
+        self.with_client(&conn_id, |client| {
+            let h3 = client.h3.as_ref().ok_or_else(|| not_http3(&conn_id))?;
+            send_request_datagram(h3, &mut client.conn, stream_id, &data, policy, drops)
+        })
+    }
+
+    /// The largest datagram payload `datagramSend()` accepts now, or `null`
+    /// if the peer does not accept datagrams. It follows the path MTU, so it
+    /// may grow during the connection.
+    #[napi]
+    pub fn dgram_max_writable_len(&self, conn_id: String) -> Result<Option<u32>> {
+        self.with_client(&conn_id, |client| Ok(client.conn.dgram_max_writable_len().map(|len| len as u32)))
+    }
+
+    /// Sets a stream's urgency (0–7, lower is sent first; 3 by default) and
+    /// whether it shares bandwidth round-robin with streams of equal urgency.
+    #[napi]
+    pub fn set_stream_priority(&self, conn_id: String, stream_id: i64, urgency: u32, incremental: bool) -> Result<()> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| set_stream_priority(&mut client.conn, stream_id, urgency, incremental))
+    }
+
+    /// The application protocol negotiated with a connection, or `null`
+    /// until the handshake has chosen one. Connections speak HTTP/3 or raw
+    /// streams according to it; see `alpn` and `http3` in the options.
+    #[napi]
+    pub fn alpn(&self, conn_id: String) -> Result<Option<String>> {
+        self.with_client(&conn_id, |client| Ok(negotiated_alpn(&client.conn)))
+    }
+
+    /// Returns the DER certificates the client presented, leaf first, or
+    /// `null` if it sent none or the handshake has not completed.
+    #[napi]
+    pub fn peer_cert_chain(&self, conn_id: String) -> Result<Option<Vec<Buffer>>> {
+        self.with_client(&conn_id, |client| Ok(peer_cert_chain(&client.conn)))
+    }
+
+    /// Closes a connection with a transport error code, or an application one
+    /// if `applicationError` is set. A `closed` event follows once the
+    /// draining period ends. With `timeoutMs`, the connection is wound down
+    /// as `drain()` does first: the close is sent once the data already
+    /// written has been acknowledged and open requests or streams are done,
+    /// or when `timeoutMs` runs out.
+    #[napi]
+    pub fn close_connection(
+        &self,
+        conn_id: String,
+        application_error: bool,
+        error_code: i64,
+        reason: Option<Buffer>,
+        timeout_ms: Option<u32>,
+    ) -> Result<()> {
+        let reason = reason.as_deref().unwrap_or_default();
+        self.with_client(&conn_id, |client| match timeout_ms {
+            Some(ms) => {
+                client.graceful_close = Some(GracefulClose {
+                    deadline: Instant::now() + Duration::from_millis(ms.into()),
+                    application_error,
+                    error_code: close_code(error_code)?,
+                    reason: reason.to_vec(),
+                });
+                Ok(())
+            }
+            None => close_connection(&mut client.conn, application_error, error_code, reason),
+        })
+    }
+
+    /// Abruptly ends one or both directions of a stream with an error code,
+    /// such as a `TransportError` on a raw QUIC connection or an `H3Error`
+    /// on an HTTP/3 one: `read` asks the client to stop sending
+    /// (STOP_SENDING), `write` abandons what the server was sending
+    /// (RESET_STREAM). Directions already shut are left as they are.
+    #[napi(ts_args_type = "connId: string, streamId: number, direction: 'read' | 'write' | 'both', errorCode: number")]
+    pub fn stream_shutdown(&self, conn_id: String, stream_id: i64, direction: String, error_code: i64) -> Result<()> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| stream_shutdown(&mut client.conn, stream_id, &direction, error_code))
+    }
+
+    /// Starts writing a qlog trace of a live connection to a new file at
+    /// `path`, replacing any trace it was writing, so that one connection
+    /// can be looked into without tracing them all with `qlogDir`. Events
+    /// before the call, the handshake among them, are not in it. Requires
+    /// building with the `qlog` cargo feature.
+    #[napi]
+    pub fn start_qlog(&self, conn_id: String, path: String) -> Result<()> {
+        self.with_client(&conn_id, |client| {
+            start_qlog(&mut client.conn, Path::new(&path), &client.id, "server").map_err(io_err_to_napi)
+        })
+    }
+
+    /// Stops a connection's qlog trace, from `startQlog()` or `qlogDir`,
+    /// and flushes its file.
+    #[napi]
+    pub fn stop_qlog(&self, conn_id: String) -> Result<()> {
+        self.with_client(&conn_id, |client| {
+            stop_qlog(&mut client.conn);
+            Ok(())
+        })
+    }
+
+    /// Writes what a stream receives from now on to a new file at `path`
+    /// instead of emitting `data` events. The stream is read only as fast as
+    /// the file is written, so flow control holds the peer back. On HTTP/3
+    /// connections the request body is written. Resolves once the stream
+    /// ends; rejects, removing the file, if the stream is reset, the
+    /// connection closes first, or more than `maxBytes` arrive.
+    #[napi(ts_return_type = "Promise<PipeResult>")]
+    pub fn pipe_to_file(
+        &self,
+        env: Env,
+        conn_id: String,
+        stream_id: i64,
+        path: String,
+        options: Option<PipeOptions>,
+    ) -> Result<JsObject> {
+        let stream_id = to_stream_id(stream_id)?;
+        let max_bytes = options
+            .and_then(|options| options.max_bytes)
+            .map(|max| u64::try_from(max).map_err(|_| napi::Error::from_reason("maxBytes must not be negative")))
+            .transpose()?;
+
+        self.with_client(&conn_id, |client| {
+            let ended = match client.h3 {
+                Some(_) => !client.requests.pipe(stream_id),
+                None => client.conn.stream_finished(stream_id) && !client.conn.stream_readable(stream_id),
+            };
+            if ended {
+                return Err(napi::Error::from_reason(format!("Stream {} has already ended", stream_id)));
+            }
+            client.sinks.open(env, stream_id, PathBuf::from(path), max_bytes)
+        })
+    }
+
+    /// Sends the response head for a `request` event, followed by `body` if
+    /// given. The stream is finished afterwards unless `fin` is `false`, in
+    /// which case the body can be continued with `sendBody()`. Returns how many
+    /// bytes of `body` were accepted.
+    #[napi]
+    pub fn send_response(
+        &self,
+        conn_id: String,
+        stream_id: i64,
+        headers: Vec<HttpHeader>,
+        body: Option<Buffer>,
+        fin: Option<bool>,
+    ) -> Result<u32> {
+        let stream_id = to_stream_id(stream_id)?;
+        let fin = fin.unwrap_or(true);
+
+        self.with_client(&conn_id, |client| {
+            let h3 = client.h3.as_mut().ok_or_else(|| not_http3(&conn_id))?;
+            h3.send_response(&mut client.conn, stream_id, &to_h3_headers(&headers), fin && body.is_none())
+                .map_err(h3_err_to_napi)?;
+            client.requests.note_response(stream_id, &headers);
+
+            let written = match &body {
+                Some(body) => send_h3_body(h3, &mut client.conn, stream_id, body, fin)?,
+                None => 0,
+            };
+            let fin_written = fin && written as usize == body.map_or(0, |b| b.len());
+            client.requests.note_body(stream_id, written, fin_written, &self.metrics.h3);
+            client.note_write(stream_id, fin_written);
+            Ok(written)
+        })
+    }
+
+    /// Continues a response body started with `sendResponse(..., fin = false)`
+    /// and returns how many bytes were accepted.
+    #[napi]
+    pub fn send_body(&self, conn_id: String, stream_id: i64, data: Buffer, fin: bool) -> Result<u32> {
+        let stream_id = to_stream_id(stream_id)?;
+        self.with_client(&conn_id, |client| {
+            let h3 = client.h3.as_mut().ok_or_else(|| not_http3(&conn_id))?;
+            let written = send_h3_body(h3, &mut client.conn, stream_id, &data, fin)?;
+            let fin_written = fin && written as usize == data.len();
+            client.requests.note_body(stream_id, written, fin_written, &self.metrics.h3);
+            client.note_write(stream_id, fin_written);
+            Ok(written)
+        })
+    }
+
+    // Builds the quiche configuration for one certificate from the options
+    fn build_config(&self, identity: Option<(PemFile, PemFile)>) -> Result<Config> {
+        server_config(&self.options, self.ticket_key.as_deref(), identity)
+    }
+
+    // The running server's datagram policy; with_client() fails otherwise
+    fn datagram_policy(&self) -> DatagramPolicy {
+        match &self.state {
+            State::Running(running) => running.shared.datagram_policy,
+            _ => DatagramPolicy::Queue,
+        }
+    }
+
+    // Runs `f` against an accepted connection, then flushes whatever it queued
+    fn with_client<R>(&self, conn_id: &str, f: impl FnOnce(&mut Client) -> Result<R>) -> Result<R> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let key = parse_hex_id(conn_id)
+            .ok_or_else(|| napi::Error::from_reason(format!("Invalid connection ID {:?}", conn_id)))?;
+
+        let mut clients = running.shared.clients.lock().unwrap();
+        let client = clients
+            .get_mut(&quiche::ConnectionId::from_vec(key))
+            .ok_or_else(|| napi::Error::from_reason(format!("Unknown connection {}", conn_id)))?;
+
+        client.held = false;
+        let result = f(client);
+
+        if !client.held {
+            let mut out = [0; MAX_DATAGRAM_SIZE];
+            client.flush(&running.shared, &mut out);
+        }
+
+        result
+    }
+
+    /// Replaces the certificate and key presented to new connections, from
+    /// paths or, given Buffers, from PEM or DER content. Established
+    /// connections keep the certificate they started with. With
+    /// `serverName`, the matching `certificates` entry is replaced instead
+    /// of the default. Unless `ticketKey` is set, session tickets issued
+    /// before the reload are no longer accepted, so returning clients do a
+    /// full handshake once.
+    #[napi]
+    pub fn reload_certificates(
+        &mut self,
+        cert: Either<String, Buffer>,
+        key: Either<String, Buffer>,
+        server_name: Option<String>,
+    ) -> Result<()> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let server_name = server_name.map(|name| name.to_ascii_lowercase());
+        if let Some(name) = &server_name {
+            let known = self.options.certificates.iter().flatten().any(|c| c.server_name.eq_ignore_ascii_case(name));
+            if !known && !self.hosts.iter().any(|(host, _, _)| host == name) {
+                return Err(napi::Error::from_reason(format!("No certificate is configured for {}", name)));
+            }
+        }
+
+        let files = VirtualHostFiles { cert, key };
+        let mut config = self.build_config(files.identity()?)?;
+        if running.shared.keylog.is_some() {
+            config.log_keys();
+        }
+
+        running.shared.reloads.lock().unwrap().push((server_name.clone(), config));
+        self.reloaded.insert(server_name, files);
+        Ok(())
+    }
+
+    /// Changes the transport settings of connections accepted from now on,
+    /// e.g. to lower flow control windows under memory pressure. Fields set
+    /// in `config` replace those given to the constructor or an earlier
+    /// call, and omitted ones are kept. Established connections keep the
+    /// settings they started with.
+    #[napi]
+    pub fn update_config(&mut self, config: QuicConfig) -> Result<()> {
+        let previous = self.options.config.clone();
+        self.options.config.get_or_insert_with(QuicConfig::default).merge(config);
+
+        // Every configuration is built before any is swapped in, so settings
+        // quiche refuses leave the server as it was
+        let configs = match self.current_configs() {
+            Ok(configs) => configs,
+            Err(e) => {
+                self.options.config = previous;
+                return Err(e);
+            }
+        };
+        if let State::Running(running) = &self.state {
+            running.shared.reloads.lock().unwrap().extend(configs);
+        }
+        Ok(())
+    }
+
+    // Builds every certificate's configuration from the current options
+    fn current_configs(&self) -> Result<Vec<(Option<String>, Config)>> {
+        let keylog = matches!(&self.state, State::Running(running) if running.shared.keylog.is_some());
+        let mut server_names = vec![None];
+        let certificates = self.options.certificates.iter().flatten();
+        server_names.extend(certificates.map(|c| Some(c.server_name.to_ascii_lowercase())));
+        server_names.extend(self.hosts.iter().map(|(name, _, _)| Some(name.clone())));
+
+        let mut configs = Vec::new();
+        for server_name in server_names {
+            let mut config = self.build_config(self.current_identity(&server_name)?)?;
+            if keylog {
+                config.log_keys();
+            }
+            configs.push((server_name, config));
+        }
+        Ok(configs)
+    }
+
+    // The certificate served for `server_name`, or by default for None: the
+    // last one reloaded, else the one configured
+    fn current_identity(&self, server_name: &Option<String>) -> Result<Option<(PemFile, PemFile)>> {
+        if let Some(files) = self.reloaded.get(server_name) {
+            return files.identity();
+        }
+        let Some(name) = server_name else {
+            let options = &self.options;
+            return identity(
+                options.cert_path.as_deref(),
+                options.cert.as_ref(),
+                options.key_path.as_deref(),
+                options.key.as_ref(),
+            );
+        };
+        let mut certificates = self.options.certificates.iter().flatten();
+        if let Some(cert) = certificates.find(|c| c.server_name.eq_ignore_ascii_case(name)) {
+            return identity(cert.cert_path.as_deref(), cert.cert.as_ref(), cert.key_path.as_deref(), cert.key.as_ref());
+        }
+        match self.hosts.iter().find(|(host, _, _)| host == name) {
+            Some((_, files, _)) => files.identity(),
+            None => Ok(None),
+        }
+    }
+
+    /// Serves `serverName` (which may start with `*.`) with its own
+    /// certificate, and sends the events of connections that ask for it
+    /// through SNI to `host.handler` instead of the constructor's callback.
+    /// Works before and after `start()`; connections already open are not
+    /// moved. A name can only be added once, and not if `certificates`
+    /// lists it; use `reloadCertificates()` to replace its certificate.
+    #[napi]
+    pub fn add_virtual_host(&mut self, env: Env, server_name: String, host: VirtualHost) -> Result<()> {
+        let server_name = server_name.to_ascii_lowercase();
+        let taken = self.options.certificates.iter().flatten().any(|c| c.server_name.eq_ignore_ascii_case(&server_name))
+            || self.hosts.iter().any(|(name, _, _)| *name == server_name);
+        if taken {
+            return Err(napi::Error::from_reason(format!("A certificate is already configured for {}", server_name)));
+        }
+
+        let files = VirtualHostFiles { cert: host.cert, key: host.key };
+        // Checked now, so a bad certificate fails here rather than in start()
+        let mut config = self.build_config(files.identity()?)?;
+        let mut handler = event_callback(host.handler)?;
+        // The server's own callback keeps Node running while it serves
+        handler.unref(&env)?;
+        let handler: Handler = Arc::new(handler);
+
+        if let State::Running(running) = &self.state {
+            if running.shared.keylog.is_some() {
+                config.log_keys();
+            }
+            running.shared.new_hosts.lock().unwrap().push((server_name.clone(), config, handler.clone()));
+        }
+        self.hosts.push((server_name, files, handler));
+        Ok(())
+    }
+
+    /// Replaces the session ticket key (48 bytes) for new and resumed
+    /// handshakes. quiche holds one key at a time, so tickets issued under
+    /// the previous key are refused and those clients do a full handshake;
+    /// rotate every instance sharing the key at about the same time, and
+    /// often enough to keep forward secrecy for resumed sessions.
+    #[napi]
+    pub fn set_ticket_key(&mut self, key: Buffer) -> Result<()> {
+        let key = ticket_key(&key)?;
+        if let State::Running(running) = &self.state {
+            *running.shared.ticket_key.lock().unwrap() = Some(key.clone());
+        }
+        self.ticket_key = Some(key);
+        Ok(())
+    }
+
+    /// The `Alt-Svc` header value that points HTTP/1.1 and HTTP/2 clients at
+    /// this server, e.g. `h3=":443"; ma=86400`. It lists every ALPN served as
+    /// HTTP/3 on the port actually bound, to be cached for `maxAgeSecs`
+    /// (default 86400).
+    #[napi]
+    pub fn alt_svc(&self, max_age_secs: Option<u32>) -> Result<String> {
+        let running = match &self.state {
+            State::Running(running) => running,
+            _ => return Err(napi::Error::from_reason("QuicServer is not running")),
+        };
+
+        let default_alpn = vec!["h3".to_string()];
+        let protocols: Vec<String> = self
+            .options
+            .alpn
+            .as_ref()
+            .unwrap_or(&default_alpn)
+            .iter()
+            .filter(|proto| running.shared.h3_configs.contains_key(proto.as_bytes()))
+            .map(|proto| {
+                format!(
+                    "{}=\":{}\"; ma={}",
+                    alt_svc_token(proto),
+                    running.local_addr.port(),
+                    max_age_secs.unwrap_or(ALT_SVC_MAX_AGE)
+                )
+            })
+            .collect();
+
+        if protocols.is_empty() {
+            return Err(napi::Error::from_reason("No ALPN is served as HTTP/3"));
+        }
+        Ok(protocols.join(", "))
+    }
+
+    /// Adds `altSvc()` to every response of a Node `http`, `https` or `http2`
+    /// server in this process (such as the one an Express app's `listen()`
+    /// returns), so browsers learn they can switch to HTTP/3. Give both
+    /// servers the same `cert` and `key` so they present one certificate.
+    /// Call it once `address()` is set; returns the advertised value.
+    #[napi(ts_args_type = "httpServer: import('events').EventEmitter, maxAgeSecs?: number")]
+    pub fn advertise_on(&self, env: Env, http_server: JsObject, max_age_secs: Option<u32>) -> Result<String> {
+        let value = self.alt_svc(max_age_secs)?;
+
+        let make_listener: JsFunction = env.run_script(ALT_SVC_SCRIPT)?;
+        let listener = make_listener.call(None, &[env.create_string(&value)?])?;
+        let on: JsFunction = http_server.get_named_property("on")?;
+        on.call(Some(&http_server), &[env.create_string("request")?.into_unknown(), listener])?;
+
+        Ok(value)
+    }
+
+    /// The address the server is bound to, or `null` when it is not running.
+    #[napi]
+    pub fn address(&self) -> Option<SocketAddress> {
+        match &self.state {
+            State::Running(running) => Some(running.local_addr.into()),
+            _ => None,
+        }
+    }
+}
+
+fn send_h3_body(
+    h3: &mut quiche::h3::Connection,
+    conn: &mut quiche::Connection,
+    stream_id: u64,
+    body: &[u8],
+    fin: bool,
+) -> Result<u32> {
+    match h3.send_body(conn, stream_id, body, fin) {
+        Ok(written) => Ok(written as u32),
+        Err(quiche::h3::Error::Done) => Ok(0),
+        Err(e) => Err(h3_err_to_napi(e)),
+    }
+}
+
+fn not_http3(conn_id: &str) -> napi::Error {
+    napi::Error::from_reason(format!("Connection {} is not using HTTP/3", conn_id))
+}
+
+// Answers a packet of `len` bytes for connection `cid` with a stateless
+// reset, if martianAction asks for one
+fn send_stateless_reset(shared: &Shared, cid: &[u8], len: usize, to: SocketAddr, out: &mut [u8]) {
+    if shared.martian_action != MartianAction::Reset {
+        return;
+    }
+    let Some(reset_len) = shared.martians.lock().unwrap().stateless_reset(cid, len, out) else {
+        return;
+    };
+    match shared.socket.send_to(&out[..reset_len], to) {
+        Ok(_) => {
+            shared.metrics.stateless_resets.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => eprintln!("Failed to send stateless reset: {:?}", e),
+    }
+}
+
+// Builds the quiche configuration for one certificate from the options
+fn server_config(
+    options: &QuicServerOptions,
+    ticket_key: Option<&[u8]>,
+    identity: Option<(PemFile, PemFile)>,
+) -> Result<Config> {
+    let identity = identity
+        .ok_or_else(|| napi::Error::from_reason("certPath and keyPath, or cert and key, are required"))?;
+    let alpn = options.alpn.clone().unwrap_or_else(|| vec!["h3".to_string()]);
+    let alpn: Vec<&[u8]> = alpn.iter().map(|p| p.as_bytes()).collect();
+    let default_config = QuicConfig::default();
+
+    let mut config = build_server_config(&identity, &alpn, options.config.as_ref().unwrap_or(&default_config))?;
+    apply_datagram_options(&mut config, options.datagrams.as_ref());
+    load_trust_anchors(&mut config, options.ca_file.as_deref(), options.ca_dir.as_deref())?;
+    let require_client_cert = options.require_client_cert.unwrap_or(false);
+    config.verify_peer(require_client_cert || options.verify_client.unwrap_or(false));
+    apply_pacing_rate(&mut config, options.max_pacing_rate_bps)?;
+    if let Some(key) = ticket_key {
+        config.set_ticket_key(key).map_err(quiche_err_to_napi)?;
+    }
+    Ok(config)
+}
+
+// Validates the options and builds everything the packet loop needs to
+// serve them over `socket`
+fn prepare(
+    options: &QuicServerOptions,
+    ticket_key: Option<&[u8]>,
+    metrics: Arc<Metrics>,
+    socket: Box<dyn Transport + Send + Sync>,
+) -> Result<(ServerConfigs, Shared)> {
+    let pinned_peer = match &options.pinned_peer {
+        Some(peer) => Some(PinnedPeer::parse(peer).ok_or_else(|| {
+            napi::Error::from_reason(format!("pinnedPeer {:?} is not an IP address or socket address", peer))
+        })?),
+        None => None,
+    };
+    let versions = options.versions.clone().unwrap_or_else(|| vec![QUIC_V1]);
+    if let Some(&version) = versions.iter().find(|&&v| !quiche::version_is_supported(v) && !is_reserved_version(v)) {
+        return Err(napi::Error::from_reason(format!(
+            "QUIC version {:#x} is neither supported nor reserved",
+            version
+        )));
+    }
+    if !versions.iter().any(|&v| quiche::version_is_supported(v)) {
+        return Err(napi::Error::from_reason("versions must include one quiche supports"));
+    }
+    if versions.len() > MAX_VERSIONS {
+        return Err(napi::Error::from_reason(format!("versions lists at most {} versions", MAX_VERSIONS)));
+    }
+
+    let qlog_dir = qlog_dir(options.qlog_dir.as_deref(), options.qlog_output.as_ref())?;
+
+    let alpn = options.alpn.clone().unwrap_or_else(|| vec!["h3".to_string()]);
+    let h3_configs = h3_configs(&alpn, options.http3.as_ref())?;
+    let datagrams = options.datagrams.is_some();
+    let alpn_datagrams = options.http3.iter().flatten().filter_map(|(proto, settings)| {
+        settings.datagrams.filter(|&on| on != datagrams).map(|on| (proto.as_bytes().to_vec(), on))
+    });
+    let alpn_datagrams = alpn_datagrams.collect();
+    let queue_len = |len: Option<u32>| len.unwrap_or(DEFAULT_DGRAM_QUEUE_LEN) as usize;
+    let datagram_queues = (
+        queue_len(options.datagrams.as_ref().and_then(|d| d.recv_queue_len)),
+        queue_len(options.datagrams.as_ref().and_then(|d| d.send_queue_len)),
+    );
+    let require_client_cert = options.require_client_cert.unwrap_or(false);
+    let martian_action = MartianAction::parse(options.martian_action.as_deref())?;
+    let alpn_mismatch = AlpnMismatch::parse(options.alpn_mismatch.as_deref())?;
+    let drain_stream_error_code = u64::try_from(options.drain_stream_error_code.unwrap_or(0))
+        .map_err(|_| napi::Error::from_reason("drainStreamErrorCode must not be negative"))?;
+
+    let default_identity = identity(
+        options.cert_path.as_deref(),
+        options.cert.as_ref(),
+        options.key_path.as_deref(),
+        options.key.as_ref(),
+    )?;
+    let mut config = server_config(options, ticket_key, default_identity)?;
+    let keylog = open_keylog(&mut config, options.keylog_file.as_deref())?;
+    let mut by_name = Vec::new();
+    for cert in options.certificates.iter().flatten() {
+        let named_identity = identity(
+            cert.cert_path.as_deref(),
+            cert.cert.as_ref(),
+            cert.key_path.as_deref(),
+            cert.key.as_ref(),
+        )?;
+        let mut named = server_config(options, ticket_key, named_identity)?;
+        if keylog.is_some() {
+            named.log_keys();
+        }
+        by_name.push((cert.server_name.to_ascii_lowercase(), named));
+    }
+    let configs = ServerConfigs { default: config, by_name, handlers: HashMap::new() };
+
+    let shared = Shared {
+        socket,
+        clients: Mutex::new(ClientMap::new()),
+        accepting: AtomicBool::new(true),
+        shutdown: Mutex::new(None),
+        metrics,
+        log_tls_alerts: options.log_tls_alerts.unwrap_or(true),
+        incoming: Mutex::new(None),
+        pinned_peer,
+        versions,
+        initial_limiter: options.initial_rate_limit.as_ref().map(|l| Mutex::new(InitialLimiter::new(l))),
+        retry: RetryTokens::new()?,
+        retry_all: options.retry.unwrap_or(false),
+        initial_hook: Mutex::new(None),
+        held_initials: Mutex::new(HeldInitials::default()),
+        cids: ConnectionIds::new(options.cid_key.as_deref(), options.worker_id)?,
+        scheduler: Mutex::new(None),
+        qlog_dir,
+        acceptor: Mutex::new(None),
+        keylog,
+        drain: Mutex::new(None),
+        require_client_cert,
+        h3_configs,
+        datagrams,
+        datagram_queues,
+        alpn_datagrams,
+        handshake_retransmit_threshold: options
+            .handshake_retransmit_threshold
+            .map_or(HANDSHAKE_RETRANSMIT_THRESHOLD, |n| n as usize),
+        reloads: Mutex::new(Vec::new()),
+        new_hosts: Mutex::new(Vec::new()),
+        routes: Mutex::new(HashMap::new()),
+        martians: Mutex::new(Martians::new()?),
+        martian_threshold: options.martian_threshold.map_or(MARTIAN_THRESHOLD, |n| n as usize),
+        martian_action,
+        log_martian_packets: options.log_martian_packets.unwrap_or(false),
+        unhandled_packet_events: options.unhandled_packet_events.unwrap_or(false),
+        max_connection_age: options.max_connection_age_ms.map(|ms| Duration::from_millis(ms.into())),
+        drain_stream_error_code,
+        write_coalescing: coalescing_window(options.write_coalescing_us),
+        profile_phases: options.profile_phases.unwrap_or(false),
+        datagram_policy: DatagramPolicy::parse(options.datagrams.as_ref())?,
+        alpn: alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        alpn_mismatch,
+        ticket_key: Mutex::new(None),
+        hello_bytes: AtomicUsize::new(0),
+    };
+
+    Ok((configs, shared))
+}
+
+// Starts the packet loop on its own thread and returns as soon as it is running
+fn spawn(mut configs: ServerConfigs, events: EventCallback, shared: Shared) -> napi::Result<Running> {
+    let local_addr = shared.socket.local_addr().map_err(io_err_to_napi)?;
+    shared.socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(io_err_to_napi)?;
+
+    let shared = Arc::new(shared);
+    let loop_shared = shared.clone();
+
+    let thread = thread::Builder::new()
+        .name("quic-server".into())
+        .spawn(move || {
+            events.emit(QuicEvent::listening(local_addr));
+
+            if let Err(e) = run_server(&mut configs, &loop_shared, &events) {
+                eprintln!("QUIC server stopped: {}", e.reason);
+                events.emit(QuicEvent::error(e.reason));
+            }
+        })
+        .map_err(io_err_to_napi)?;
+
+    Ok(Running { shared, local_addr, thread })
+}
+
+// Drives the accept/recv/send loop over any packet transport
+fn run_server(
+    configs: &mut ServerConfigs,
+    shared: &Arc<Shared>,
+    events: &dyn EmitEvent,
+) -> napi::Result<()> {
+    let events = &Router { routes: &shared.routes, fallback: events };
+    let mut buf = [0; RECV_BUFFER_SIZE];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    let socket = shared.socket.as_ref();
+    // Peers whose handshake has not completed yet, keyed by source address
+    let mut handshaking: HashMap<SocketAddr, Handshake> = HashMap::new();
+    let mut hellos = ClientHellos::default();
+    // When the current second of unhandledPacket events began, and how many it has had
+    let mut unhandled = (Instant::now(), 0);
+    let local_addr = socket.local_addr().map_err(io_err_to_napi)?;
+    let mut clock = ClockWatch::new();
+    // Initials onInitial has answered for, to be handled as if just received
+    let mut replays = VecDeque::new();
+
+    loop {
+        if let Some(offset) = clock.check() {
+            eprintln!("System clock jumped by {} ms relative to monotonic time", offset);
+            events.emit(QuicEvent::clock_jump(offset));
+        }
+
+        let shutdown = *shared.shutdown.lock().unwrap();
+        let flushing = shutdown == Some(Shutdown::Flush) && shared.drain.lock().unwrap().is_some();
+        if let (Some(mode), false) = (shutdown, flushing) {
+            if mode != Shutdown::Immediate {
+                for client in shared.clients.lock().unwrap().values_mut() {
+                    let _ = client.conn.close(false, TransportError::NoError as u64, b"server closing");
+                    flush_egress(socket, &mut client.conn, &mut out);
+                }
+            }
+            for queue in shared.clients.lock().unwrap().values().filter_map(|c| c.incoming_streams.as_ref()) {
+                queue.close();
+            }
+            println!("QUIC server on {:?} closed", local_addr);
+            return Ok(());
+        }
+
+        // Hosts first, so that configurations rebuilt for them just after
+        // they were added replace the ones they were added with
+        for (server_name, config, handler) in shared.new_hosts.lock().unwrap().drain(..) {
+            println!("Serving virtual host {}", server_name);
+            configs.add_host(server_name, config, handler);
+        }
+        for (server_name, config) in shared.reloads.lock().unwrap().drain(..) {
+            println!("Reloaded configuration for {}", server_name.as_deref().unwrap_or("the default server name"));
+            configs.replace(server_name, config);
+        }
+        // After the reloads, which may have been built with the previous key
+        if let Some(key) = shared.ticket_key.lock().unwrap().take() {
+            match configs.set_ticket_key(&key) {
+                Ok(()) => println!("Session ticket key replaced"),
+                Err(e) => eprintln!("Failed to set session ticket key: {:?}", e),
+            }
+        }
+
+        let wait = run_timers(shared, &mut handshaking, &mut out, events);
+        run_admissions(shared, &mut buf, &mut out, events);
+        run_drain(shared, &mut out);
+        run_max_age(shared, &mut out);
+        run_graceful_closes(shared, &mut out);
+        run_sinks(shared, &mut buf, &mut out, events);
+        run_scheduler(shared);
+        shared.hello_bytes.store(hellos.kept(), Ordering::Relaxed);
+        replays.extend(shared.held_initials.lock().unwrap().release(Instant::now()));
+        socket.set_read_timeout(Some(wait)).map_err(io_err_to_napi)?;
+
+        // Some(verdict) for a held Initial, None for a datagram off the socket
+        let mut replayed = None;
+        let (len, from) = match replays.pop_front() {
+            Some((from, datagram, verdict)) => {
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                replayed = Some(verdict);
+                (datagram.len(), from)
+            }
+            None => match socket.recv_from(&mut buf) {
+                Ok(v) => v,
+                // Read timeout: nothing arrived before the next timer or POLL_INTERVAL
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(io_err_to_napi(e)),
+            },
+        };
+
+        // Checked before any parsing so strangers cannot reach the QUIC stack
+        if shared.pinned_peer.is_some_and(|peer| !peer.matches(from)) {
+            shared.metrics.foreign_datagrams.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        if len >= buf.len() {
+            eprintln!("Dropping oversized datagram from {:?}", from);
+            shared.metrics.oversized_datagrams.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let pkt_buf = &mut buf[..len];
+
+        let hdr = match quiche::Header::from_slice(pkt_buf, quiche::MAX_CONN_ID_LEN) {
+            Ok(hdr) => hdr,
+            Err(e) => {
+                eprintln!("Failed to parse header: {:?}", e);
+                shared.metrics.malformed_datagrams.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
+        // Short header packets carry no version and are never negotiated.
+        let accepted = quiche::version_is_supported(hdr.version) && shared.versions.contains(&hdr.version);
+        if hdr.ty != quiche::Type::Short && !accepted {
+            // The pinned peer is known to speak v1; negotiating is pointless
+            if shared.pinned_peer.is_some() {
+                println!("Dropping packet with unsupported version {:#x} from {:?}", hdr.version, from);
+                continue;
+            }
+
+            println!("Unsupported QUIC version from client: {:#x}. Offering {:x?}.", hdr.version, shared.versions);
+            let len = negotiate_version(&hdr.scid, &hdr.dcid, &shared.versions, &mut out);
+            println!("Sending version negotiation packet: {} bytes", len);
+            
+            if let Err(e) = socket.send_to(&out[..len], from) {
+                eprintln!("Failed to send version negotiation packet: {:?}", e);
+            } else {
+                println!("Version negotiation packet sent successfully.");
+            }
+            continue;
+        }
+
+        let mut conn_id: quiche::ConnectionId<'static> = hdr.dcid.to_vec().into();
+        let mut clients = shared.clients.lock().unwrap();
+
+        // Until our first Initial reaches it, a client keeps addressing the
+        // connection by the DCID it picked itself
+        if !clients.contains_key(&conn_id) {
+            if let Some(handshake) = handshaking.get(&from).filter(|h| h.odcid == conn_id) {
+                conn_id = handshake.scid.clone();
+            }
+        }
+
+        if !clients.contains_key(&conn_id) {
+            // Only an Initial can open a connection; anything else for an unknown
+            // DCID is stale, misrouted, or scanning traffic.
+            if hdr.ty != quiche::Type::Initial {
+                shared.metrics.martian_packets.fetch_add(1, Ordering::Relaxed);
+                if shared.log_martian_packets {
+                    eprintln!(
+                        "Unhandled {:?} packet from {:?}: dcid={:?} scid={:?} version={:#x} len={}",
+                        hdr.ty, from, hdr.dcid, hdr.scid, hdr.version, len
+                    );
+                }
+                if shared.unhandled_packet_events {
+                    if unhandled.0.elapsed() >= Duration::from_secs(1) {
+                        unhandled = (Instant::now(), 0);
+                    }
+                    if unhandled.1 < MAX_UNHANDLED_EVENTS {
+                        unhandled.1 += 1;
+                        events.emit(QuicEvent::unhandled_packet(from, &hdr, len));
+                    }
+                }
+                let closed = shared.martians.lock().unwrap().packet_for(&hdr.dcid);
+                if let Some((id, peer, packets)) = closed {
+                    if shared.martian_threshold > 0 && packets % shared.martian_threshold == 0 {
+                        if packets == shared.martian_threshold {
+                            events.emit(QuicEvent::martian_packets(&id, peer, packets, true));
+                        }
+                        send_stateless_reset(shared, &hdr.dcid, len, from, &mut out);
+                    }
+                }
+                continue;
+            }
+
+            if !shared.accepting.load(Ordering::SeqCst) {
+                println!("Not accepting new connections; dropping Initial from {:?}", from);
+                continue;
+            }
+
+            // Drop handshake floods before spending any crypto on them
+            if let Some(limiter) = &shared.initial_limiter {
+                if !limiter.lock().unwrap().allow(from.ip()) {
+                    shared.metrics.rate_limited_initials.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+
+            // Clients that retransmit their first Initial under a fresh DCID would
+            // otherwise get a second server connection for the same peer.
+            if let Some(existing) = handshaking.get(&from) {
+                println!(
+                    "Ignoring duplicate connection attempt from {:?}; handshake already in progress as {:?}",
+                    from, existing.scid
+                );
+                continue;
+            }
+
+            let token = hdr.token.as_deref().unwrap_or_default();
+            let verdict = match replayed {
+                Some(verdict) => verdict,
+                None if hold_initial(shared, from, &hdr.dcid, !token.is_empty(), pkt_buf) => continue,
+                None => None,
+            };
+            let verdict = verdict.unwrap_or(if shared.retry_all { Verdict::Retry } else { Verdict::Accept });
+            if verdict == Verdict::Drop {
+                shared.metrics.dropped_initials.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            let mut odcid = None;
+            if !token.is_empty() {
+                match shared.retry.validate(token, from, &hdr.dcid) {
+                    Some(id) => odcid = Some(id),
+                    None => {
+                        println!("Dropping Initial with invalid retry token from {:?}", from);
+                        shared.metrics.invalid_tokens.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+            } else if verdict == Verdict::Retry {
+                let Some(new_scid) = shared.cids.generate() else {
+                    eprintln!("Failed to generate retry connection ID");
+                    continue;
+                };
+                let token = shared.retry.mint(&hdr.dcid, from, &new_scid);
+                match quiche::retry(&hdr.scid, &hdr.dcid, &new_scid, &token, hdr.version, &mut out) {
+                    Ok(len) => match socket.send_to(&out[..len], from) {
+                        Ok(_) => {
+                            shared.metrics.retries_sent.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => eprintln!("Failed to send retry packet: {:?}", e),
+                    },
+                    Err(e) => eprintln!("Failed to build retry packet: {:?}", e),
+                }
+                continue;
+            }
+
+            // Wait for the whole ClientHello, to see which certificate the
+            // client wants and which protocols it offers
+            let (hello, earlier) = match hellos.offer(from, &hdr.dcid, pkt_buf) {
+                Hello::Done(hello, earlier) => (hello, earlier),
+                Hello::Incomplete => continue,
+            };
+            let server_name = hello.as_ref().and_then(|h| h.server_name.as_deref());
+            let mismatch = hello.as_ref().filter(|h| !h.alpn.iter().any(|p| shared.alpn.contains(p)));
+            let fallback = match mismatch {
+                Some(ClientHello { alpn, .. }) if shared.alpn_mismatch == AlpnMismatch::Accept => alpn.first(),
+                _ => None,
+            };
+
+            // A validated client already addresses us by the CID our Retry chose;
+            // otherwise pick one, so the client's DCID never becomes our SCID
+            let scid = match odcid {
+                Some(_) => conn_id.clone(),
+                None => match shared.cids.generate() {
+                    Some(scid) => scid,
+                    None => {
+                        eprintln!("Failed to generate connection ID");
+                        continue;
+                    }
+                },
+            };
+
+            println!("Accepting new connection with scid: {:?}", scid);
+
+            // quiche settles on the client's first choice the server supports
+            let alpn = hello.as_ref().and_then(|h| h.alpn.iter().find(|p| shared.alpn.contains(p))).or(fallback);
+
+            let handler = configs.handler(server_name);
+            let config = configs.select(server_name);
+            config.set_stateless_reset_token(Some(shared.martians.lock().unwrap().reset_token(&scid)));
+            // The connection keeps its own copy of the protocols and transport
+            // parameters, so the fallback and the ALPN's datagram support
+            // apply to it alone
+            if let Some(proto) = fallback {
+                let _ = config.set_application_protos(&[proto]);
+            }
+            let datagrams = alpn.and_then(|proto| shared.alpn_datagrams.get(proto)).copied();
+            let (recv_queue_len, send_queue_len) = shared.datagram_queues;
+            if let Some(enabled) = datagrams {
+                config.enable_dgram(enabled, recv_queue_len, send_queue_len);
+            }
+            let accepted = quiche::accept(&scid, odcid.as_ref(), local_addr, from, config);
+            if fallback.is_some() {
+                let protos: Vec<&[u8]> = shared.alpn.iter().map(Vec::as_slice).collect();
+                let _ = config.set_application_protos(&protos);
+            }
+            if datagrams.is_some() {
+                config.enable_dgram(shared.datagrams, recv_queue_len, send_queue_len);
+            }
+            let mut conn = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("QUIC accept error: {:?}", e);
+                    continue;
+                }
+            };
+            println!("Connection accepted from {:?}", from);
+
+            let id = hex_id(&scid);
+            if let Some(handler) = handler {
+                shared.routes.lock().unwrap().insert(id.clone(), handler);
+            }
+            if let Some(dir) = &shared.qlog_dir {
+                if let Err(e) = enable_qlog(&mut conn, dir, &id, "server") {
+                    eprintln!("Failed to start qlog for connection {}: {:?}", id, e);
+                }
+            }
+            if let Some(keylog) = &shared.keylog {
+                match keylog.try_clone() {
+                    Ok(file) => conn.set_keylog(Box::new(file)),
+                    Err(e) => eprintln!("Failed to open key log for connection {}: {:?}", id, e),
+                }
+            }
+            // Initials that carried the start of the ClientHello go in first
+            for mut datagram in earlier {
+                if let Err(e) = conn.recv(&mut datagram, RecvInfo { from, to: local_addr }) {
+                    eprintln!("QUIC recv error: {:?}", e);
+                }
+            }
+            events.emit(QuicEvent::connection(&id, from));
+            if let Some(hello) = mismatch {
+                let event = QuicEvent::alpn_mismatch(&id, from, &hello.alpn, fallback.map(Vec::as_slice));
+                eprintln!("{}", event.message.as_deref().unwrap_or_default());
+                events.emit(event);
+            }
+            let incoming_connection = || IncomingConnection {
+                conn_id: id.clone(),
+                peer: from.to_string(),
+                server_name: server_name.map(str::to_string),
+                alpn: alpn.map(|proto| String::from_utf8_lossy(proto).into_owned()),
+            };
+            if let Some(incoming) = shared.incoming.lock().unwrap().as_ref() {
+                incoming.push(incoming_connection());
+            }
+
+            // Hold back application data until onAccept has answered
+            let admission = match shared.acceptor.lock().unwrap().as_ref() {
+                Some(acceptor) => {
+                    let (shared, key) = (shared.clone(), scid.clone());
+                    acceptor.callback.call_with_return_value(
+                        incoming_connection(),
+                        ThreadsafeFunctionCallMode::NonBlocking,
+                        move |answer: CallbackResult| {
+                            let decide = move |accepted: bool, value: &CallbackResult| {
+                                let decision = value.get::<AcceptDecision>().filter(|_| accepted);
+                                admit(&shared, &key, accepted, decision)
+                            };
+                            // Left pending, the connection is refused at the deadline
+                            if let Err(e) = answer.settle(decide) {
+                                eprintln!("Failed to read onAccept result: {}", e.reason);
+                            }
+                            Ok(())
+                        },
+                    );
+                    Admission::Pending(Instant::now() + acceptor.timeout)
+                }
+                None => Admission::Admitted,
+            };
+
+            handshaking.insert(from, Handshake { scid: scid.clone(), odcid: conn_id });
+            conn_id = scid;
+            clients.insert(
+                conn_id.clone(),
+                Client {
+                    conn,
+                    id,
+                    peer: from,
+                    handshake_done: false,
+                    early_data_ready: false,
+                    tls_alert_reported: false,
+                    h3: None,
+                    requests: RequestTracker::default(),
+                    incoming_streams: None,
+                    next_peer_streams: [0, 2],
+                    peer_streams: HashSet::new(),
+                    admission,
+                    sinks: Sinks::default(),
+                    framers: Framers::default(),
+                    digests: Digests::default(),
+                    congestion: CongestionWatch::default(),
+                    handshake_retransmits: 0,
+                    retransmits_reported: false,
+                    loss_reported: (0, 0, 0),
+                    undecryptable: 0,
+                    martian_reported: false,
+                    recent_tags: VecDeque::new(),
+                    phases: shared.profile_phases.then(PhaseTimes::default),
+                    address_validated: odcid.is_some(),
+                    sending: HashSet::new(),
+                    accepted_at: Instant::now(),
+                    idle_timeout: None,
+                    last_received: Instant::now(),
+                    winding_down: None,
+                    graceful_close: None,
+                    finishing: HashSet::new(),
+                    coalescing: shared.write_coalescing,
+                    held: false,
+                },
+            );
+        }
+
+        let client = clients.get_mut(&conn_id).unwrap();
+
+        let recv_info = RecvInfo { from, to: local_addr };
+        let received = client.conn.stats().recv;
+        // Taken before quiche decrypts the packet in place
+        let tag = short_header_tag(pkt_buf);
+
+        let start = client.phases.as_ref().map(|_| (Phase::of(&client.conn, false), Instant::now()));
+        let result = client.conn.recv(pkt_buf, recv_info);
+        if let (Some(profile), Some((phase, start))) = (client.profile(shared), start) {
+            profile.add(phase, start);
+        }
+        match result {
+            Ok(read) => {
+                println!("Received {} bytes", read);
+                // quiche drops packets it cannot decrypt without saying so
+                let processed = client.conn.stats().recv != received;
+                if processed {
+                    client.last_received = Instant::now();
+                }
+                client.address_validated |= processed && hdr.ty == quiche::Type::Handshake;
+                if client.conn.is_established() && client.check_decryption(shared, tag, processed, len, events) {
+                    send_stateless_reset(shared, &conn_id, len, from, &mut out);
+                    retire(shared, &conn_id, client, events);
+                    handshaking.retain(|_, h| h.scid != conn_id);
+                    clients.remove(&conn_id);
+                    continue;
+                }
+            }
+            Err(e) => {
+                eprintln!("QUIC recv error: {:?}", e);
+                events.emit(QuicEvent::connection_error(&client.id, client.peer, format!("{:?}", e)));
+            }
+        }
+
+        client.report_congestion(events);
+
+        if !client.tls_alert_reported {
+            if let Some(event) = QuicEvent::tls_alert(&client.id, client.peer, &client.conn) {
+                client.tls_alert_reported = true;
+                if shared.log_tls_alerts {
+                    eprintln!("{}", event.message.as_deref().unwrap_or_default());
+                }
+                events.emit(event);
+            }
+        }
+
+        if !client.early_data_ready && client.conn.is_in_early_data() {
+            client.early_data_ready = true;
+            println!("Early data ready on connection from {:?}", from);
+            events.emit(QuicEvent::early_data_ready(&client.id, client.peer, &client.conn));
+        }
+
+        // Checked before any 0-RTT data is delivered as well
+        let uncertified = shared.require_client_cert
+            && (client.conn.is_established() || client.conn.is_in_early_data())
+            && client.conn.peer_cert().is_none();
+        if uncertified && client.admission != Admission::Refused {
+            client.admission = Admission::Refused;
+            let alert = TransportError::CryptoError as i64 + TLS_CERTIFICATE_REQUIRED;
+            let _ = close_connection(&mut client.conn, false, alert, b"client certificate required");
+            if let Some(event) = QuicEvent::tls_alert(&client.id, client.peer, &client.conn) {
+                client.tls_alert_reported = true;
+                if shared.log_tls_alerts {
+                    eprintln!("{}", event.message.as_deref().unwrap_or_default());
+                }
+                events.emit(event);
+            }
+        }
+
+        if !client.handshake_done && client.conn.is_established() {
+            client.handshake_done = true;
+            if !uncertified {
+                println!("Handshake done with {:?}", from);
+                events.emit(QuicEvent::handshake_done(&client.id, client.peer, &client.conn));
+                events.emit(QuicEvent::datagram_support(&client.id, client.peer, &client.conn));
+            }
+
+            if handshaking.get(&from).is_some_and(|h| h.scid == conn_id) {
+                handshaking.remove(&from);
+            }
+        }
+
+        if client.admission == Admission::Admitted {
+            client.serve(shared, &mut buf, events);
+        }
+        client.report_acked_fins(events);
+
+        // Also delivers the CONNECTION_CLOSE after a failed handshake
+        client.flush(shared, &mut out);
+        client.count_handshake_retransmits(shared, events);
+
+        if client.conn.is_closed() {
+            retire(shared, &conn_id, client, events);
+
+            if handshaking.get(&from).is_some_and(|h| h.scid == conn_id) {
+                handshaking.remove(&from);
+            }
+            clients.remove(&conn_id);
+        }
+    }
+}
+
+// Fires every expired connection timer (loss detection, idle and draining
+// timeouts), sends what they produce and writes held for coalescing, and
+// retires connections that closed. Returns how long the loop may block
+// before the next timer or coalescing window is due.
+fn run_timers(
+    shared: &Shared,
+    handshaking: &mut HashMap<SocketAddr, Handshake>,
+    out: &mut [u8],
+    events: &dyn EmitEvent,
+) -> Duration {
+    let mut clients = shared.clients.lock().unwrap();
+    let now = Instant::now();
+
+    for client in clients.values_mut() {
+        if client.idle_deadline().is_some_and(|deadline| deadline <= now) {
+            println!("Connection {} from {:?} timed out idle", client.id, client.peer);
+            let _ = client.conn.close(false, TransportError::NoError as u64, b"idle timeout");
+            flush_egress(shared.socket.as_ref(), &mut client.conn, out);
+        }
+        if let Some(h3) = client.h3.as_mut() {
+            let (conn, requests, metrics) = (&mut client.conn, &mut client.requests, &shared.metrics.h3);
+            if expire_request_bodies(h3, conn, requests, &client.id, client.peer, metrics, events) {
+                flush_egress(shared.socket.as_ref(), conn, out);
+            }
+        }
+        // Writes held for coalescing go out once per window
+        if client.coalescing.is_some() {
+            client.flush(shared, out);
+            client.held = false;
+        }
+        if client.conn.timeout().is_some_and(|t| t.is_zero()) {
+            client.conn.on_timeout();
+            client.report_congestion(events);
+            // A PTO queues probes and lost frames, which go out right away
+            client.flush(shared, out);
+            client.count_handshake_retransmits(shared, events);
+            client.report_loss(events);
+        }
+    }
+
+    clients.retain(|conn_id, client| {
+        if !client.conn.is_closed() {
+            return true;
+        }
+
+        retire(shared, conn_id, client, events);
+        handshaking.retain(|_, h| h.scid != *conn_id);
+        false
+    });
+
+    // A zero read timeout is rejected by the socket, so wait at least 1ms
+    let idle = |c: &Client| c.idle_deadline().map(|deadline| deadline.saturating_duration_since(now));
+    let next = clients.values().flat_map(|c| c.conn.timeout().into_iter().chain(c.coalescing).chain(idle(c))).min();
+    next.map_or(POLL_INTERVAL, |t| t.clamp(Duration::from_millis(1), POLL_INTERVAL))
+}
+
+// Acts on onAccept answers that arrived since the last pass: admitted
+// connections get their buffered data delivered, refused or timed-out ones
+// are closed with CONNECTION_REFUSED.
+fn run_admissions(
+    shared: &Shared,
+    buf: &mut [u8],
+    out: &mut [u8],
+    events: &dyn EmitEvent,
+) {
+    let now = Instant::now();
+
+    for client in shared.clients.lock().unwrap().values_mut() {
+        match client.admission {
+            Admission::Decided(true) => {
+                client.admission = Admission::Admitted;
+                client.serve(shared, buf, events);
+            }
+            Admission::Decided(false) => {
+                println!("Refusing connection {} from {:?}", client.id, client.peer);
+                client.admission = Admission::Refused;
+                let _ = client.conn.close(false, TransportError::ConnectionRefused as u64, b"");
+            }
+            Admission::Pending(deadline) if now >= deadline => {
+                println!("onAccept timed out for connection {} from {:?}", client.id, client.peer);
+                client.admission = Admission::Refused;
+                let _ = client.conn.close(false, TransportError::ConnectionRefused as u64, b"");
+            }
+            _ => continue,
+        }
+        flush_egress(shared.socket.as_ref(), &mut client.conn, out);
+    }
+}
+
+// Keeps piped streams moving between packets. A sink that was full when the
+// last packet arrived may have room now, and a peer blocked by flow control
+// sends nothing until it is read.
+fn run_sinks(
+    shared: &Shared,
+    buf: &mut [u8],
+    out: &mut [u8],
+    events: &dyn EmitEvent,
+) {
+    for client in shared.clients.lock().unwrap().values_mut() {
+        if client.sinks.is_empty() || client.admission != Admission::Admitted {
+            continue;
+        }
+        client.serve(shared, buf, events);
+        flush_egress(shared.socket.as_ref(), &mut client.conn, out);
+    }
+}
+
+impl Drain {
+    fn finish(self) {
+        for waiter in self.waiters {
+            waiter.resolve(Box::new(|_| Ok(())));
+        }
+    }
+}
+
+// Closes each connection once drain() no longer needs it open: HTTP/3 ones
+// after GOAWAY and their last response, the rest when their streams are
+// done, and all of them at the deadline. The drain is over when every connection is closing.
+fn run_drain(shared: &Shared, out: &mut [u8]) {
+    let mut drain = shared.drain.lock().unwrap();
+    let expired = match drain.as_ref() {
+        Some(drain) => Instant::now() >= drain.deadline,
+        None => return,
+    };
+
+    let mut clients = shared.clients.lock().unwrap();
+    for client in clients.values_mut().filter(|c| !is_closing(&c.conn)) {
+        if client.wind_down(expired, shared.drain_stream_error_code) {
+            client.close_wound_down(b"server draining");
+        }
+        flush_egress(shared.socket.as_ref(), &mut client.conn, out);
+    }
+
+    if clients.values().all(|c| is_closing(&c.conn)) {
+        println!("Drain complete");
+        if let Some(drain) = drain.take() {
+            drain.finish();
+        }
+    }
+}
+
+// Winds down connections older than maxConnectionAgeMs as drain() does,
+// giving their requests DRAIN_TIMEOUT to finish
+fn run_max_age(shared: &Shared, out: &mut [u8]) {
+    let Some(max_age) = shared.max_connection_age else {
+        return;
+    };
+
+    for client in shared.clients.lock().unwrap().values_mut() {
+        let age = client.accepted_at.elapsed();
+        if age < max_age || is_closing(&client.conn) {
+            continue;
+        }
+        if client.wind_down(age >= max_age + DRAIN_TIMEOUT, shared.drain_stream_error_code) {
+            client.close_wound_down(b"connection too old");
+        }
+        flush_egress(shared.socket.as_ref(), &mut client.conn, out);
+    }
+}
+
+// Sends the close closeConnection() deferred once the connection has wound
+// down as drain() would, or at the deadline it was given
+fn run_graceful_closes(shared: &Shared, out: &mut [u8]) {
+    let now = Instant::now();
+    for client in shared.clients.lock().unwrap().values_mut() {
+        let Some(close) = &client.graceful_close else {
+            continue;
+        };
+        if is_closing(&client.conn) {
+            client.graceful_close = None;
+            continue;
+        }
+        if client.wind_down(now >= close.deadline, shared.drain_stream_error_code) {
+            let close = client.graceful_close.take().unwrap();
+            let _ = client.conn.close(close.application_error, close.error_code, &close.reason);
+        }
+        flush_egress(shared.socket.as_ref(), &mut client.conn, out);
+    }
+}
+
+// Adds up what memoryUsage() reports
+fn memory_usage(shared: &Shared) -> MemoryUsage {
+    let (mut connections, mut pending_writes, mut reassembly, mut token_caches) = (0, 0, 0, 0);
+    let clients = shared.clients.lock().unwrap();
+    for client in clients.values() {
+        let (outgoing, partial) = client.framers.buffered();
+        connections += client.memory();
+        pending_writes += outgoing + client.sinks.pending_bytes();
+        reassembly += partial;
+        token_caches += client.recent_tags.capacity() * TAG_LEN;
+    }
+    reassembly += shared.hello_bytes.load(Ordering::Relaxed);
+    reassembly += shared.held_initials.lock().unwrap().memory();
+    token_caches += shared.martians.lock().unwrap().memory();
+    token_caches += shared.initial_limiter.as_ref().map_or(0, |limiter| limiter.lock().unwrap().memory());
+
+    MemoryUsage {
+        connection_count: clients.len() as u32,
+        connections: connections as i64,
+        pending_writes: pending_writes as i64,
+        reassembly: reassembly as i64,
+        token_caches: token_caches as i64,
+        total: (connections + pending_writes + reassembly + token_caches) as i64,
+    }
+}
+
+// Whether either side has sent CONNECTION_CLOSE
+fn is_closing(conn: &quiche::Connection) -> bool {
+    conn.local_error().is_some() || conn.peer_error().is_some() || conn.is_closed()
+}
+
+// Holds an Initial for onInitial if one is installed, asking it about the
+// connection attempt the first time. Returns false if the Initial is to be
+// decided on natively now.
+fn hold_initial(shared: &Arc<Shared>, from: SocketAddr, dcid: &[u8], token_present: bool, datagram: &[u8]) -> bool {
+    let hook = shared.initial_hook.lock().unwrap();
+    let Some(hook) = hook.as_ref() else {
+        return false;
+    };
+    let deadline = Instant::now() + hook.timeout;
+    match shared.held_initials.lock().unwrap().hold(from, dcid, datagram, deadline) {
+        Hold::New => (),
+        Hold::Joined => return true,
+        Hold::Full => return false,
+    }
+
+    let initial = InitialPacket { peer: from.to_string(), dcid: hex_id(dcid), token_present };
+    let (shared, dcid) = (shared.clone(), dcid.to_vec());
+    hook.callback.call_with_return_value(
+        initial,
+        ThreadsafeFunctionCallMode::NonBlocking,
+        move |answer: CallbackResult| {
+            let decide = move |fulfilled: bool, value: &CallbackResult| {
+                let verdict = value.get::<String>().filter(|_| fulfilled).and_then(|answer| Verdict::parse(&answer));
+                shared.held_initials.lock().unwrap().decide(from, &dcid, verdict);
+            };
+            // Left pending, the Initial gets the native decision at the deadline
+            if let Err(e) = answer.settle(decide) {
+                eprintln!("Failed to read onInitial result: {}", e.reason);
+            }
+            Ok(())
+        },
+    );
+    true
+}
+
+// Whether `version` is one of those reserved to exercise version negotiation
+fn is_reserved_version(version: u32) -> bool {
+    version & 0x0f0f_0f0f == 0x0a0a_0a0a
+}
+
+// Writes a Version Negotiation packet listing `versions` in response to a
+// packet from `scid` to `dcid`, returning its length. quiche's own lists
+// only the versions it supports.
+fn negotiate_version(scid: &[u8], dcid: &[u8], versions: &[u32], out: &mut [u8]) -> usize {
+    let mut packet = vec![0xc0];
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    // The client's source connection ID becomes the destination, and back
+    for cid in [scid, dcid] {
+        packet.push(cid.len() as u8);
+        packet.extend_from_slice(cid);
+    }
+    for version in versions {
+        packet.extend_from_slice(&version.to_be_bytes());
+    }
+    out[..packet.len()].copy_from_slice(&packet);
+    packet.len()
+}
+
+// Records an onAccept answer for the loop to act on
+fn admit(shared: &Shared, key: &quiche::ConnectionId<'static>, accepted: bool, decision: Option<AcceptDecision>) {
+    if let Some(client) = shared.clients.lock().unwrap().get_mut(key) {
+        if let Admission::Pending(_) = client.admission {
+            let decision = decision.unwrap_or(AcceptDecision { accept: None, max_idle_timeout_ms: None });
+            client.admission = Admission::Decided(accepted && decision.accept != Some(false));
+            client.idle_timeout = decision.max_idle_timeout_ms.map(|ms| Duration::from_millis(ms.into()));
+        }
+    }
+}
+
+// Offers each connection's writable streams to the JS scheduler when a round
+// is due. Its answers arrive later on the JS thread and are applied there.
+fn run_scheduler(shared: &Arc<Shared>) {
+    let mut scheduler = shared.scheduler.lock().unwrap();
+    let scheduler = match scheduler.as_mut() {
+        Some(scheduler) if scheduler.last_round.elapsed() >= scheduler.interval => scheduler,
+        _ => return,
+    };
+    scheduler.last_round = Instant::now();
+
+    let clients = shared.clients.lock().unwrap();
+    for (key, client) in clients.iter() {
+        if !client.conn.is_established() {
+            continue;
+        }
+        let stream_ids: Vec<i64> = client.conn.writable().map(|id| id as i64).collect();
+        if stream_ids.is_empty() {
+            continue;
+        }
+
+        let round = ScheduleRound { conn_id: client.id.clone(), stream_ids };
+        let (shared, key) = (shared.clone(), key.clone());
+        scheduler.callback.call_with_return_value(
+            round,
+            ThreadsafeFunctionCallMode::NonBlocking,
+            move |order: CallbackResult| {
+                if let Some(order) = order.get::<Vec<i64>>() {
+                    apply_schedule(&shared, &key, &order);
+                }
+                Ok(())
+            },
+        );
+    }
+}
+
+// Ranks streams by their position in `order`. quiche has eight urgency
+// levels, so everything from the eighth stream on shares the last one.
+fn apply_schedule(shared: &Shared, key: &quiche::ConnectionId<'static>, order: &[i64]) {
+    let mut clients = shared.clients.lock().unwrap();
+    // The connection may have closed while JS was deciding
+    let client = match clients.get_mut(key) {
+        Some(client) => client,
+        None => return,
+    };
+
+    for (rank, &stream_id) in order.iter().enumerate() {
+        if let Ok(stream_id) = u64::try_from(stream_id) {
+            let _ = client.conn.stream_priority(stream_id, rank.min(7) as u8, false);
+        }
+    }
+
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+    flush_egress(shared.socket.as_ref(), &mut client.conn, &mut out);
+}
+
+// The AEAD tag ending a datagram whose first packet has a short header,
+// which then runs to the end of the datagram
+fn short_header_tag(datagram: &[u8]) -> Option<[u8; TAG_LEN]> {
+    if datagram.first()? & 0x80 != 0 || datagram.len() <= TAG_LEN {
+        return None;
+    }
+    let mut tag = [0; TAG_LEN];
+    tag.copy_from_slice(&datagram[datagram.len() - TAG_LEN..]);
+    Some(tag)
+}
+
+// Reports a closed connection to JavaScript before it is dropped
+fn retire(shared: &Shared, conn_id: &[u8], client: &Client, events: &dyn EmitEvent) {
+    println!("Connection {} from {:?} closed", client.id, client.peer);
+    shared.martians.lock().unwrap().closed(conn_id, &client.id, client.peer);
+    events.emit(QuicEvent::closed(&client.id, client.peer, &client.conn));
+    shared.routes.lock().unwrap().remove(&client.id);
+    if let Some(queue) = &client.incoming_streams {
+        queue.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http3::{decode_datagram, encode_datagram};
+    use crate::pending::PendingWrites;
+    use crate::transport::MemoryTransport;
+
+    const SERVER: &str = "192.0.2.1:443";
+    const CLIENT: &str = "192.0.2.2:50000";
+    // Long enough for a handshake under a loaded test runner
+    const DEADLINE: Duration = Duration::from_secs(5);
+
+    // Collects the events the loop would have sent to JavaScript
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<QuicEvent>>);
+
+    impl EmitEvent for Recorder {
+        fn emit(&self, event: QuicEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    impl Recorder {
+        fn any(&self, matches: impl Fn(&QuicEvent) -> bool) -> bool {
+            self.0.lock().unwrap().iter().any(matches)
+        }
+
+        fn count(&self, kind: &str) -> usize {
+            self.0.lock().unwrap().iter().filter(|e| e.kind == kind).count()
+        }
+    }
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn options() -> QuicServerOptions {
+        QuicServerOptions {
+            cert_path: Some(testdata("cert.pem")),
+            key_path: Some(testdata("key.pem")),
+            alpn: Some(vec!["test".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    // run_server on its own thread, stopped when dropped
+    struct TestServer {
+        shared: Arc<Shared>,
+        events: Arc<Recorder>,
+        thread: Option<JoinHandle<napi::Result<()>>>,
+    }
+
+    impl TestServer {
+        fn start(options: QuicServerOptions, socket: MemoryTransport) -> TestServer {
+            let (mut configs, shared) = prepare(&options, None, Arc::default(), Box::new(socket)).unwrap();
+            shared.socket.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+
+            let shared = Arc::new(shared);
+            let events = Arc::new(Recorder::default());
+            let thread = thread::spawn({
+                let shared = shared.clone();
+                let events = events.clone();
+                move || run_server(&mut configs, &shared, events.as_ref())
+            });
+            TestServer { shared, events, thread: Some(thread) }
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            *self.shared.shutdown.lock().unwrap() = Some(Shutdown::Immediate);
+            if let Some(thread) = self.thread.take() {
+                let result = thread.join().expect("packet loop panicked");
+                if !thread::panicking() {
+                    result.expect("packet loop failed");
+                }
+            }
+        }
+    }
+
+    // A quiche client driven from the test thread
+    struct Peer {
+        socket: MemoryTransport,
+        conn: quiche::Connection,
+        local: SocketAddr,
+    }
+
+    impl Peer {
+        fn connect(socket: MemoryTransport, alpn: &[&[u8]]) -> Peer {
+            Peer::connect_offering(socket, alpn, quiche::PROTOCOL_VERSION)
+        }
+
+        fn connect_offering(socket: MemoryTransport, alpn: &[&[u8]], version: u32) -> Peer {
+            let mut config = quiche::Config::new(version).unwrap();
+            config.verify_peer(false);
+            config.set_application_protos(alpn).unwrap();
+            config.set_max_idle_timeout(30_000);
+            config.set_initial_max_data(1_000_000);
+            config.set_initial_max_stream_data_bidi_local(100_000);
+            config.set_initial_max_stream_data_bidi_remote(100_000);
+            config.set_initial_max_streams_bidi(10);
+            config.enable_dgram(true, 10, 10);
+            // Room for the server's HTTP/3 control and QPACK streams
+            if alpn.contains(&&b"h3"[..]) {
+                config.set_initial_max_streams_uni(3);
+                config.set_initial_max_stream_data_uni(1000);
+            }
+
+            let local = socket.local_addr().unwrap();
+            let server = SERVER.parse().unwrap();
+            let scid = quiche::ConnectionId::from_ref(&[0xc1; 16]);
+            let conn = quiche::connect(Some("quic.test"), &scid, local, server, &mut config).unwrap();
+            Peer { socket, conn, local }
+        }
+
+        // Sends whatever the connection has queued, returning the datagrams
+        fn flush(&mut self) -> Vec<Vec<u8>> {
+            let mut out = [0; MAX_DATAGRAM_SIZE];
+            let mut sent = Vec::new();
+            while let Ok((len, info)) = self.conn.send(&mut out) {
+                self.socket.send_to(&out[..len], info.to).unwrap();
+                sent.push(out[..len].to_vec());
+            }
+            sent
+        }
+
+        // Exchanges packets with the server until `done` holds, or fails the test
+        fn run_until(&mut self, what: &str, mut done: impl FnMut(&mut Peer) -> bool) {
+            let deadline = Instant::now() + DEADLINE;
+            let mut buf = [0; RECV_BUFFER_SIZE];
+
+            while !done(self) {
+                assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+                self.flush();
+
+                let wait = self.conn.timeout().unwrap_or(POLL_INTERVAL).min(Duration::from_millis(10));
+                self.socket.set_read_timeout(Some(wait.max(Duration::from_millis(1)))).unwrap();
+                match self.socket.recv_from(&mut buf) {
+                    Ok((len, from)) => {
+                        let info = RecvInfo { from, to: self.local };
+                        let _ = self.conn.recv(&mut buf[..len], info);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("memory transport failed: {}", e),
+                }
+
+                if self.conn.timeout_instant().is_some_and(|at| at <= Instant::now()) {
+                    self.conn.on_timeout();
+                }
+            }
+        }
+
+        // The next flight the server sends within `wait`, kept from the
+        // connection: datagrams until none follows for a POLL_INTERVAL
+        fn intercept(&mut self, wait: Duration) -> Vec<Vec<u8>> {
+            let mut buf = [0; RECV_BUFFER_SIZE];
+            let mut datagrams = Vec::new();
+
+            loop {
+                let timeout = if datagrams.is_empty() { wait } else { POLL_INTERVAL };
+                self.socket.set_read_timeout(Some(timeout)).unwrap();
+                match self.socket.recv_from(&mut buf) {
+                    Ok((len, _)) => datagrams.push(buf[..len].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("memory transport failed: {}", e),
+                }
+            }
+            datagrams
+        }
+
+        fn handshake(&mut self, server: &TestServer) {
+            self.run_until("the handshake", |peer| {
+                peer.conn.is_established() && server.events.count("handshakeDone") > 0
+            });
+        }
+    }
+
+    impl TestServer {
+        // Runs `f` against the server's only connection, as the JS methods do
+        fn with_client<R>(&self, f: impl FnOnce(&mut Client) -> R) -> R {
+            let mut clients = self.shared.clients.lock().unwrap();
+            assert_eq!(clients.len(), 1);
+            let client = clients.values_mut().next().unwrap();
+            let result = f(client);
+            let mut out = [0; MAX_DATAGRAM_SIZE];
+            flush_egress(self.shared.socket.as_ref(), &mut client.conn, &mut out);
+            result
+        }
+    }
+
+    // The client comes first so that it outlives the loop, which treats its
+    // end closing as a failure
+    fn start(options: QuicServerOptions) -> (Peer, TestServer) {
+        start_offering(options, &[b"test"])
+    }
+
+    fn start_offering(options: QuicServerOptions, alpn: &[&[u8]]) -> (Peer, TestServer) {
+        let (server_end, client_end) = MemoryTransport::pair(SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        (Peer::connect(client_end, alpn), TestServer::start(options, server_end))
+    }
+
+    #[test]
+    fn completes_a_handshake_over_memory_transport() {
+        let (mut peer, server) = start(options());
+
+        peer.handshake(&server);
+
+        assert_eq!(peer.conn.application_proto(), b"test");
+        assert!(server.events.any(|e| {
+            e.kind == "handshakeDone"
+                && e.alpn.as_deref() == Some("test")
+                && e.peer.as_deref() == Some(CLIENT)
+        }));
+        assert_eq!(server.events.count("alpnMismatch"), 0);
+    }
+
+    #[test]
+    fn delivers_stream_data_with_fin() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        peer.conn.stream_send(0, b"hello", true).unwrap();
+        peer.run_until("the data event", |_| {
+            server.events.any(|e| {
+                e.kind == "data"
+                    && e.stream_id == Some(0)
+                    && e.fin == Some(true)
+                    && e.data.as_ref().map(|d| &d[..]) == Some(&b"hello"[..])
+            })
+        });
+    }
+
+    #[test]
+    fn sends_writes_held_until_the_handshake() {
+        let (mut peer, server) = start(QuicServerOptions { datagrams: Some(Default::default()), ..options() });
+        let drops = AtomicU64::new(0);
+        let mut pending = PendingWrites::new(1);
+        assert!(pending.holds_stream(&peer.conn, 0) && pending.holds_datagrams(&peer.conn));
+        pending.stream_send(0, b"hello", false).unwrap();
+        pending.stream_send(0, b" world", true).unwrap();
+        assert!(pending.stream_send(0, b"!", false).is_err());
+        assert!(pending.datagram_send(b"early"));
+        assert!(!pending.datagram_send(b"one too many"));
+
+        pending.flush(&mut peer.conn, DatagramPolicy::Queue, &drops);
+        peer.handshake(&server);
+        assert!(!pending.holds_stream(&peer.conn, 4));
+        pending.flush(&mut peer.conn, DatagramPolicy::Queue, &drops);
+        assert!(pending.is_empty());
+
+        peer.run_until("the held writes", |_| {
+            server.events.any(|e| e.kind == "data" && e.fin == Some(true)) && server.events.count("datagram") > 0
+        });
+        assert!(server.events.any(|e| e.kind == "data" && e.data.as_deref() == Some(&b"hello world"[..])));
+    }
+
+    #[test]
+    fn merges_a_burst_into_one_data_event() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        let payload: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        peer.conn.stream_send(0, &payload, true).unwrap();
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        let mut packets = Vec::new();
+        while let Ok((len, _)) = peer.conn.send(&mut out) {
+            packets.push(out[..len].to_vec());
+        }
+        assert!(packets.len() > 1);
+
+        // Everything that reached the connection before it is read, such as
+        // data held while onAccept() answers or a gap filled by a retransmission
+        let events = Recorder::default();
+        server.with_client(|client| {
+            let info = RecvInfo { from: peer.local, to: SERVER.parse().unwrap() };
+            for packet in &mut packets {
+                client.conn.recv(packet, info).unwrap();
+            }
+            let mut buf = [0; RECV_BUFFER_SIZE];
+            read_streams(&mut client.conn, &client.id, client.peer, &mut buf, &events, |_| false);
+        });
+        let data = events.0.into_inner().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].data.as_deref(), Some(&payload[..]));
+        assert_eq!(data[0].fin, Some(true));
+    }
+
+    #[test]
+    fn reports_the_peer_closing() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        peer.conn.close(true, 0x7, b"bye").unwrap();
+        peer.run_until("the closed event", |_| {
+            server.events.any(|e| e.kind == "closed" && e.error_code == Some(0x7))
+        });
+        assert!(server.events.any(|e| e.kind == "closed" && e.reason.as_deref() == Some("bye")));
+    }
+
+    #[test]
+    fn duplicate_datagrams_are_not_undecryptable() {
+        let options = QuicServerOptions {
+            martian_threshold: Some(2),
+            martian_action: Some("reset".to_string()),
+            ..options()
+        };
+        let (mut peer, server) = start(options);
+        peer.handshake(&server);
+
+        peer.conn.stream_send(0, b"hello", false).unwrap();
+        let sent = peer.flush();
+        for datagram in sent.iter().chain(&sent).chain(&sent) {
+            peer.socket.send_to(datagram, SERVER.parse().unwrap()).unwrap();
+        }
+
+        peer.conn.stream_send(0, b" world", true).unwrap();
+        peer.flush();
+        // Replayed after a newer packet, as a reordering network would
+        for datagram in &sent {
+            peer.socket.send_to(datagram, SERVER.parse().unwrap()).unwrap();
+        }
+        peer.run_until("the data event", |_| server.events.any(|e| e.kind == "data" && e.fin == Some(true)));
+
+        assert_eq!(server.events.count("martianPackets"), 0);
+        assert_eq!(server.shared.metrics.undecryptable_datagrams.load(Ordering::Relaxed), 0);
+        assert!(!peer.conn.is_closed());
+    }
+
+    #[test]
+    fn resets_a_connection_sent_undecryptable_packets() {
+        let options = QuicServerOptions {
+            martian_threshold: Some(3),
+            martian_action: Some("reset".to_string()),
+            ..options()
+        };
+        let (mut peer, server) = start(options);
+        peer.handshake(&server);
+
+        for n in 0..3 {
+            // A short header for the connection, then bytes under no key of its
+            let mut datagram = vec![0x40];
+            datagram.extend_from_slice(&peer.conn.destination_id());
+            datagram.extend_from_slice(&[n; 40]);
+            peer.socket.send_to(&datagram, SERVER.parse().unwrap()).unwrap();
+        }
+        // The loop counts the reset once it is sent, so possibly after the peer acts on it
+        let metrics = &server.shared.metrics;
+        peer.run_until("the stateless reset", |peer| {
+            peer.conn.is_closed() && metrics.stateless_resets.load(Ordering::Relaxed) == 1
+        });
+
+        assert!(server.events.any(|e| e.kind == "martianPackets" && e.packets == Some(3)));
+        assert_eq!(metrics.undecryptable_datagrams.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn retransmits_a_lost_handshake_flight_on_pto() {
+        let (mut peer, server) = start(options());
+        peer.flush();
+
+        // Lose the server's first flight, and keep the client quiet so only
+        // the server's probe timeout can bring it back
+        let lost = peer.intercept(DEADLINE);
+        assert!(!lost.is_empty());
+        let resent = peer.intercept(DEADLINE);
+        assert!(!resent.is_empty(), "the server never retransmitted its flight");
+
+        for datagram in resent {
+            let info = RecvInfo { from: SERVER.parse().unwrap(), to: peer.local };
+            let _ = peer.conn.recv(&mut datagram.clone(), info);
+        }
+        peer.handshake(&server);
+
+        assert!(server.events.any(|e| e.kind == "loss" && e.retransmits.is_some_and(|n| n > 0)));
+        assert!(server.shared.metrics.handshake_retransmits.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn replays_held_initials_with_the_verdict_given() {
+        let (mut peer, server) = start(options());
+
+        // Hand the client's first flight over as onInitial would, answered "retry"
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        let (len, _) = peer.conn.send(&mut out).unwrap();
+        let dcid = quiche::Header::from_slice(&mut out[..len], quiche::MAX_CONN_ID_LEN).unwrap().dcid.to_vec();
+        let mut held = server.shared.held_initials.lock().unwrap();
+        assert_eq!(held.hold(peer.local, &dcid, &out[..len], Instant::now() + DEADLINE), Hold::New);
+        held.decide(peer.local, &dcid, Some(Verdict::Retry));
+        drop(held);
+
+        peer.handshake(&server);
+        assert_eq!(server.shared.metrics.retries_sent.load(Ordering::Relaxed), 1);
+        assert_eq!(server.shared.metrics.invalid_tokens.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn negotiates_from_a_greased_version() {
+        let (server_end, client_end) = MemoryTransport::pair(SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let mut peer = Peer::connect_offering(client_end, &[b"test"], 0x1a2a_3a4a);
+        let greased = QuicServerOptions { versions: Some(vec![0x5a6a_7a8a, QUIC_V1]), ..options() };
+        let server = TestServer::start(greased, server_end);
+
+        peer.flush();
+        let negotiation = peer.intercept(DEADLINE);
+        assert_eq!(negotiation.len(), 1);
+        assert_eq!(&negotiation[0][1..5], &[0; 4]);
+        assert!(negotiation[0].ends_with(&[0x5a, 0x6a, 0x7a, 0x8a, 0, 0, 0, 1]));
+
+        let info = RecvInfo { from: SERVER.parse().unwrap(), to: peer.local };
+        peer.conn.recv(&mut negotiation[0].clone(), info).unwrap();
+        peer.handshake(&server);
+    }
+
+    #[test]
+    fn times_phases_when_profiling() {
+        let (mut peer, server) = start(QuicServerOptions { profile_phases: Some(true), ..options() });
+        peer.handshake(&server);
+        peer.conn.stream_send(0, b"ping", true).unwrap();
+        peer.run_until("the data", |_| server.events.count("data") > 0);
+
+        let metrics = server.shared.metrics.snapshot();
+        assert!(metrics.crypto_ns > 0 && metrics.recv_ns > 0 && metrics.send_ns > 0);
+        let conn_recv = server.with_client(|client| client.phases.as_ref().unwrap().nanos(Phase::Recv));
+        assert!(conn_recv > 0 && conn_recv <= server.shared.metrics.snapshot().recv_ns);
+    }
+
+    #[test]
+    fn reports_packets_for_unknown_connections() {
+        let options = QuicServerOptions { unhandled_packet_events: Some(true), ..options() };
+        let (mut peer, server) = start(options);
+        peer.handshake(&server);
+
+        let mut datagram = vec![0x40];
+        datagram.extend_from_slice(&[0xab; 20]);
+        datagram.extend_from_slice(&[0; 30]);
+        peer.socket.send_to(&datagram, SERVER.parse().unwrap()).unwrap();
+
+        peer.run_until("the unhandledPacket event", |_| server.events.count("unhandledPacket") > 0);
+        assert!(server.events.any(|e| {
+            e.kind == "unhandledPacket"
+                && e.packet_type.as_deref() == Some("short")
+                && e.dcid.as_deref() == Some(&"ab".repeat(20)[..])
+                && e.scid.is_none()
+                && e.length == Some(51)
+                && e.peer.as_deref() == Some(CLIENT)
+        }));
+    }
+
+    #[test]
+    fn closes_a_connection_offering_no_supported_alpn() {
+        let (mut peer, server) = start_offering(options(), &[b"other"]);
+        peer.run_until("the handshake to fail", |peer| peer.conn.peer_error().is_some());
+
+        let alert = peer.conn.peer_error().map(|e| e.error_code);
+        assert_eq!(alert, Some(TransportError::CryptoError as u64 + 120));
+        assert!(server.events.any(|e| {
+            e.kind == "alpnMismatch"
+                && e.offered_alpn == Some(vec!["other".to_string()])
+                && e.action.as_deref() == Some("close")
+                && e.alpn.is_none()
+        }));
+    }
+
+    #[test]
+    fn accepts_the_clients_first_alpn_when_asked() {
+        let options = QuicServerOptions { alpn_mismatch: Some("accept".to_string()), ..options() };
+        let (mut peer, server) = start_offering(options, &[b"other", b"another"]);
+        peer.handshake(&server);
+
+        assert_eq!(peer.conn.application_proto(), b"other");
+        assert!(server.events.any(|e| e.kind == "handshakeDone" && e.alpn.as_deref() == Some("other")));
+        assert!(server.events.any(|e| {
+            e.kind == "alpnMismatch"
+                && e.offered_alpn == Some(vec!["other".to_string(), "another".to_string()])
+                && e.action.as_deref() == Some("accept")
+                && e.alpn.as_deref() == Some("other")
+        }));
+    }
+
+    #[test]
+    fn sends_virtual_host_events_to_its_handler() {
+        let (mut peer, server) = start(options());
+        let host = Arc::new(Recorder::default());
+        let identity = identity(Some(&testdata("cert.pem")), None, Some(&testdata("key.pem")), None).unwrap();
+        let config = server_config(&options(), None, identity).unwrap();
+        server.shared.new_hosts.lock().unwrap().push(("quic.test".to_string(), config, host.clone()));
+        // Picked up at the top of the loop, before the client's first packet
+        let deadline = Instant::now() + DEADLINE;
+        while !server.shared.new_hosts.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "timed out waiting for the host to be added");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        peer.run_until("the handshake", |peer| peer.conn.is_established() && host.count("handshakeDone") > 0);
+        peer.conn.stream_send(0, b"hello", true).unwrap();
+        peer.run_until("the data event", |_| host.any(|e| e.kind == "data" && e.fin == Some(true)));
+
+        assert_eq!(host.count("connection"), 1);
+        assert!(!server.events.any(|e| e.conn_id.is_some()));
+
+        peer.conn.close(true, 0, b"").unwrap();
+        peer.run_until("the closed event", |_| host.count("closed") > 0);
+        assert!(server.shared.routes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_amplification_before_the_client_is_validated() {
+        let (mut peer, server) = start(options());
+        peer.flush();
+        // Answered, but the client's Handshake packets never reach the server
+        peer.intercept(DEADLINE);
+
+        let state = server.with_client(Client::blocked_state);
+        assert!(state.amplification_limited);
+        assert!(!state.app_limited);
+    }
+
+    #[test]
+    fn reports_streams_that_can_take_no_more_data() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+        peer.conn.stream_send(0, b"hello", false).unwrap();
+        peer.run_until("the data event", |_| server.events.count("data") > 0);
+
+        let state = server.with_client(Client::blocked_state);
+        assert!(state.app_limited && !state.amplification_limited && state.congestion_window > 0);
+
+        // More than the congestion window has room for
+        let state = server.with_client(|client| {
+            let written = client.conn.stream_send(0, &[0; 100_000], false).unwrap();
+            assert!(written < 100_000);
+            client.note_write(0, false);
+            client.blocked_state()
+        });
+        assert_eq!(state.blocked_streams, vec![0]);
+        assert!(state.connection_blocked && !state.app_limited);
+
+        // Acknowledged and read, the data makes room again
+        peer.run_until("the window to open", |peer| {
+            let mut buf = [0; 65536];
+            while peer.conn.stream_recv(0, &mut buf).is_ok() {}
+            server.with_client(Client::blocked_state).app_limited
+        });
+    }
+
+    #[test]
+    fn closes_connections_past_their_maximum_age() {
+        let options = QuicServerOptions { max_connection_age_ms: Some(200), ..options() };
+        let (mut peer, server) = start(options);
+        peer.handshake(&server);
+
+        peer.run_until("the connection to be closed", |peer| peer.conn.peer_error().is_some());
+        let error = peer.conn.peer_error().unwrap();
+        assert_eq!((error.is_app, error.error_code), (false, TransportError::NoError as u64));
+        assert_eq!(error.reason, b"connection too old");
+    }
+
+    #[test]
+    fn tells_a_finished_stream_from_one_waiting_for_data() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        peer.conn.stream_send(0, b"hello", false).unwrap();
+        peer.run_until("the data event", |_| server.events.count("data") > 0);
+        let state = server.with_client(|client| (client.conn.stream_finished(0), stream_readable_fin(&client.conn, 0)));
+        assert_eq!(state, (false, false));
+
+        peer.conn.stream_send(0, b"", true).unwrap();
+        peer.run_until("the FIN", |_| server.events.any(|e| e.kind == "data" && e.fin == Some(true)));
+        let state = server.with_client(|client| (client.conn.stream_finished(0), stream_readable_fin(&client.conn, 0)));
+        assert_eq!(state, (true, true));
+    }
+
+    #[test]
+    fn refuses_new_streams_while_draining() {
+        let options = QuicServerOptions { drain_stream_error_code: Some(0x42), ..options() };
+        let (mut peer, server) = start(options);
+        peer.handshake(&server);
+        peer.conn.stream_send(0, b"hello", false).unwrap();
+        peer.run_until("the data event", |_| server.events.count("data") > 0);
+
+        *server.shared.drain.lock().unwrap() = Some(Drain { deadline: Instant::now() + DEADLINE, waiters: Vec::new() });
+        peer.run_until("the drain to start", |_| server.with_client(|client| client.winding_down.is_some()));
+
+        peer.conn.stream_send(4, b"late", false).unwrap();
+        peer.run_until("stream 4 to be refused", |peer| {
+            // Both frames arrive together; reading the reset lets quiche drop the stream
+            let stopped = peer.conn.stream_capacity(4) == Err(quiche::Error::StreamStopped(0x42));
+            let mut buf = [0; 16];
+            stopped && peer.conn.stream_recv(4, &mut buf) == Err(quiche::Error::StreamReset(0x42))
+        });
+        assert!(!server.events.any(|e| e.stream_id == Some(4)));
+
+        // The stream opened before the drain still completes, then the connection closes
+        peer.conn.stream_send(0, b" world", true).unwrap();
+        peer.run_until("the FIN", |_| server.events.any(|e| e.kind == "data" && e.fin == Some(true)));
+        assert!(peer.conn.peer_error().is_none());
+        server.with_client(|client| {
+            client.conn.stream_send(0, b"bye", true).unwrap();
+            client.note_write(0, true);
+        });
+        peer.run_until("the connection to be closed", |peer| peer.conn.peer_error().is_some());
+        assert_eq!(peer.conn.peer_error().unwrap().reason, b"server draining");
+    }
+
+    #[test]
+    fn shuts_down_streams_with_an_error_code() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+        peer.conn.stream_send(0, b"hello", false).unwrap();
+        peer.run_until("the data event", |_| server.events.count("data") > 0);
+
+        let code = TransportError::ApplicationError as i64;
+        server.with_client(|client| stream_shutdown(&mut client.conn, 0, "both", code)).unwrap();
+        peer.run_until("stream 0 to be shut", |peer| {
+            let stopped = peer.conn.stream_capacity(0) == Err(quiche::Error::StreamStopped(0x0c));
+            let mut buf = [0; 16];
+            stopped && peer.conn.stream_recv(0, &mut buf) == Err(quiche::Error::StreamReset(0x0c))
+        });
+
+        // Shutting it again, or a stream never opened, is not an error
+        server.with_client(|client| stream_shutdown(&mut client.conn, 0, "write", code)).unwrap();
+        server.with_client(|client| stream_shutdown(&mut client.conn, 8, "read", code)).unwrap();
+        assert!(server.with_client(|client| stream_shutdown(&mut client.conn, 0, "sideways", code)).is_err());
+        assert!(server.with_client(|client| stream_shutdown(&mut client.conn, 0, "read", -1)).is_err());
+    }
+
+    #[test]
+    fn closes_a_connection_once_its_streams_are_done() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+        peer.conn.stream_send(0, b"hello", true).unwrap();
+        peer.run_until("the FIN", |_| server.events.any(|e| e.kind == "data" && e.fin == Some(true)));
+
+        server.with_client(|client| {
+            client.conn.stream_send(0, b"bye", true).unwrap();
+            client.note_write(0, true);
+            client.graceful_close = Some(GracefulClose {
+                deadline: Instant::now() + DEADLINE,
+                application_error: true,
+                error_code: 7,
+                reason: b"done".to_vec(),
+            });
+        });
+
+        // The reply is delivered before the close, which follows its acknowledgement
+        let mut buf = [0; 16];
+        peer.run_until("the reply", |peer| peer.conn.stream_recv(0, &mut buf).is_ok());
+        assert_eq!(&buf[..3], b"bye");
+        peer.run_until("the connection to be closed", |peer| peer.conn.peer_error().is_some());
+        let error = peer.conn.peer_error().unwrap();
+        assert_eq!((error.is_app, error.error_code, error.reason.as_slice()), (true, 7, &b"done"[..]));
+    }
+
+    #[test]
+    fn counts_open_streams() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        for stream_id in [0, 4, 2] {
+            peer.conn.stream_send(stream_id, b"hello", false).unwrap();
+        }
+        peer.run_until("the data events", |_| server.events.count("data") == 3);
+        let counts = server.with_client(|client| {
+            client.conn.stream_send(1, b"hello", false).unwrap();
+            client.note_write(1, false);
+            client.stream_counts()
+        });
+        assert_eq!((counts.peer_bidi, counts.peer_uni, counts.local_bidi, counts.local_uni), (2, 1, 1, 0));
+        // The client allows 10 bidirectional streams and no unidirectional ones
+        assert_eq!((counts.bidi_left, counts.uni_left), (9, 0));
+
+        // Finished both ways, stream 4 is let go of once the client acknowledges
+        server.with_client(|client| {
+            client.conn.stream_send(4, b"", true).unwrap();
+            client.note_write(4, true);
+        });
+        peer.conn.stream_send(4, b"", true).unwrap();
+        peer.run_until("stream 4 to close", |_| server.with_client(Client::stream_counts).peer_bidi == 1);
+    }
+
+    #[test]
+    fn tells_whether_the_peer_accepts_datagrams() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        assert!(server.events.any(|e| e.kind == "datagramSupport"
+            && e.datagrams == Some(true)
+            && e.max_datagram_frame_size == Some(65536)));
+        let writable = server.with_client(|client| client.conn.dgram_max_writable_len()).unwrap();
+        assert!(writable > 0 && writable < 1350, "{}", writable);
+    }
+
+    #[test]
+    fn coalesces_small_writes_into_fewer_packets() {
+        let options = QuicServerOptions { write_coalescing_us: Some(20_000), ..options() };
+        let (mut peer, server) = start(options);
+        peer.handshake(&server);
+
+        let sent = {
+            let mut clients = server.shared.clients.lock().unwrap();
+            let client = clients.values_mut().next().unwrap();
+            let sent = client.conn.stats().sent;
+            for _ in 0..10 {
+                client.stream_send(1, b"x", false).unwrap();
+            }
+            assert!(client.held);
+            sent
+        };
+
+        peer.run_until("the writes", |peer| peer.conn.stream_readable(1));
+        let mut buf = [0; 16];
+        assert_eq!(peer.conn.stream_recv(1, &mut buf), Ok((10, false)));
+        // One packet carries them all, where each would have had its own
+        let sent = server.with_client(|client| client.conn.stats().sent) - sent;
+        assert!(sent < 3, "{}", sent);
+    }
+
+    #[test]
+    fn traces_a_live_connection_on_demand() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        let path = std::env::temp_dir().join(format!("quiche-node-bindings-{}.sqlog", std::process::id()));
+        let started = server.with_client(|client| start_qlog(&mut client.conn, &path, &client.id, "server"));
+        if !cfg!(feature = "qlog") {
+            assert_eq!(started.unwrap_err().kind(), io::ErrorKind::Unsupported);
+            return;
+        }
+        started.unwrap();
+
+        peer.conn.stream_send(0, b"hello", true).unwrap();
+        peer.run_until("the data event", |_| server.events.any(|e| e.kind == "data" && e.fin == Some(true)));
+        server.with_client(|client| stop_qlog(&mut client.conn));
+
+        let trace = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(trace.first(), Some(&0x1e), "not a JSON-SEQ trace");
+        assert!(trace.windows(11).any(|w| w == b"packet_sent"));
+    }
+
+    #[test]
+    fn answers_431_to_requests_over_the_header_limits() {
+        let settings = Http3Settings { max_request_headers: Some(6), ..Default::default() };
+        let options = QuicServerOptions {
+            alpn: Some(vec!["h3".to_string()]),
+            http3: Some(HashMap::from([("h3".to_string(), settings)])),
+            ..options()
+        };
+        let (mut peer, server) = start_offering(options, &[b"h3"]);
+        peer.handshake(&server);
+        let config = quiche::h3::Config::new().unwrap();
+        let mut h3 = quiche::h3::Connection::with_transport(&mut peer.conn, &config).unwrap();
+
+        let mut headers = vec![
+            quiche::h3::Header::new(b":method", b"GET"),
+            quiche::h3::Header::new(b":scheme", b"https"),
+            quiche::h3::Header::new(b":authority", b"quic.test"),
+            quiche::h3::Header::new(b":path", b"/"),
+        ];
+        let small = h3.send_request(&mut peer.conn, &headers, true).unwrap();
+        headers.extend((0..3).map(|_| quiche::h3::Header::new(b"x-filler", b"1")));
+        let large = h3.send_request(&mut peer.conn, &headers, true).unwrap();
+
+        let mut status = None;
+        peer.run_until("the 431 response", |peer| {
+            while let Ok((stream_id, event)) = h3.poll(&mut peer.conn) {
+                if let (true, quiche::h3::Event::Headers { list, .. }) = (stream_id == large, event) {
+                    status = list.iter().map(HttpHeader::from).find(|h| h.name == ":status").map(|h| h.value);
+                }
+            }
+            status.is_some()
+        });
+
+        assert_eq!(status.as_deref(), Some("431"));
+        assert!(server.events.any(|e| e.kind == "request" && e.stream_id == Some(small as i64)));
+        assert!(!server.events.any(|e| e.kind == "request" && e.stream_id == Some(large as i64)));
+        assert!(server.events.any(|e| e.kind == "requestRejected" && e.status == Some(431)));
+        assert_eq!(server.shared.metrics.h3.oversized_request_heads.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn accepts_protocol_only_under_extended_connect() {
+        for extended_connect in [false, true] {
+            let settings = Http3Settings { extended_connect: Some(extended_connect), ..Default::default() };
+            let options = QuicServerOptions {
+                alpn: Some(vec!["h3".to_string()]),
+                http3: Some(HashMap::from([("h3".to_string(), settings)])),
+                ..options()
+            };
+            let (mut peer, server) = start_offering(options, &[b"h3"]);
+            peer.handshake(&server);
+            let config = quiche::h3::Config::new().unwrap();
+            let mut h3 = quiche::h3::Connection::with_transport(&mut peer.conn, &config).unwrap();
+
+            let request = |method: &[u8], path: Option<&[u8]>| {
+                let mut headers = vec![
+                    quiche::h3::Header::new(b":method", method),
+                    quiche::h3::Header::new(b":protocol", b"websocket"),
+                    quiche::h3::Header::new(b":scheme", b"https"),
+                    quiche::h3::Header::new(b":authority", b"quic.test"),
+                ];
+                headers.extend(path.map(|path| quiche::h3::Header::new(b":path", path)));
+                headers
+            };
+            let tunnel = h3.send_request(&mut peer.conn, &request(b"CONNECT", Some(b"/chat")), false).unwrap();
+            let get = h3.send_request(&mut peer.conn, &request(b"GET", Some(b"/chat")), true).unwrap();
+            let pathless = h3.send_request(&mut peer.conn, &request(b"CONNECT", None), false).unwrap();
+            let answered = || server.events.count("request") + server.events.count("requestRejected");
+            peer.run_until("the requests", |_| answered() == 3);
+
+            let rejected = |stream_id: u64, reason: &str| {
+                server.events.any(|e| {
+                    e.kind == "requestRejected"
+                        && e.stream_id == Some(stream_id as i64)
+                        && e.reason.as_deref() == Some(reason)
+                })
+            };
+            let accepted = server.events.any(|e| e.kind == "request" && e.stream_id == Some(tunnel as i64));
+            assert_eq!(accepted, extended_connect);
+            if extended_connect {
+                assert!(rejected(get, ":protocol on a method other than CONNECT"));
+                assert!(rejected(pathless, "extended CONNECT without :scheme, :path and :authority"));
+            } else {
+                for stream_id in [tunnel, get, pathless] {
+                    assert!(rejected(stream_id, ":protocol without extended CONNECT"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn switches_datagrams_per_alpn() {
+        let options = || QuicServerOptions {
+            alpn: Some(vec!["h3".to_string(), "test".to_string()]),
+            http3: Some(HashMap::from([(
+                "h3".to_string(),
+                Http3Settings { datagrams: Some(false), ..Default::default() },
+            )])),
+            datagrams: Some(Default::default()),
+            ..options()
+        };
+        for (alpn, datagrams) in [(&b"h3"[..], false), (b"test", true)] {
+            let (mut peer, server) = start_offering(options(), &[alpn]);
+            peer.handshake(&server);
+            assert_eq!(peer.conn.dgram_max_writable_len().is_some(), datagrams);
+            assert_eq!(server.with_client(|client| client.h3.is_some()), alpn == b"h3");
+        }
+    }
+
+    #[test]
+    fn counts_responses_by_status_class_and_method() {
+        let options = QuicServerOptions { alpn: Some(vec!["h3".to_string()]), ..options() };
+        let (mut peer, server) = start_offering(options, &[b"h3"]);
+        peer.handshake(&server);
+        let config = quiche::h3::Config::new().unwrap();
+        let mut h3 = quiche::h3::Connection::with_transport(&mut peer.conn, &config).unwrap();
+
+        let request = |method: &[u8]| {
+            vec![
+                quiche::h3::Header::new(b":method", method),
+                quiche::h3::Header::new(b":scheme", b"https"),
+                quiche::h3::Header::new(b":authority", b"quic.test"),
+                quiche::h3::Header::new(b":path", b"/"),
+            ]
+        };
+        let streams = [
+            h3.send_request(&mut peer.conn, &request(b"GET"), true).unwrap(),
+            h3.send_request(&mut peer.conn, &request(b"GET"), true).unwrap(),
+            h3.send_request(&mut peer.conn, &request(b"BREW"), true).unwrap(),
+        ];
+        peer.run_until("the requests", |_| server.events.count("request") == 3);
+
+        // As sendResponse() and sendBody() do
+        let metrics = &server.shared.metrics.h3;
+        let answers = [(streams[0], "200", &b"hello"[..]), (streams[1], "404", b""), (streams[2], "418", b"")];
+        for (stream_id, status, body) in answers {
+            server.with_client(|client| {
+                let headers = [HttpHeader { name: ":status".to_string(), value: status.to_string() }];
+                let h3 = client.h3.as_mut().unwrap();
+                h3.send_response(&mut client.conn, stream_id, &to_h3_headers(&headers), false).unwrap();
+                client.requests.note_response(stream_id, &headers);
+                let written = send_h3_body(h3, &mut client.conn, stream_id, body, true).unwrap();
+                client.requests.note_body(stream_id, written, true, metrics);
+            });
+        }
+
+        let responses = server.shared.metrics.snapshot().responses;
+        let find = |class: &str, method: &str| {
+            responses.iter().find(|r| r.status_class == class && r.method == method).map(|r| (r.count, r.body_bytes))
+        };
+        assert_eq!(responses.len(), 3);
+        assert_eq!(find("2xx", "GET"), Some((1, 5)));
+        assert_eq!(find("4xx", "GET"), Some((1, 0)));
+        assert_eq!(find("4xx", "OTHER"), Some((1, 0)));
+        assert!(responses.iter().all(|r| r.max_duration_ms > 0.0 && r.total_duration_ms >= r.max_duration_ms));
+    }
+
+    #[test]
+    fn answers_413_and_408_to_requests_over_the_body_limits() {
+        let settings = Http3Settings {
+            max_request_body_bytes: Some(4),
+            request_body_timeout_ms: Some(200),
+            ..Default::default()
+        };
+        let options = QuicServerOptions {
+            alpn: Some(vec!["h3".to_string()]),
+            http3: Some(HashMap::from([("h3".to_string(), settings)])),
+            ..options()
+        };
+        let (mut peer, server) = start_offering(options, &[b"h3"]);
+        peer.handshake(&server);
+        let config = quiche::h3::Config::new().unwrap();
+        let mut h3 = quiche::h3::Connection::with_transport(&mut peer.conn, &config).unwrap();
+
+        let headers = [
+            quiche::h3::Header::new(b":method", b"POST"),
+            quiche::h3::Header::new(b":scheme", b"https"),
+            quiche::h3::Header::new(b":authority", b"quic.test"),
+            quiche::h3::Header::new(b":path", b"/"),
+        ];
+        let large = h3.send_request(&mut peer.conn, &headers, false).unwrap();
+        h3.send_body(&mut peer.conn, large, b"too long", true).unwrap();
+        let stalled = h3.send_request(&mut peer.conn, &headers, false).unwrap();
+        h3.send_body(&mut peer.conn, stalled, b"ok", false).unwrap();
+
+        let mut statuses = HashMap::new();
+        peer.run_until("both responses", |peer| {
+            while let Ok((stream_id, event)) = h3.poll(&mut peer.conn) {
+                if let quiche::h3::Event::Headers { list, .. } = event {
+                    let status = list.iter().map(HttpHeader::from).find(|h| h.name == ":status").map(|h| h.value);
+                    statuses.insert(stream_id, status.unwrap());
+                }
+            }
+            statuses.len() == 2
+        });
+
+        assert_eq!((statuses[&large].as_str(), statuses[&stalled].as_str()), ("413", "408"));
+        assert!(server.events.any(|e| e.kind == "requestRejected" && e.status == Some(413)));
+        assert!(server.events.any(|e| e.kind == "requestRejected" && e.status == Some(408)));
+        let metrics = &server.shared.metrics.h3;
+        assert_eq!(metrics.oversized_request_bodies.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.request_body_timeouts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drops_datagrams_while_congested() {
+        let datagrams = DatagramOptions {
+            policy: Some("drop-when-congested".to_string()),
+            congested_fraction: Some(0.5),
+            ..Default::default()
+        };
+        let (mut peer, server) = start(QuicServerOptions { datagrams: Some(datagrams), ..options() });
+        peer.handshake(&server);
+        let policy = server.shared.datagram_policy;
+        let drops = &server.shared.metrics.congestion_dropped_datagrams;
+
+        assert!(server.with_client(|client| send_datagram(&mut client.conn, b"calm", policy, drops)).unwrap());
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+
+        // Fill the window with stream data the peer never gets to acknowledge
+        server.with_client(|client| client.conn.stream_send(1, &[0; 100_000], false)).unwrap();
+        assert!(server.with_client(|client| send_datagram(&mut client.conn, b"busy", policy, drops)).unwrap());
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        assert_eq!(server.with_client(|client| client.conn.dgram_send_queue_len()), 0);
+    }
+
+    #[test]
+    fn carries_http_datagrams_for_request_streams() {
+        let options = QuicServerOptions {
+            alpn: Some(vec!["h3".to_string()]),
+            datagrams: Some(DatagramOptions::default()),
+            ..options()
+        };
+        let (mut peer, server) = start_offering(options, &[b"h3"]);
+        peer.handshake(&server);
+        let config = quiche::h3::Config::new().unwrap();
+        let mut h3 = quiche::h3::Connection::with_transport(&mut peer.conn, &config).unwrap();
+
+        let headers = [
+            quiche::h3::Header::new(b":method", b"GET"),
+            quiche::h3::Header::new(b":scheme", b"https"),
+            quiche::h3::Header::new(b":authority", b"quic.test"),
+            quiche::h3::Header::new(b":path", b"/"),
+        ];
+        let stream_id = h3.send_request(&mut peer.conn, &headers, false).unwrap();
+        peer.run_until("the request", |_| server.events.count("request") == 1);
+
+        peer.conn.dgram_send(&encode_datagram(stream_id, b"ping").unwrap()).unwrap();
+        peer.run_until("the datagram", |_| {
+            server.events.any(|e| {
+                e.kind == "datagram"
+                    && e.stream_id == Some(stream_id as i64)
+                    && e.data.as_ref().map(|d| &d[..]) == Some(&b"ping"[..])
+            })
+        });
+
+        let queued = server.with_client(|client| {
+            let (h3, drops) = (client.h3.as_ref().unwrap(), AtomicU64::new(0));
+            send_request_datagram(h3, &mut client.conn, stream_id, b"pong", DatagramPolicy::Queue, &drops)
+        });
+        assert!(queued.unwrap());
+        let mut buf = [0; 64];
+        peer.run_until("the reply", |peer| {
+            while let Ok((_, _)) = h3.poll(&mut peer.conn) {}
+            peer.conn.dgram_recv_queue_len() > 0
+        });
+        let len = peer.conn.dgram_recv(&mut buf).unwrap();
+        assert_eq!(decode_datagram(&buf[..len]), Some((stream_id, &b"pong"[..])));
+
+        // One too short for its quarter stream ID closes the connection
+        peer.conn.dgram_send(&[0x40]).unwrap();
+        peer.run_until("the close", |peer| peer.conn.is_closed() || peer.conn.is_draining());
+        assert_eq!(peer.conn.peer_error().map(|e| e.error_code), Some(H3Error::DatagramError as u64));
+    }
+
+    #[test]
+    fn closes_connections_past_their_own_idle_timeout() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        server.with_client(|client| client.idle_timeout = Some(Duration::from_millis(20)));
+        peer.run_until("the idle close", |peer| peer.conn.peer_error().is_some());
+        let error = peer.conn.peer_error().unwrap();
+        assert_eq!((error.error_code, &error.reason[..]), (TransportError::NoError as u64, &b"idle timeout"[..]));
+    }
+
+    #[test]
+    fn applies_an_updated_config_to_new_connections() {
+        let (mut peer, server) = start(options());
+
+        let mut updated = options();
+        updated.config.get_or_insert_with(QuicConfig::default).merge(QuicConfig {
+            initial_max_streams_bidi: Some(3),
+            ..Default::default()
+        });
+        let identity = identity(Some(&testdata("cert.pem")), None, Some(&testdata("key.pem")), None).unwrap();
+        let config = server_config(&updated, None, identity).unwrap();
+        server.shared.reloads.lock().unwrap().push((None, config));
+        let deadline = Instant::now() + DEADLINE;
+        while !server.shared.reloads.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "timed out waiting for the configuration to be replaced");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        peer.handshake(&server);
+        assert_eq!(peer.conn.peer_streams_left_bidi(), 3);
+    }
+
+    #[test]
+    fn accounts_for_partial_messages_in_memory_usage() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+        let before = memory_usage(&server.shared);
+        assert_eq!((before.connection_count, before.reassembly), (1, 0));
+        assert!(before.connections > 0);
+
+        server.with_client(|client| client.framers.set(0, Framing::Ndjson, None).unwrap());
+        peer.conn.stream_send(0, b"{\"partial\"", false).unwrap();
+        peer.run_until("the partial message", |_| memory_usage(&server.shared).reassembly > 0);
+        let after = memory_usage(&server.shared);
+        assert_eq!(after.reassembly, 10);
+        assert_eq!(after.total, after.connections + after.pending_writes + after.reassembly + after.token_caches);
+    }
+
+    #[test]
+    fn digests_what_a_stream_delivers() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+        server.with_client(|client| client.digests.start(0, "sha256").unwrap());
+
+        peer.conn.stream_send(0, b"hello", false).unwrap();
+        peer.run_until("the first chunk", |_| server.events.count("data") == 1);
+        peer.conn.stream_send(0, b" world", true).unwrap();
+        peer.run_until("the FIN", |_| server.events.any(|e| e.kind == "data" && e.fin == Some(true)));
+
+        let expected = hex_id(ring::digest::digest(&ring::digest::SHA256, b"hello world").as_ref());
+        assert!(server.events.any(|e| e.fin == Some(true) && e.digest.as_deref() == Some(expected.as_str())));
+        assert!(!server.events.any(|e| e.fin == Some(false) && e.digest.is_some()));
+    }
+
+    #[test]
+    fn frames_messages_on_a_stream() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+        server.with_client(|client| {
+            client.framers.set(0, Framing::Ndjson, None).unwrap();
+            client.framers.set(4, Framing::Length, Some(8)).unwrap();
+        });
+
+        peer.conn.stream_send(0, b"{\"a\":1}\n{\"b\"", false).unwrap();
+        peer.run_until("the first message", |_| server.events.count("message") == 1);
+        peer.conn.stream_send(0, b":2}\n", true).unwrap();
+        peer.run_until("the end of the stream", |_| {
+            server.events.any(|e| e.kind == "data" && e.stream_id == Some(0) && e.fin == Some(true))
+        });
+        let framed = |data: &[u8]| {
+            server.events.any(|e| {
+                e.kind == "message"
+                    && e.framing.as_deref() == Some("ndjson")
+                    && e.data.as_ref().map(|d| &d[..]) == Some(data)
+            })
+        };
+        assert!(framed(b"{\"a\":1}") && framed(b"{\"b\":2}"));
+        assert!(!server.events.any(|e| e.kind == "data" && e.stream_id == Some(0) && e.fin == Some(false)));
+
+        // Over maxMessageBytes fails the stream
+        peer.conn.stream_send(4, b"\0\0\0\x02hi\0\0\0\x09", false).unwrap();
+        peer.run_until("the framing error", |_| {
+            server.events.any(|e| e.kind == "error" && e.stream_id == Some(4))
+        });
+        assert!(server.events.any(|e| {
+            e.kind == "message" && e.stream_id == Some(4) && e.data.as_ref().map(|d| &d[..]) == Some(&b"hi"[..])
+        }));
+
+        let queued = server.with_client(|client| {
+            let (framers, conn) = (&mut client.framers, &mut client.conn);
+            framers.send(conn, 0, b"{\"c\":3}", true).unwrap()
+        });
+        assert_eq!(queued, 0);
+        peer.run_until("the reply", |peer| peer.conn.stream_readable(0));
+        let mut buf = [0; 16];
+        assert_eq!(peer.conn.stream_recv(0, &mut buf), Ok((8, true)));
+        assert_eq!(&buf[..8], b"{\"c\":3}\n");
+    }
+
+    #[test]
+    fn summarizes_connections_as_json() {
+        let (mut peer, server) = start(options());
+        peer.handshake(&server);
+
+        let (id, json) = server.with_client(|client| (client.id.clone(), client.status().finish()));
+        assert!(json.starts_with(&format!(r#"{{"connId":"{}","peer":"{}","state":"established""#, id, peer.local)));
+        assert!(json.contains(r#""streams":{"peerBidi":0,"#), "{}", json);
+    }
+}
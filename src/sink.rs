@@ -0,0 +1,239 @@
+use napi::{Env, JsDeferred};
+use napi_derive::napi;
+use quiche::h3;
+use ring::digest;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use crate::error_codes::H3Error;
+
+// Chunks buffered between the packet loop and a writer thread. Once they are
+// all in use the loop stops reading the stream, so flow control pushes back
+// on the peer until the disk catches up.
+const QUEUE_DEPTH: usize = 8;
+
+/// Options for `QuicServer.pipeToFile()`.
+#[napi(object)]
+pub struct PipeOptions {
+    /// Abort with an error, and stop reading the stream, once more than this
+    /// many bytes arrive.
+    pub max_bytes: Option<i64>,
+}
+
+/// What `QuicServer.pipeToFile()` resolves with once the stream has ended.
+#[napi(object)]
+pub struct PipeResult {
+    pub bytes: i64,
+    /// Hex-encoded SHA-256 of everything written.
+    pub sha256: String,
+}
+
+type PipeDeferred = JsDeferred<PipeResult, Box<dyn FnOnce(Env) -> napi::Result<PipeResult> + Send>>;
+
+enum Chunk {
+    Data(Vec<u8>),
+    // The stream ended with FIN
+    End,
+    Failed(String),
+}
+
+// The packet loop's end of a stream being written to a file
+struct FileSink {
+    sender: SyncSender<Chunk>,
+    // Read from the stream but not yet accepted by the writer
+    pending: Option<Chunk>,
+    received: u64,
+    max_bytes: Option<u64>,
+    // FIN has been read, and the end marker handed over, respectively
+    fin: bool,
+    ended: bool,
+}
+
+impl FileSink {
+    // Hands the pending chunk to the writer. Returns false while it has no room.
+    fn flush(&mut self) -> Result<bool, ()> {
+        match self.pending.take() {
+            None => Ok(true),
+            Some(chunk) => match self.sender.try_send(chunk) {
+                Ok(()) => Ok(true),
+                Err(TrySendError::Full(chunk)) => {
+                    self.pending = Some(chunk);
+                    Ok(false)
+                }
+                // The writer failed and has already rejected the promise
+                Err(TrySendError::Disconnected(_)) => Err(()),
+            },
+        }
+    }
+}
+
+// Streams of one connection whose data goes to files instead of JavaScript
+#[derive(Default)]
+pub(crate) struct Sinks {
+    sinks: HashMap<u64, FileSink>,
+}
+
+impl Sinks {
+    // Bytes read from the streams but not yet handed to their writers
+    pub(crate) fn pending_bytes(&self) -> usize {
+        let pending = self.sinks.values().filter_map(|sink| sink.pending.as_ref());
+        pending.map(|chunk| if let Chunk::Data(data) = chunk { data.len() } else { 0 }).sum()
+    }
+
+    pub(crate) fn contains(&self, stream_id: u64) -> bool {
+        self.sinks.contains_key(&stream_id)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    // Creates `path` and starts writing whatever the stream receives from
+    // now on to it. Returns the promise for its PipeResult.
+    pub(crate) fn open(
+        &mut self,
+        env: Env,
+        stream_id: u64,
+        path: PathBuf,
+        max_bytes: Option<u64>,
+    ) -> napi::Result<napi::JsObject> {
+        if self.contains(stream_id) {
+            return Err(napi::Error::from_reason(format!("Stream {} is already piped to a file", stream_id)));
+        }
+
+        let file = File::create(&path)
+            .map_err(|e| napi::Error::from_reason(format!("Failed to create {}: {}", path.display(), e)))?;
+        let (deferred, promise) = env.create_deferred()?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+
+        thread::Builder::new()
+            .name("quic-file-sink".into())
+            .spawn(move || write_file(file, path, receiver, deferred))
+            .map_err(|e| napi::Error::from_reason(format!("Failed to start file writer: {}", e)))?;
+
+        let sink = FileSink { sender, pending: None, received: 0, max_bytes, fin: false, ended: false };
+        self.sinks.insert(stream_id, sink);
+        Ok(promise)
+    }
+
+    // Moves received data into the files, as far as their writers keep up.
+    // HTTP/3 streams are read through `h3` so only body data is written.
+    pub(crate) fn pump(
+        &mut self,
+        conn: &mut quiche::Connection,
+        mut h3: Option<&mut h3::Connection>,
+        buf: &mut [u8],
+    ) {
+        let is_h3 = h3.is_some();
+
+        self.sinks.retain(|&stream_id, sink| loop {
+            match sink.flush() {
+                Ok(true) if sink.ended => return false,
+                Ok(true) => (),
+                Ok(false) => return true,
+                Err(()) => {
+                    stop_reading(conn, stream_id, is_h3);
+                    return false;
+                }
+            }
+
+            if sink.fin {
+                sink.pending = Some(Chunk::End);
+                sink.ended = true;
+                continue;
+            }
+
+            let read = match h3.as_deref_mut() {
+                Some(h3) => h3.recv_body(conn, stream_id, buf).map(|len| (len, false)).map_err(|e| match e {
+                    h3::Error::Done => None,
+                    e => Some(format!("{:?}", e)),
+                }),
+                None => conn.stream_recv(stream_id, buf).map_err(|e| match e {
+                    quiche::Error::Done => None,
+                    e => Some(format!("{:?}", e)),
+                }),
+            };
+
+            let (len, fin) = match read {
+                Ok(read) => read,
+                Err(None) => return true,
+                Err(Some(reason)) => {
+                    let _ = sink.sender.try_send(Chunk::Failed(reason));
+                    return false;
+                }
+            };
+
+            sink.received += len as u64;
+            if sink.max_bytes.is_some_and(|max| sink.received > max) {
+                let _ = sink.sender.try_send(Chunk::Failed("maxBytes exceeded".to_string()));
+                stop_reading(conn, stream_id, is_h3);
+                return false;
+            }
+
+            sink.pending = Some(Chunk::Data(buf[..len].to_vec()));
+            sink.fin = fin;
+        });
+    }
+
+    // Ends a piped HTTP/3 body, which quiche reports as an event rather than
+    // through recv_body(). Data still queued is written first.
+    pub(crate) fn finish(&mut self, stream_id: u64) {
+        if let Some(sink) = self.sinks.get_mut(&stream_id) {
+            sink.fin = true;
+        }
+    }
+
+    pub(crate) fn fail(&mut self, stream_id: u64, reason: String) {
+        if let Some(sink) = self.sinks.remove(&stream_id) {
+            let _ = sink.sender.try_send(Chunk::Failed(reason));
+        }
+    }
+}
+
+// Tells the peer to stop sending on a stream whose sink gave up
+fn stop_reading(conn: &mut quiche::Connection, stream_id: u64, is_h3: bool) {
+    let code = if is_h3 { H3Error::RequestCancelled as u64 } else { 0 };
+    let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, code);
+}
+
+// Runs on the sink's own thread so disk writes never stall the packet loop.
+// A sink dropped before the stream ended (say, because the connection
+// closed) rejects the promise. Files of failed pipes are removed.
+fn write_file(mut file: File, path: PathBuf, chunks: Receiver<Chunk>, deferred: PipeDeferred) {
+    let mut hash = digest::Context::new(&digest::SHA256);
+    let mut bytes = 0u64;
+
+    let outcome = loop {
+        match chunks.recv() {
+            Ok(Chunk::Data(data)) => {
+                if let Err(e) = file.write_all(&data) {
+                    break Err(format!("Failed to write {}: {}", path.display(), e));
+                }
+                hash.update(&data);
+                bytes += data.len() as u64;
+            }
+            Ok(Chunk::End) => match file.sync_all() {
+                Ok(()) => break Ok(()),
+                Err(e) => break Err(format!("Failed to write {}: {}", path.display(), e)),
+            },
+            Ok(Chunk::Failed(reason)) => break Err(reason),
+            Err(_) => break Err("Stream closed before it finished".to_string()),
+        }
+    };
+
+    match outcome {
+        Ok(()) => {
+            let sha256 = hash.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+            deferred.resolve(Box::new(move |_| Ok(PipeResult { bytes: bytes as i64, sha256 })));
+        }
+        Err(reason) => {
+            drop(file);
+            let _ = fs::remove_file(&path);
+            deferred.reject(napi::Error::from_reason(reason));
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use napi::bindgen_prelude::{Buffer, Either};
+use napi_derive::napi;
+use ring::aead::{self, quic, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// RFC 9001, section 5.2
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f,
+    0x0a,
+];
+
+// ClientHellos longer than this, split across more datagrams, needing more
+// bytes kept, or left unfinished for longer, are served with the default
+// certificate
+const MAX_HELLO_LEN: usize = 16384;
+const MAX_HELLO_DATAGRAMS: usize = 16;
+const MAX_KEPT_BYTES: usize = 32768;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+// Peers whose ClientHello is being reassembled at once, and the bytes kept
+// for all of them
+const MAX_PENDING: usize = 4096;
+const MAX_TOTAL_KEPT_BYTES: usize = 16 << 20;
+
+/// A certificate served to clients that ask for `serverName` through SNI.
+#[napi(object)]
+pub struct ServerCertificate {
+    /// Host name the certificate is for. `*.example.com` matches any single
+    /// label in front of `example.com`.
+    pub server_name: String,
+    /// Files or content, as for the server's own certificate.
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub cert: Option<Either<String, Buffer>>,
+    pub key: Option<Either<String, Buffer>>,
+}
+
+// What is known about a new connection's ClientHello
+pub(crate) enum Hello {
+    // Complete (or unreadable, in which case it is None); carries the
+    // earlier datagrams that held the start of it, oldest first
+    Done(Option<ClientHello>, Vec<Vec<u8>>),
+    // More Initials are needed; the datagram has been kept
+    Incomplete,
+}
+
+// What the server picks a configuration by, read from a ClientHello
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ClientHello {
+    pub(crate) server_name: Option<String>,
+    // Protocols offered in the ALPN extension, in the client's order
+    pub(crate) alpn: Vec<Vec<u8>>,
+}
+
+// The part of a ClientHello received so far from one peer
+struct Pending {
+    dcid: Vec<u8>,
+    crypto: Vec<u8>,
+    // CRYPTO data that arrived ahead of `crypto`, by offset
+    ahead: Vec<(u64, Vec<u8>)>,
+    datagrams: Vec<Vec<u8>>,
+    started: Instant,
+}
+
+// Reads the server name and offered protocols from ClientHellos before a
+// connection is accepted, which quiche offers no hook for, by opening
+// Initial packets with the keys anyone can derive from their DCID (RFC
+// 9001, section 5)
+#[derive(Default)]
+pub(crate) struct ClientHellos {
+    pending: HashMap<SocketAddr, Pending>,
+    // Sum of every Pending's `kept`
+    kept: usize,
+}
+
+impl ClientHellos {
+    // Bytes kept for ClientHellos not yet complete
+    pub(crate) fn kept(&self) -> usize {
+        self.kept
+    }
+
+    // Adds a datagram that starts with an Initial packet from `from`
+    pub(crate) fn offer(&mut self, from: SocketAddr, dcid: &[u8], datagram: &[u8]) -> Hello {
+        let frames = match open_initial(datagram).and_then(|payload| crypto_frames(&payload)) {
+            Some(frames) => frames,
+            None => return Hello::Done(None, self.take(from, dcid)),
+        };
+
+        if !self.pending.contains_key(&from) && self.pending.len() >= MAX_PENDING {
+            self.expire();
+            if self.pending.len() >= MAX_PENDING {
+                return Hello::Done(None, Vec::new());
+            }
+        }
+
+        let pending = self.pending.entry(from).or_insert_with(|| Pending::new(dcid));
+        // A new attempt from the same address starts over
+        if pending.dcid != dcid || pending.started.elapsed() >= HELLO_TIMEOUT {
+            self.kept -= pending.kept();
+            *pending = Pending::new(dcid);
+        }
+
+        // CRYPTO data past the largest ClientHello accepted can never be used
+        let frames: Vec<_> = frames.into_iter().filter(|(offset, _)| *offset < MAX_HELLO_LEN as u64).collect();
+        let adding = datagram.len() + frames.iter().map(|(_, data)| data.len()).sum::<usize>();
+        let over_limit = pending.datagrams.len() + 1 >= MAX_HELLO_DATAGRAMS
+            || pending.kept() + adding > MAX_KEPT_BYTES
+            || self.kept + adding > MAX_TOTAL_KEPT_BYTES;
+
+        let before = pending.kept();
+        pending.ahead.extend(frames);
+        pending.assemble();
+        self.kept = self.kept - before + pending.kept();
+
+        // The ClientHello once it is complete, or None (and the default
+        // certificate) once it cannot be
+        let done = match hello_len(&pending.crypto) {
+            Some(len) if len <= MAX_HELLO_LEN && pending.crypto.len() >= len => {
+                Some(read_hello(&pending.crypto[..len]))
+            }
+            Some(len) if len > MAX_HELLO_LEN => Some(None),
+            _ if over_limit => Some(None),
+            _ => None,
+        };
+
+        match done {
+            Some(hello) => Hello::Done(hello, self.take(from, dcid)),
+            None => {
+                pending.datagrams.push(datagram.to_vec());
+                self.kept += datagram.len();
+                Hello::Incomplete
+            }
+        }
+    }
+
+    // Forgets `from`, returning the datagrams kept for it
+    fn take(&mut self, from: SocketAddr, dcid: &[u8]) -> Vec<Vec<u8>> {
+        let Some(pending) = self.pending.remove(&from) else {
+            return Vec::new();
+        };
+        self.kept -= pending.kept();
+        if pending.dcid == dcid {
+            pending.datagrams
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Forgets the peers whose ClientHello has timed out
+    fn expire(&mut self) {
+        let kept = &mut self.kept;
+        self.pending.retain(|_, p| {
+            let live = p.started.elapsed() < HELLO_TIMEOUT;
+            if !live {
+                *kept -= p.kept();
+            }
+            live
+        });
+    }
+}
+
+impl Pending {
+    fn new(dcid: &[u8]) -> Self {
+        Pending {
+            dcid: dcid.to_vec(),
+            crypto: Vec::new(),
+            ahead: Vec::new(),
+            datagrams: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    // Bytes held for this peer
+    fn kept(&self) -> usize {
+        let ahead: usize = self.ahead.iter().map(|(_, data)| data.len()).sum();
+        let datagrams: usize = self.datagrams.iter().map(Vec::len).sum();
+        self.crypto.len() + ahead + datagrams
+    }
+
+    // Appends CRYPTO data that now follows on from what has been assembled
+    fn assemble(&mut self) {
+        while let Some(i) = self.ahead.iter().position(|(offset, _)| *offset <= self.crypto.len() as u64) {
+            let (offset, data) = self.ahead.swap_remove(i);
+            let skip = self.crypto.len() - offset as usize;
+            if skip < data.len() {
+                self.crypto.extend_from_slice(&data[skip..]);
+            }
+        }
+        self.ahead.retain(|(offset, _)| *offset < MAX_HELLO_LEN as u64);
+    }
+}
+
+// Cursor over big-endian TLS and QUIC encodings
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn uint(&mut self, len: usize) -> Option<u64> {
+        Some(self.bytes(len)?.iter().fold(0, |n, b| n << 8 | *b as u64))
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let len = 1 << (self.0.first()? >> 6);
+        Some(self.uint(len)? & (u64::MAX >> (64 - 8 * len + 2)))
+    }
+
+    // A field preceded by its length, itself `len` bytes long
+    fn vec(&mut self, len: usize) -> Option<&'a [u8]> {
+        let n = self.uint(len)?;
+        self.bytes(n as usize)
+    }
+}
+
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// HKDF-Expand-Label from TLS 1.3 with an empty context
+fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [6 + label.len() as u8];
+    let info = [&len[..], &label_len, b"tls13 ", label, &[0]];
+    prk.expand(&info, KeyLen(out.len())).ok()?.fill(out).ok()
+}
+
+// The AEAD key, IV and header protection key of a client's Initial packets
+fn client_initial_keys(dcid: &[u8]) -> Option<([u8; 16], [u8; 12], [u8; 16])> {
+    let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT_V1).extract(dcid);
+    let mut secret = [0; 32];
+    expand_label(&initial, b"client in", &mut secret)?;
+    let client = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+    let (mut key, mut iv, mut hp) = ([0; 16], [0; 12], [0; 16]);
+    expand_label(&client, b"quic key", &mut key)?;
+    expand_label(&client, b"quic iv", &mut iv)?;
+    expand_label(&client, b"quic hp", &mut hp)?;
+    Some((key, iv, hp))
+}
+
+// Decrypts the Initial packet at the start of `datagram`, returning its frames
+fn open_initial(datagram: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader(datagram);
+    let first = r.uint(1)? as u8;
+    if first & 0xb0 != 0x80 || r.uint(4)? != 1 {
+        return None;
+    }
+    let dcid = r.vec(1)?;
+    r.vec(1)?;
+    let token_len = r.varint()?;
+    r.bytes(token_len as usize)?;
+    let len = r.varint()? as usize;
+    let pn_offset = datagram.len() - r.0.len();
+    if len < 20 || r.0.len() < len {
+        return None;
+    }
+
+    let (key, mut iv, hp) = client_initial_keys(dcid)?;
+    let sample = &datagram[pn_offset + 4..pn_offset + 20];
+    let mask = quic::HeaderProtectionKey::new(&quic::AES_128, &hp).ok()?.new_mask(sample).ok()?;
+    let pn_len = ((first ^ mask[0]) & 0x03) as usize + 1;
+
+    let mut header = datagram[..pn_offset + pn_len].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    for i in 0..pn_len {
+        header[pn_offset + i] ^= mask[1 + i];
+    }
+    let pn = Reader(&header[pn_offset..]).uint(pn_len)?;
+    for (i, b) in pn.to_be_bytes().iter().enumerate() {
+        iv[4 + i] ^= b;
+    }
+
+    let key = LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &key).ok()?);
+    let mut payload = datagram[pn_offset + pn_len..pn_offset + len].to_vec();
+    let plain_len = key
+        .open_in_place(Nonce::assume_unique_for_key(iv), Aad::from(&header), &mut payload)
+        .ok()?
+        .len();
+    payload.truncate(plain_len);
+    Some(payload)
+}
+
+// Collects the CRYPTO frames of an Initial payload as (offset, data)
+fn crypto_frames(payload: &[u8]) -> Option<Vec<(u64, Vec<u8>)>> {
+    let mut r = Reader(payload);
+    let mut frames = Vec::new();
+
+    while !r.0.is_empty() {
+        match r.varint()? {
+            // PADDING, PING
+            0x00 | 0x01 => (),
+            // ACK, with ECN counts for 0x03
+            kind @ (0x02 | 0x03) => {
+                r.varint()?;
+                r.varint()?;
+                let ranges = r.varint()?;
+                r.varint()?;
+                for _ in 0..ranges {
+                    r.varint()?;
+                    r.varint()?;
+                }
+                if kind == 0x03 {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+            }
+            0x06 => {
+                let offset = r.varint()?;
+                let len = r.varint()?;
+                frames.push((offset, r.bytes(len as usize)?.to_vec()));
+            }
+            // CONNECTION_CLOSE
+            0x1c => {
+                r.varint()?;
+                r.varint()?;
+                let len = r.varint()?;
+                r.bytes(len as usize)?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(frames)
+}
+
+// Length of the ClientHello message that `crypto` starts with, once known
+fn hello_len(crypto: &[u8]) -> Option<usize> {
+    let mut r = Reader(crypto);
+    match r.uint(1)? {
+        // client_hello
+        1 => Some(4 + r.uint(3)? as usize),
+        _ => Some(usize::MAX),
+    }
+}
+
+// Reads the server_name and ALPN extensions of a ClientHello
+fn read_hello(hello: &[u8]) -> Option<ClientHello> {
+    let mut r = Reader(&hello[4..]);
+    // legacy_version, random
+    r.bytes(34)?;
+    r.vec(1)?;
+    r.vec(2)?;
+    r.vec(1)?;
+
+    let mut found = ClientHello::default();
+    let mut extensions = Reader(r.vec(2)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.uint(2)?;
+        let mut data = Reader(extensions.vec(2)?);
+        match kind {
+            // server_name
+            0 => {
+                let mut names = Reader(data.vec(2)?);
+                while !names.0.is_empty() {
+                    let name_type = names.uint(1)?;
+                    let name = names.vec(2)?;
+                    if name_type == 0 && found.server_name.is_none() {
+                        found.server_name = std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+                    }
+                }
+            }
+            // application_layer_protocol_negotiation
+            16 => {
+                let mut protocols = Reader(data.vec(2)?);
+                while !protocols.0.is_empty() {
+                    found.alpn.push(protocols.vec(1)?.to_vec());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 9001, appendix A.2: the client's first Initial, whose ClientHello
+    // asks for example.com
+    const RFC9001_DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    const RFC9001_CLIENT_INITIAL: &str = concat!(
+        "c000000001088394c8f03e5157080000449e7b9aec34d1b1c98dd7689fb8ec11d242b123dc9bd8bab936b47d92ec356c",
+        "0bab7df5976d27cd449f63300099f3991c260ec4c60d17b31f8429157bb35a1282a643a8d2262cad67500cadb8e7378c",
+        "8eb7539ec4d4905fed1bee1fc8aafba17c750e2c7ace01e6005f80fcb7df621230c83711b39343fa028cea7f7fb5ff89",
+        "eac2308249a02252155e2347b63d58c5457afd84d05dfffdb20392844ae812154682e9cf012f9021a6f0be17ddd0c208",
+        "4dce25ff9b06cde535d0f920a2db1bf362c23e596dee38f5a6cf3948838a3aec4e15daf8500a6ef69ec4e3feb6b1d98e",
+        "610ac8b7ec3faf6ad760b7bad1db4ba3485e8a94dc250ae3fdb41ed15fb6a8e5eba0fc3dd60bc8e30c5c4287e53805db",
+        "059ae0648db2f64264ed5e39be2e20d82df566da8dd5998ccabdae053060ae6c7b4378e846d29f37ed7b4ea9ec5d82e7",
+        "961b7f25a9323851f681d582363aa5f89937f5a67258bf63ad6f1a0b1d96dbd4faddfcefc5266ba6611722395c906556",
+        "be52afe3f565636ad1b17d508b73d8743eeb524be22b3dcbc2c7468d54119c7468449a13d8e3b95811a198f3491de3e7",
+        "fe942b330407abf82a4ed7c1b311663ac69890f4157015853d91e923037c227a33cdd5ec281ca3f79c44546b9d90ca00",
+        "f064c99e3dd97911d39fe9c5d0b23a229a234cb36186c4819e8b9c5927726632291d6a418211cc2962e20fe47feb3edf",
+        "330f2c603a9d48c0fcb5699dbfe5896425c5bac4aee82e57a85aaf4e2513e4f05796b07ba2ee47d80506f8d2c25e50fd",
+        "14de71e6c418559302f939b0e1abd576f279c4b2e0feb85c1f28ff18f58891ffef132eef2fa09346aee33c28eb130ff2",
+        "8f5b766953334113211996d20011a198e3fc433f9f2541010ae17c1bf202580f6047472fb36857fe843b19f5984009dd",
+        "c324044e847a4f4a0ab34f719595de37252d6235365e9b84392b061085349d73203a4a13e96f5432ec0fd4a1ee65accd",
+        "d5e3904df54c1da510b0ff20dcc0c77fcb2c0e0eb605cb0504db87632cf3d8b4dae6e705769d1de354270123cb11450e",
+        "fc60ac47683d7b8d0f811365565fd98c4c8eb936bcab8d069fc33bd801b03adea2e1fbc5aa463d08ca19896d2bf59a07",
+        "1b851e6c239052172f296bfb5e72404790a2181014f3b94a4e97d117b438130368cc39dbb2d198065ae3986547926cd2",
+        "162f40a29f0c3c8745c0f50fba3852e566d44575c29d39a03f0cda721984b6f440591f355e12d439ff150aab7613499d",
+        "bd49adabc8676eef023b15b65bfc5ca06948109f23f350db82123535eb8a7433bdabcb909271a6ecbcb58b936a88cd4e",
+        "8f2e6ff5800175f113253d8fa9ca8885c2f552e657dc603f252e1a8e308f76f0be79e2fb8f5d5fbbe2e30ecadd220723",
+        "c8c0aea8078cdfcb3868263ff8f0940054da48781893a7e49ad5aff4af300cd804a6b6279ab3ff3afb64491c85194aab",
+        "760d58a606654f9f4400e8b38591356fbf6425aca26dc85244259ff2b19c41b9f96f3ca9ec1dde434da7d2d392b905dd",
+        "f3d1f9af93d1af5950bd493f5aa731b4056df31bd267b6b90a079831aaf579be0a39013137aac6d404f518cfd4684064",
+        "7e78bfe706ca4cf5e9c5453e9f7cfd2b8b4c8d169a44e55c88d4a9a7f94742411092abbdf8b889e5c199d096e3f24788",
+    );
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn peer(n: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], n))
+    }
+
+    // The ClientHello carried by the RFC packet
+    fn rfc_client_hello() -> Vec<u8> {
+        let payload = open_initial(&unhex(RFC9001_CLIENT_INITIAL)).unwrap();
+        crypto_frames(&payload).unwrap().remove(0).1
+    }
+
+    fn crypto_frame(offset: u64, data: &[u8]) -> Vec<u8> {
+        // Four-byte offset and two-byte length varints
+        let mut frame = vec![0x06];
+        frame.extend_from_slice(&(offset as u32 | 0x8000_0000).to_be_bytes());
+        frame.extend_from_slice(&(data.len() as u16 | 0x4000).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    // Protects `frames` as a client Initial, as open_initial() expects
+    fn seal_initial(dcid: &[u8], pn: u32, frames: &[u8]) -> Vec<u8> {
+        let (key, mut iv, hp) = client_initial_keys(dcid).unwrap();
+
+        let mut packet = vec![0xc3, 0, 0, 0, 1, dcid.len() as u8];
+        packet.extend_from_slice(dcid);
+        // No SCID or token; a two-byte Length covering the packet number and tag
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&((4 + frames.len() + 16) as u16 | 0x4000).to_be_bytes());
+        let pn_offset = packet.len();
+        packet.extend_from_slice(&pn.to_be_bytes());
+
+        for (i, b) in (pn as u64).to_be_bytes().iter().enumerate() {
+            iv[4 + i] ^= b;
+        }
+        let key = LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &key).unwrap());
+        let mut payload = frames.to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(iv), Aad::from(&packet), &mut payload)
+            .unwrap();
+        packet.extend_from_slice(&payload);
+
+        let sample = &packet[pn_offset + 4..pn_offset + 20];
+        let mask = quic::HeaderProtectionKey::new(&quic::AES_128, &hp).unwrap().new_mask(sample).unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        for i in 0..4 {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+        packet
+    }
+
+    #[test]
+    fn opens_the_rfc_9001_client_initial() {
+        let payload = open_initial(&unhex(RFC9001_CLIENT_INITIAL)).unwrap();
+        let frames = crypto_frames(&payload).unwrap();
+
+        assert_eq!(frames.len(), 1);
+        let (offset, hello) = &frames[0];
+        assert_eq!(*offset, 0);
+        assert_eq!(hello.len(), 241);
+        assert_eq!(hello_len(hello), Some(241));
+        let hello = read_hello(hello).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        // The RFC's example offers a protocol literally named "alpn"
+        assert_eq!(hello.alpn, vec![b"alpn".to_vec()]);
+    }
+
+    #[test]
+    fn rejects_a_corrupted_initial() {
+        let mut datagram = unhex(RFC9001_CLIENT_INITIAL);
+        datagram[100] ^= 1;
+        assert!(open_initial(&datagram).is_none());
+    }
+
+    #[test]
+    fn reads_the_server_name_from_one_datagram() {
+        let mut hellos = ClientHellos::default();
+        match hellos.offer(peer(1), &RFC9001_DCID, &unhex(RFC9001_CLIENT_INITIAL)) {
+            Hello::Done(hello, earlier) => {
+                assert_eq!(hello.and_then(|h| h.server_name).as_deref(), Some("example.com"));
+                assert!(earlier.is_empty());
+            }
+            Hello::Incomplete => panic!("the ClientHello fits in one datagram"),
+        }
+        assert_eq!(hellos.kept, 0);
+    }
+
+    #[test]
+    fn reassembles_a_client_hello_received_out_of_order() {
+        let hello = rfc_client_hello();
+        let second = seal_initial(&RFC9001_DCID, 1, &crypto_frame(100, &hello[100..]));
+        let first = seal_initial(&RFC9001_DCID, 0, &crypto_frame(0, &hello[..100]));
+
+        let mut hellos = ClientHellos::default();
+        assert!(matches!(hellos.offer(peer(1), &RFC9001_DCID, &second), Hello::Incomplete));
+        match hellos.offer(peer(1), &RFC9001_DCID, &first) {
+            Hello::Done(hello, earlier) => {
+                assert_eq!(hello.and_then(|h| h.server_name).as_deref(), Some("example.com"));
+                assert_eq!(earlier, vec![second]);
+            }
+            Hello::Incomplete => panic!("the ClientHello is complete"),
+        }
+        assert_eq!(hellos.kept, 0);
+    }
+
+    #[test]
+    fn gives_up_after_max_datagrams_without_the_start() {
+        let mut hellos = ClientHellos::default();
+        for pn in 0..MAX_HELLO_DATAGRAMS as u32 - 1 {
+            let datagram = seal_initial(&RFC9001_DCID, pn, &crypto_frame(1, b"x"));
+            assert!(matches!(hellos.offer(peer(1), &RFC9001_DCID, &datagram), Hello::Incomplete));
+        }
+
+        let datagram = seal_initial(&RFC9001_DCID, 99, &crypto_frame(1, b"x"));
+        match hellos.offer(peer(1), &RFC9001_DCID, &datagram) {
+            Hello::Done(hello, earlier) => {
+                assert_eq!(hello, None);
+                assert_eq!(earlier.len(), MAX_HELLO_DATAGRAMS - 1);
+            }
+            Hello::Incomplete => panic!("too many datagrams were kept"),
+        }
+        assert!(hellos.pending.is_empty());
+        assert_eq!(hellos.kept, 0);
+    }
+
+    #[test]
+    fn caps_the_bytes_kept_for_one_peer() {
+        let mut hellos = ClientHellos::default();
+        let chunk = [0u8; 1100];
+        for pn in 0.. {
+            let datagram = seal_initial(&RFC9001_DCID, pn, &crypto_frame(1 + 1000 * pn as u64, &chunk));
+            match hellos.offer(peer(1), &RFC9001_DCID, &datagram) {
+                Hello::Incomplete => assert!(hellos.kept <= MAX_KEPT_BYTES),
+                Hello::Done(hello, _) => {
+                    assert_eq!(hello, None);
+                    assert!((pn as usize) < MAX_HELLO_DATAGRAMS - 1, "the byte cap should apply first");
+                    break;
+                }
+            }
+        }
+        assert_eq!(hellos.kept, 0);
+    }
+
+    #[test]
+    fn drops_crypto_data_beyond_the_largest_client_hello() {
+        let mut hellos = ClientHellos::default();
+        let datagram = seal_initial(&RFC9001_DCID, 0, &crypto_frame(MAX_HELLO_LEN as u64, &[0; 500]));
+
+        assert!(matches!(hellos.offer(peer(1), &RFC9001_DCID, &datagram), Hello::Incomplete));
+        assert!(hellos.pending[&peer(1)].ahead.is_empty());
+        assert_eq!(hellos.kept, datagram.len());
+    }
+
+    #[test]
+    fn caps_the_bytes_kept_for_all_peers() {
+        let mut hellos = ClientHellos::default();
+        let chunk = [0u8; 1100];
+        let mut refused = false;
+
+        'peers: for n in 0..1000 {
+            for pn in 0..10u32 {
+                let datagram = seal_initial(&RFC9001_DCID, pn, &crypto_frame(1 + 1000 * pn as u64, &chunk));
+                if let Hello::Done(..) = hellos.offer(peer(n), &RFC9001_DCID, &datagram) {
+                    refused = true;
+                    break 'peers;
+                }
+            }
+        }
+
+        assert!(refused);
+        assert!(hellos.kept <= MAX_TOTAL_KEPT_BYTES);
+        assert_eq!(hellos.kept, hellos.pending.values().map(Pending::kept).sum::<usize>());
+    }
+
+    #[test]
+    fn starts_over_for_a_new_dcid() {
+        let mut hellos = ClientHellos::default();
+        let stale = seal_initial(&[1; 8], 0, &crypto_frame(1, b"x"));
+        assert!(matches!(hellos.offer(peer(1), &[1; 8], &stale), Hello::Incomplete));
+
+        match hellos.offer(peer(1), &RFC9001_DCID, &unhex(RFC9001_CLIENT_INITIAL)) {
+            Hello::Done(hello, earlier) => {
+                assert_eq!(hello.and_then(|h| h.server_name).as_deref(), Some("example.com"));
+                assert!(earlier.is_empty());
+            }
+            Hello::Incomplete => panic!("the ClientHello fits in one datagram"),
+        }
+        assert_eq!(hellos.kept, 0);
+    }
+}
@@ -0,0 +1,302 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A datagram carrier the QUIC packet loop reads from and writes to.
+///
+/// `UdpSocket` is the production implementation; anything that can move
+/// whole datagrams between two addresses (an in-memory pair, a tunnel)
+/// can stand in for it.
+pub trait Transport {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Bounds how long `recv_from` blocks; on expiry it fails with
+    /// `WouldBlock` or `TimedOut`. `None` blocks indefinitely.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Sends a datagram that should not leave before `at`, quiche's pacing
+    /// hint. Transports that cannot schedule transmission send immediately.
+    fn send_at(&self, buf: &[u8], to: SocketAddr, at: Instant) -> io::Result<usize> {
+        let _ = at;
+        self.send_to(buf, to)
+    }
+}
+
+impl Transport for UdpSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, to)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+/// Binds a UDP socket, optionally restricted to a single network device.
+///
+/// Restricting to a device uses `SO_BINDTODEVICE`, which confines the socket
+/// to that interface (or VRF master device) for both send and receive.
+pub fn bind_udp(addr: impl ToSocketAddrs, device: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+
+    if let Some(device) = device {
+        bind_to_device(&socket, device)?;
+    }
+
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_device(socket: &UdpSocket, device: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!("failed to bind to device {:?}: {}", device, err),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_to_device(_socket: &UdpSocket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot bind to device {:?}: SO_BINDTODEVICE is only available on Linux", device),
+    ))
+}
+
+/// A UDP socket that hands quiche's pacing hints to the kernel with
+/// `SO_TXTIME`, so packets are held back until their release time.
+///
+/// Release times are only honoured when the egress interface uses the `fq`
+/// (or `etf`) qdisc; other qdiscs send packets immediately.
+#[cfg(target_os = "linux")]
+pub struct PacedUdpSocket {
+    socket: UdpSocket,
+}
+
+#[cfg(target_os = "linux")]
+impl PacedUdpSocket {
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let txtime = libc::sock_txtime { clockid: libc::CLOCK_MONOTONIC, flags: 0 };
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TXTIME,
+                &txtime as *const libc::sock_txtime as *const libc::c_void,
+                std::mem::size_of::<libc::sock_txtime>() as libc::socklen_t,
+            )
+        };
+
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            return Err(io::Error::new(err.kind(), format!("failed to enable SO_TXTIME: {}", err)));
+        }
+
+        Ok(PacedUdpSocket { socket })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Transport for PacedUdpSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, to)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn send_at(&self, buf: &[u8], to: SocketAddr, at: Instant) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        let (mut addr, addr_len) = sockaddr_from(to);
+        let txtime = monotonic_ns(at);
+
+        let mut iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        // u64 elements keep the control buffer aligned for cmsghdr
+        let mut control = [0u64; 4];
+
+        let sent = unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_namelen = addr_len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<u64>() as u32) as usize;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_TXTIME;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u64>() as u32) as usize;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u64, txtime);
+
+            libc::sendmsg(self.socket.as_raw_fd(), &msg, 0)
+        };
+
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+// Converts an Instant to CLOCK_MONOTONIC nanoseconds, the clock SO_TXTIME uses
+#[cfg(target_os = "linux")]
+fn monotonic_ns(at: Instant) -> u64 {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    let now_ns = now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64;
+    now_ns + at.saturating_duration_since(Instant::now()).as_nanos() as u64
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr_from(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Wraps a socket so quiche's pacing hints are enforced where the platform
+/// allows it, falling back to the plain socket (with a warning) elsewhere.
+pub fn paced(socket: UdpSocket) -> Box<dyn Transport + Send + Sync> {
+    #[cfg(target_os = "linux")]
+    {
+        match socket.try_clone().and_then(PacedUdpSocket::new) {
+            Ok(paced) => return Box::new(paced),
+            Err(e) => eprintln!("Pacing rate cap will not be enforced: {}", e),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    eprintln!("Pacing rate cap will not be enforced: SO_TXTIME is only available on Linux");
+
+    Box::new(socket)
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// One end of an in-process datagram link, created with [`MemoryTransport::pair`].
+///
+/// Datagrams sent to the peer's address are queued for the other end;
+/// anything addressed elsewhere is dropped, as UDP would.
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    peer: SocketAddr,
+    tx: Sender<Datagram>,
+    rx: Mutex<Receiver<Datagram>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryTransport {
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+
+        let a_end = MemoryTransport::new(a, b, a_tx, a_rx);
+        let b_end = MemoryTransport::new(b, a, b_tx, b_rx);
+
+        (a_end, b_end)
+    }
+
+    fn new(addr: SocketAddr, peer: SocketAddr, tx: Sender<Datagram>, rx: Receiver<Datagram>) -> Self {
+        MemoryTransport { addr, peer, tx, rx: Mutex::new(rx), read_timeout: Mutex::new(None) }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let rx = self.rx.lock().unwrap();
+        let received = match *self.read_timeout.lock().unwrap() {
+            Some(timeout) => rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => io::Error::from(io::ErrorKind::WouldBlock),
+                RecvTimeoutError::Disconnected => peer_closed(),
+            }),
+            None => rx.recv().map_err(|_| peer_closed()),
+        };
+        let (data, from) = received?;
+
+        // Like a UDP socket, a datagram longer than the buffer is truncated.
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        if to == self.peer {
+            self.tx.send((buf.to_vec(), self.addr)).map_err(|_| peer_closed())?;
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+fn peer_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "memory transport peer closed")
+}
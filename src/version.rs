@@ -0,0 +1,109 @@
+use napi::{Env, JsFunction, JsObject};
+use napi_derive::napi;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Version of the JavaScript API. It goes up by one whenever a method or
+/// option is added or changes behaviour; `capabilities()` tells which of the
+/// optional parts are present. Deprecated methods keep working, with a
+/// one-time `DeprecationWarning`, for at least two versions after the one
+/// that deprecated them.
+#[napi(js_name = "apiVersion")]
+pub const API_VERSION: u32 = 1;
+
+// Deprecations already warned about in this process
+static WARNED: Mutex<Option<HashSet<&'static str>>> = Mutex::new(None);
+
+/// Names the features this build supports, so callers can test for one
+/// instead of comparing versions. Features that depend on the platform or
+/// on cargo features are listed only where they work.
+#[napi]
+pub fn capabilities() -> Vec<String> {
+    let mut features = vec![
+        "http3",
+        "http3PerAlpn",
+        "datagrams",
+        "retry",
+        "initialRateLimit",
+        "mutualTls",
+        "sniCertificates",
+        "certificateReload",
+        "peerCertChain",
+        "keylog",
+        "congestionEvents",
+        "lossEvents",
+        "onAccept",
+        "onSchedule",
+        "drain",
+        "closeConnection",
+        "pipeToFile",
+        "altSvc",
+        "streamFinAcked",
+        "martianPackets",
+        "statelessReset",
+        "ticketKey",
+        "unhandledPacketEvents",
+        "alpnMismatch",
+        "virtualHosts",
+        "blockedState",
+        "maxConnectionAge",
+        "streamFinished",
+        "drainRefusesStreams",
+        "streamCounts",
+        "datagramSupport",
+        "updateConfig",
+        "writeCoalescing",
+        "requestHeaderLimits",
+        "messageFraming",
+        "statusJson",
+        "clientLocalAddress",
+        "httpDatagrams",
+        "acceptDecisions",
+        "gracefulClose",
+        "memoryUsage",
+        "cidEncryption",
+        "keepAlive",
+        "requestBodyLimits",
+        "streamDigests",
+        "initialDecisions",
+        "forcedVersions",
+        "phaseProfiling",
+        "streamContexts",
+        "datagramPolicy",
+        "streamShutdown",
+        "readCoalescing",
+        "preHandshakeWrites",
+        "responseMetrics",
+        "cidWorkerIds",
+        "alpnDatagrams",
+    ];
+
+    if cfg!(feature = "qlog") {
+        features.push("qlog");
+        features.push("runtimeQlog");
+        features.push("qlogOutput");
+    }
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        features.push("bindDevice");
+    }
+    if cfg!(target_os = "linux") {
+        features.push("pacing");
+        features.push("inMemoryCertificates");
+    }
+
+    features.into_iter().map(String::from).collect()
+}
+
+// Warns through process.emitWarning() that a deprecated method was called,
+// once per process for each `code`
+pub(crate) fn deprecate(env: &Env, code: &'static str, message: &str) -> napi::Result<()> {
+    if !WARNED.lock().unwrap().get_or_insert_with(HashSet::new).insert(code) {
+        return Ok(());
+    }
+
+    let process: JsObject = env.get_global()?.get_named_property("process")?;
+    let emit_warning: JsFunction = process.get_named_property("emitWarning")?;
+    let args = [env.create_string(message)?, env.create_string("DeprecationWarning")?, env.create_string(code)?];
+    emit_warning.call(Some(&process), &args)?;
+    Ok(())
+}